@@ -1,8 +1,9 @@
+use crate::parse_bsp::{DetailProp, EmitType, StaticProp, WorldLight};
 use crate::vector::{Vec2, Vec3};
 use image::{DynamicImage, ImageError};
 use json::{array, object, JsonError, JsonValue};
 use std::fs::File;
-use std::io::{Cursor, Seek, SeekFrom, Write};
+use std::io::{Cursor, Seek, Write};
 use std::{fs, result};
 use thiserror::Error;
 
@@ -21,7 +22,7 @@ where
 }
 
 fn pad_length(x: usize) -> usize {
-    ((x + 3) / 4) * 4
+    x.div_ceil(4) * 4
 }
 
 #[derive(Debug, Error)]
@@ -38,11 +39,266 @@ pub struct GltfObject<'a> {
     pub vertexes: &'a [Vec3],
     pub normals: &'a [Vec3],
     pub uvs: &'a [Vec2],
+    /// Coordinates in the lightmap passed to [`save_mesh`], empty for objects
+    /// without baked lighting.
+    pub lightmap_uvs: &'a [Vec2],
     pub indices: &'a [usize],
     pub texture: DynamicImage,
     pub name: &'a str,
 }
 
+/// Geometry made of several meshes, only added to the scene by the props
+/// placing it.
+pub struct GltfModel<'a> {
+    pub objects: Vec<GltfObject<'a>>,
+}
+
+/// A static prop placed in the scene, so the model can be swapped in downstream.
+pub struct GltfProp<'a> {
+    /// Path of the model, used as the name of the node.
+    pub name: &'a str,
+    /// Index of the [`GltfModel`] rendered by the prop, if its model was loaded.
+    pub model: Option<usize>,
+    pub translation: Vec3,
+    /// Quaternion as `[x, y, z, w]`.
+    pub rotation: [f32; 4],
+    pub scale: Vec3,
+    /// Linear color the model is multiplied with, like the baked lighting of
+    /// detail props.
+    pub color: Option<Vec3>,
+}
+
+impl<'a> GltfProp<'a> {
+    /// Converts the placement of a static prop to the y up space and scale of
+    /// the exported meshes.
+    pub fn from_static_prop(prop: &'a StaticProp) -> Self {
+        return GltfProp {
+            name: &prop.model_name,
+            model: None,
+            translation: prop.origin.to_y_up() * 0.1,
+            rotation: prop.angles.angles_to_y_up_quaternion(),
+            scale: Vec3 {
+                x: prop.uniform_scale,
+                y: prop.uniform_scale,
+                z: prop.uniform_scale,
+            },
+            color: None,
+        };
+    }
+
+    /// Converts the placement of a sprite detail prop, the model has to be
+    /// built from the [`DetailSpriteShape`](crate::parse_bsp::DetailSpriteShape)
+    /// of the prop. `name` is usually the detail material.
+    pub fn from_detail_prop(prop: &DetailProp, name: &'a str) -> Self {
+        return GltfProp {
+            name,
+            model: None,
+            translation: prop.origin.to_y_up() * 0.1,
+            rotation: prop.sprite_angles().angles_to_y_up_quaternion(),
+            scale: Vec3 {
+                x: prop.scale,
+                y: prop.scale,
+                z: prop.scale,
+            },
+            color: None,
+        };
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum GltfLightKind {
+    Point,
+    Spot {
+        /// Angles from the direction in radians, where the light starts to
+        /// fade and where it has faded out.
+        inner_cone_angle: f32,
+        outer_cone_angle: f32,
+    },
+    Directional,
+}
+
+/// A light written with the `KHR_lights_punctual` extension.
+pub struct GltfLight {
+    pub kind: GltfLightKind,
+    /// Linear color, the brightest component is 1.
+    pub color: [f32; 3],
+    /// Candela for point and spot lights, lux for directional lights.
+    pub intensity: f32,
+    /// Distance past which the light has no effect.
+    pub range: Option<f32>,
+    pub translation: Vec3,
+    /// Quaternion as `[x, y, z, w]`, rotating the `-z` axis to the direction
+    /// the light shines in.
+    pub rotation: [f32; 4],
+}
+
+impl GltfLight {
+    /// Converts a light to the y up space and scale of the exported meshes.
+    ///
+    /// Source lights fall off with the square of the distance by default, so
+    /// the intensity shrinks with the square of the scale. Linear and constant
+    /// falloff can't be represented and are ignored. Surface lights become
+    /// spotlights covering their hemisphere, ambient sky light is skipped.
+    pub fn from_world_light(light: &WorldLight) -> Option<Self> {
+        let scale = 0.1;
+        let cone = |stopdot: f32| stopdot.clamp(0.0, 1.0).acos();
+        let kind = match light.emit_type {
+            EmitType::Point | EmitType::QuakeLight => GltfLightKind::Point,
+            EmitType::Spotlight => GltfLightKind::Spot {
+                inner_cone_angle: cone(light.stopdot).min(cone(light.stopdot2)),
+                outer_cone_angle: cone(light.stopdot2),
+            },
+            EmitType::Surface => GltfLightKind::Spot {
+                inner_cone_angle: 0.0,
+                outer_cone_angle: std::f32::consts::FRAC_PI_2,
+            },
+            EmitType::Skylight => GltfLightKind::Directional,
+            EmitType::SkyAmbient | EmitType::Other(_) => return None,
+        };
+
+        let peak = float_max([light.intensity.x, light.intensity.y, light.intensity.z].into_iter());
+        if peak <= 0.0 {
+            return None;
+        }
+        let intensity = match (kind, light.quadratic_attenuation > 0.0) {
+            (GltfLightKind::Directional, _) => peak,
+            (_, true) => peak * scale * scale / light.quadratic_attenuation,
+            (_, false) => peak * scale * scale,
+        };
+
+        return Some(GltfLight {
+            kind,
+            color: [light.intensity.x, light.intensity.y, light.intensity.z].map(|k| k / peak),
+            intensity,
+            range: (light.radius > 0.0).then_some(light.radius * scale),
+            translation: light.origin.to_y_up() * scale,
+            rotation: rotation_from_negative_z(light.normal.to_y_up()),
+        });
+    }
+}
+
+/// The quaternion rotating the `-z` axis onto `direction`.
+fn rotation_from_negative_z(direction: Vec3) -> [f32; 4] {
+    let from = Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: -1.0,
+    };
+    if direction.dot(&direction) == 0.0 {
+        return [0.0, 0.0, 0.0, 1.0];
+    }
+    let to = direction.normalize();
+    let w = 1.0 + from.dot(&to);
+    // Any half turn works for the opposite direction
+    if w < 1e-6 {
+        return [1.0, 0.0, 0.0, 0.0];
+    }
+    let axis = from.cross(to);
+    let length = (axis.dot(&axis) + w * w).sqrt();
+    return [
+        axis.x / length,
+        axis.y / length,
+        axis.z / length,
+        w / length,
+    ];
+}
+
+/// Props sharing a model, written as a single node using
+/// `EXT_mesh_gpu_instancing`.
+struct InstancedProps<'a, 'b> {
+    name: &'b str,
+    model: usize,
+    instances: Vec<&'a GltfProp<'b>>,
+}
+
+/// Groups props by model when `instancing` is set, models used only once are
+/// still written as plain nodes. So are props whose model could not be
+/// loaded, as an instanced node without a mesh would show nothing.
+///
+/// Props with a color are always instanced, only the instancing extension
+/// can give a node's mesh a color of its own.
+fn group_props<'a, 'b>(
+    props: &'a [GltfProp<'b>],
+    instancing: bool,
+) -> (Vec<&'a GltfProp<'b>>, Vec<InstancedProps<'a, 'b>>) {
+    let mut placeholders = vec![];
+    let mut groups: Vec<InstancedProps> = vec![];
+    for prop in props {
+        let Some(model) = prop.model.filter(|_| instancing || prop.color.is_some()) else {
+            placeholders.push(prop);
+            continue;
+        };
+        match groups
+            .iter_mut()
+            .find(|group| group.name == prop.name && group.model == model)
+        {
+            Some(group) => group.instances.push(prop),
+            None => groups.push(InstancedProps {
+                name: prop.name,
+                model,
+                instances: vec![prop],
+            }),
+        }
+    }
+    let (single, instanced) = groups.into_iter().partition::<Vec<_>, _>(|group| {
+        group.instances.len() == 1 && group.instances[0].color.is_none()
+    });
+    placeholders.extend(single.into_iter().map(|group| group.instances[0]));
+    return (placeholders, instanced);
+}
+
+/// The node of a prop, `first_model_mesh` is the index of the glTF mesh of the first model.
+fn prop_node(prop: &GltfProp, first_model_mesh: usize) -> JsonValue {
+    let mut node = object! {
+        "name"=>prop.name,
+        "translation"=>array![prop.translation.x, prop.translation.y, prop.translation.z],
+        "rotation"=>array![prop.rotation[0], prop.rotation[1], prop.rotation[2], prop.rotation[3]],
+        "scale"=>array![prop.scale.x, prop.scale.y, prop.scale.z]
+    };
+    if let Some(model) = prop.model {
+        node["mesh"] = (first_model_mesh + model).into();
+    }
+    return node;
+}
+
+/// Data written after the meshes, for things only some scenes have.
+struct ExtraData {
+    /// Offset of the data in the buffer.
+    offset: usize,
+    first_buffer_view: usize,
+    first_accessor: usize,
+    data: Vec<u8>,
+    buffer_views: Vec<JsonValue>,
+    accessors: Vec<JsonValue>,
+}
+
+impl ExtraData {
+    /// Adds `bytes` as a new buffer view, returning its index.
+    fn push_buffer_view(&mut self, bytes: &[u8]) -> usize {
+        self.buffer_views.push(object! {
+            "buffer"=>0,
+            "byteOffset"=>self.offset + self.data.len(),
+            "byteLength"=>bytes.len(),
+        });
+        self.data.extend_from_slice(bytes);
+        self.data.resize(pad_length(self.data.len()), 0);
+        return self.first_buffer_view + self.buffer_views.len() - 1;
+    }
+
+    /// Adds an accessor for float vectors with `kind` as their type, returning its index.
+    fn push_accessor(&mut self, values: &[f32], kind: &str, components: usize) -> usize {
+        let bytes: Vec<u8> = values.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let buffer_view = self.push_buffer_view(&bytes);
+        self.accessors.push(object! {
+            "bufferView"=>buffer_view,
+            "componentType"=> 5126_u32, // Float
+            "count"=>values.len() / components,
+            "type"=>kind
+        });
+        return self.first_accessor + self.accessors.len() - 1;
+    }
+}
+
 impl<'a> GltfObject<'a> {
     fn byte_length_excluding_texture(&self) -> usize {
         return self.vertexes.len() * 4 * 3
@@ -52,20 +308,42 @@ impl<'a> GltfObject<'a> {
     }
 }
 
+/// Writes the meshes and props to `cache/<filename>.glb`, along with the JSON
+/// part in `cache/<filename>.json`.
+///
+/// Each of `meshes` gets its own node, while `models` only appear where props
+/// place them. With `instancing` set, props sharing a model are written as one
+/// node using the `EXT_mesh_gpu_instancing` extension. The colors of props are
+/// written as its custom `_COLOR_0` attribute, which some viewers multiply
+/// with the base color.
+///
+/// glTF has no slot for lightmaps. The `lightmap` texture becomes the
+/// `occlusionTexture` of the materials of objects with lightmap coordinates,
+/// which are stored as `TEXCOORD_1`, so viewers darken unlit areas. Since
+/// viewers only apply occlusion to ambient light, it is also referenced as
+/// `lightmapTexture` in the `extras` of those materials, for tools that
+/// multiply it with the base color instead.
+///
+/// `lights` each get a node using the `KHR_lights_punctual` extension.
 pub fn save_mesh<'a, 'b>(
     filename: String,
     meshes: &'a [GltfObject<'b>],
+    models: &'a [GltfModel<'b>],
+    props: &[GltfProp],
+    instancing: bool,
+    lightmap: Option<&DynamicImage>,
+    lights: &[GltfLight],
 ) -> result::Result<(), SaveMeshError> {
+    // Every object becomes a primitive with its own material, the objects of
+    // a model share a glTF mesh
+    let world_meshes = meshes.len();
+    let meshes: Vec<&GltfObject> = meshes
+        .iter()
+        .chain(models.iter().flat_map(|model| &model.objects))
+        .collect();
     let images: Vec<Vec<u8>> = meshes
         .iter()
-        .map(|mesh| -> Result<Vec<u8>, ImageError> {
-            let mut image_bytes: Vec<u8> = Vec::new();
-            mesh.texture.write_to(
-                &mut Cursor::new(&mut image_bytes),
-                image::ImageOutputFormat::Png,
-            )?;
-            Ok(image_bytes)
-        })
+        .map(|mesh| encode_png(&mesh.texture))
         .collect::<Result<Vec<_>, _>>()?;
 
     let buffer_offsets: Vec<usize> = (0..meshes.len())
@@ -75,13 +353,150 @@ pub fn save_mesh<'a, 'b>(
                 .sum::<usize>()
         })
         .collect();
-    let total_buffer_length = meshes
+    let mesh_buffer_length = meshes
         .iter()
         .map(|i| i.byte_length_excluding_texture())
         .sum::<usize>()
         + images.iter().map(|i| pad_length(i.len())).sum::<usize>();
 
-    let gltf_json_part = object! {
+    let mut extra = ExtraData {
+        offset: mesh_buffer_length,
+        first_buffer_view: meshes.len() * 5,
+        first_accessor: meshes.len() * 4,
+        data: vec![],
+        buffer_views: vec![],
+        accessors: vec![],
+    };
+
+    let (single_props, instanced_props) = group_props(props, instancing);
+    let instance_attributes: Vec<JsonValue> = instanced_props
+        .iter()
+        .map(|group| {
+            let translations: Vec<f32> = group
+                .instances
+                .iter()
+                .flat_map(|prop| [prop.translation.x, prop.translation.y, prop.translation.z])
+                .collect();
+            let rotations: Vec<f32> = group
+                .instances
+                .iter()
+                .flat_map(|prop| prop.rotation)
+                .collect();
+            let scales: Vec<f32> = group
+                .instances
+                .iter()
+                .flat_map(|prop| [prop.scale.x, prop.scale.y, prop.scale.z])
+                .collect();
+            let mut attributes = object! {
+                "TRANSLATION"=>extra.push_accessor(&translations, "VEC3", 3),
+                "ROTATION"=>extra.push_accessor(&rotations, "VEC4", 4),
+                "SCALE"=>extra.push_accessor(&scales, "VEC3", 3)
+            };
+
+            // Colors are clamped like the lightmap, props without one stay white
+            if group.instances.iter().any(|prop| prop.color.is_some()) {
+                let colors: Vec<f32> = group
+                    .instances
+                    .iter()
+                    .flat_map(|prop| match prop.color {
+                        Some(color) => [color.x, color.y, color.z].map(|k| k.clamp(0.0, 1.0)),
+                        None => [1.0; 3],
+                    })
+                    .collect();
+                attributes["_COLOR_0"] = extra.push_accessor(&colors, "VEC3", 3).into();
+            }
+            attributes
+        })
+        .collect();
+
+    // The lightmap is one more texture after those of the objects
+    let lightmap_texture = match lightmap {
+        Some(image) => Some(extra.push_buffer_view(&encode_png(image)?)),
+        None => None,
+    };
+    let lightmap_accessors: Vec<Option<usize>> = meshes
+        .iter()
+        .map(|mesh| {
+            if mesh.lightmap_uvs.is_empty() || lightmap_texture.is_none() {
+                return None;
+            }
+            let uvs: Vec<f32> = mesh.lightmap_uvs.iter().flat_map(|x| [x.x, x.y]).collect();
+            Some(extra.push_accessor(&uvs, "VEC2", 2))
+        })
+        .collect();
+    let total_buffer_length = mesh_buffer_length + extra.data.len();
+
+    let primitive = |index: usize| {
+        let mut primitive = object! {
+            "attributes"=>object!{
+                "NORMAL"=> index * 4,
+                "POSITION"=>index * 4 + 1,
+                "TEXCOORD_0"=>index * 4 + 2
+            },
+            "indices"=>index * 4 + 3,
+            "material"=>index
+        };
+        if let Some(accessor) = lightmap_accessors[index] {
+            primitive["attributes"]["TEXCOORD_1"] = accessor.into();
+        }
+        primitive
+    };
+    let mut model_meshes: Vec<JsonValue> = vec![];
+    let mut first_primitive = world_meshes;
+    for model in models {
+        let primitives = (first_primitive..first_primitive + model.objects.len()).map(primitive);
+        model_meshes.push(object! {
+            "primitives"=>JsonValue::Array(primitives.collect())
+        });
+        first_primitive += model.objects.len();
+    }
+
+    let mut nodes: Vec<JsonValue> = meshes
+        .iter()
+        .take(world_meshes)
+        .enumerate()
+        .map(|(index, mesh)| {
+            object! {
+                "mesh"=>index,
+                "name"=>mesh.name
+            }
+        })
+        .collect();
+    nodes.extend(
+        single_props
+            .iter()
+            .map(|prop| prop_node(prop, world_meshes)),
+    );
+    nodes.extend(
+        instanced_props
+            .iter()
+            .zip(instance_attributes)
+            .map(|(group, attributes)| {
+                object! {
+                    "name"=>group.name,
+                    "mesh"=>world_meshes + group.model,
+                    "extensions"=>object!{
+                        "EXT_mesh_gpu_instancing"=>object!{
+                            "attributes"=>attributes
+                        }
+                    }
+                }
+            }),
+    );
+    nodes.extend(lights.iter().enumerate().map(|(index, light)| {
+        object! {
+            "name"=>format!("light{index}"),
+            "translation"=>array![light.translation.x, light.translation.y, light.translation.z],
+            "rotation"=>array![light.rotation[0], light.rotation[1], light.rotation[2], light.rotation[3]],
+            "extensions"=>object!{
+                "KHR_lights_punctual"=>object!{
+                    "light"=>index
+                }
+            }
+        }
+    }));
+
+    let mut gltf_json_part = object! {
         "asset"=> object!{
             "generator": "None",
             "version": "2.0"
@@ -91,37 +506,20 @@ pub fn save_mesh<'a, 'b>(
             object!{
                 "name"=> "Scene0",
                 "nodes" => JsonValue::Array(
-                    (0..meshes.len()).map(|i|JsonValue::Number(i.into())).collect::<Vec<_>>()
+                    (0..nodes.len()).map(|i|JsonValue::Number(i.into())).collect::<Vec<_>>()
                 )
             }
         ],
-        "nodes"=>JsonValue::Array(
-            meshes.iter().enumerate().map(|(index, mesh)|{
-                object!{
-                    "mesh"=>index,
-                    "name"=>mesh.name
-                }
-            }).collect()
-        ),
+        "nodes"=>JsonValue::Array(nodes),
         "meshes"=> JsonValue::Array(
-            meshes.iter().enumerate().map(|(index, _mesh)|{
+            (0..world_meshes).map(|index|{
                 object! {
-                    "primitives" => array![
-                        object!{
-                            "attributes"=>object!{
-                                "NORMAL"=> index * 4,
-                                "POSITION"=>index * 4 + 1,
-                                "TEXCOORD_0"=>index * 4 + 2
-                            },
-                            "indices"=>index * 4 + 3,
-                            "material"=>index
-                        }
-                    ]
+                    "primitives" => array![primitive(index)]
                 }
-            }).collect()
+            }).chain(model_meshes).collect()
         ),
         "textures"=>JsonValue::Array(
-            meshes.iter().enumerate().map(|(index, _mesh)|
+            (0..meshes.len() + lightmap_texture.iter().len()).map(|index|
                 object! {
                     "source"=>index,
                     "sampler"=>0
@@ -135,19 +533,36 @@ pub fn save_mesh<'a, 'b>(
                     "mimeType"=>"image/png",
                     "name"=>format!("texture{index}")
                 }
-            ).collect()
+            ).chain(lightmap_texture.map(|buffer_view| object! {
+                "bufferView"=>buffer_view,
+                "mimeType"=>"image/png",
+                "name"=>"lightmap"
+            })).collect()
         ),
         "materials"=>JsonValue::Array(
-            meshes.iter().enumerate().map(|(index, _mesh)|
-                object! {
+            meshes.iter().enumerate().map(|(index, _mesh)| {
+                let mut material = object! {
                     "pbrMetallicRoughness" => object!{
                         "baseColorTexture" => object!{
                             "index" => index,
                             "texCoord" => 0
                         }
                     }
+                };
+                if lightmap_accessors[index].is_some() {
+                    material["occlusionTexture"] = object!{
+                        "index" => meshes.len(),
+                        "texCoord" => 1
+                    };
+                    material["extras"] = object!{
+                        "lightmapTexture" => object!{
+                            "index" => meshes.len(),
+                            "texCoord" => 1
+                        }
+                    };
                 }
-            ).collect()
+                material
+            }).collect()
         ),
         "samplers"=>array![
             object!{
@@ -171,7 +586,7 @@ pub fn save_mesh<'a, 'b>(
 
             return [
                 object!{
-                    "bufferView"=>0 + index * 5,
+                    "bufferView"=>index * 5,
                     "componentType"=> 5126_u32, // Float
                     "count"=> mesh.normals.len(),
                     "type"=> "VEC3"
@@ -196,7 +611,7 @@ pub fn save_mesh<'a, 'b>(
                     "count"=> mesh.indices.len(),
                     "type"=> "SCALAR"
                 }
-            ]}).collect()
+            ]}).chain(extra.accessors).collect()
         ),
         "bufferViews"=> JsonValue::Array(meshes.iter().enumerate().flat_map(|(index, mesh)| [
             object!{
@@ -226,7 +641,7 @@ pub fn save_mesh<'a, 'b>(
                 "byteOffset"=>buffer_offsets[index] +4 * 3 * mesh.normals.len() + 4 * 3 * mesh.vertexes.len() + 4 * 2 * mesh.uvs.len() + 4 * mesh.indices.len(),
                 "byteLength"=>images[index].len(),
             }
-        ]).collect()),
+        ]).chain(extra.buffer_views).collect()),
         "buffers"=>array![
             object!{
                 "byteLength"=>total_buffer_length
@@ -234,6 +649,22 @@ pub fn save_mesh<'a, 'b>(
         ]
     };
 
+    let mut extensions_used = vec![];
+    if !instanced_props.is_empty() {
+        extensions_used.push("EXT_mesh_gpu_instancing");
+    }
+    if !lights.is_empty() {
+        extensions_used.push("KHR_lights_punctual");
+        gltf_json_part["extensions"] = object! {
+            "KHR_lights_punctual"=>object!{
+                "lights"=>JsonValue::Array(lights.iter().map(light_json).collect())
+            }
+        };
+    }
+    if !extensions_used.is_empty() {
+        gltf_json_part["extensionsUsed"] = extensions_used.into();
+    }
+
     fs::create_dir_all("cache")?;
     let mut jsfile = File::create(format!("cache/{:}.json", filename)).unwrap();
     jsfile.write_all(json::stringify_pretty(gltf_json_part.clone(), 2).as_bytes())?;
@@ -260,39 +691,34 @@ pub fn save_mesh<'a, 'b>(
 
     file.write_all(&(data.len() as u32).to_le_bytes())?;
     file.write_all("JSON".as_bytes())?;
-    file.write(data.as_bytes())?;
+    file.write_all(data.as_bytes())?;
 
     file.write_all(&(pad_length(total_buffer_length) as u32).to_le_bytes())?;
-    file.write_all("BIN".as_bytes())?;
-    file.write(&[0])?;
+    file.write_all("BIN\0".as_bytes())?;
 
     for (mesh, image) in meshes.iter().zip(images.iter()) {
         let buffer_normals: Vec<u8> = mesh
             .normals
             .iter()
-            .map(|x| [x.x.to_le_bytes(), x.y.to_le_bytes(), x.z.to_le_bytes()])
-            .flatten()
+            .flat_map(|x| [x.x.to_le_bytes(), x.y.to_le_bytes(), x.z.to_le_bytes()])
             .flatten()
             .collect();
         let buffer_positions: Vec<u8> = mesh
             .vertexes
             .iter()
-            .map(|x| [x.x.to_le_bytes(), x.y.to_le_bytes(), x.z.to_le_bytes()])
-            .flatten()
+            .flat_map(|x| [x.x.to_le_bytes(), x.y.to_le_bytes(), x.z.to_le_bytes()])
             .flatten()
             .collect();
         let buffer_uvs: Vec<u8> = mesh
             .uvs
             .iter()
-            .map(|x| [x.x.to_le_bytes(), x.y.to_le_bytes()])
-            .flatten()
+            .flat_map(|x| [x.x.to_le_bytes(), x.y.to_le_bytes()])
             .flatten()
             .collect();
         let buffer_indices: Vec<u8> = mesh
             .indices
             .iter()
-            .map(|x| (*x as u32).to_le_bytes())
-            .flatten()
+            .flat_map(|x| (*x as u32).to_le_bytes())
             .collect();
 
         file.write_all(buffer_normals.as_slice())?;
@@ -301,13 +727,228 @@ pub fn save_mesh<'a, 'b>(
         file.write_all(buffer_indices.as_slice())?;
         file.write_all(image.as_slice())?;
 
-        let cursor_position = file.seek(SeekFrom::Current(0))?;
+        let cursor_position = file.stream_position()?;
         for _i in 0..((4 - cursor_position % 4) % 4) {
-            file.write(&[0])?;
+            file.write_all(&[0])?;
         }
 
         //let mut img_file = File::create(format!("cache/{}.png", filename))?;
         //img_file.write_all(image_bytes.as_slice())?;
     }
+
+    file.write_all(&extra.data)?;
     return result::Result::Ok(());
 }
+
+fn light_json(light: &GltfLight) -> JsonValue {
+    let mut json = object! {
+        "color"=>array![light.color[0], light.color[1], light.color[2]],
+        "intensity"=>light.intensity
+    };
+    match light.kind {
+        GltfLightKind::Point => json["type"] = "point".into(),
+        GltfLightKind::Spot {
+            inner_cone_angle,
+            outer_cone_angle,
+        } => {
+            json["type"] = "spot".into();
+            json["spot"] = object! {
+                "innerConeAngle"=>inner_cone_angle,
+                "outerConeAngle"=>outer_cone_angle
+            };
+        }
+        GltfLightKind::Directional => json["type"] = "directional".into(),
+    }
+    // Directional lights reach everywhere
+    if let (Some(range), false) = (light.range, light.kind == GltfLightKind::Directional) {
+        json["range"] = range.into();
+    }
+    return json;
+}
+
+fn encode_png(image: &DynamicImage) -> Result<Vec<u8>, ImageError> {
+    let mut image_bytes: Vec<u8> = Vec::new();
+    image.write_to(
+        &mut Cursor::new(&mut image_bytes),
+        image::ImageOutputFormat::Png,
+    )?;
+    return Ok(image_bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prop(name: &str, model: Option<usize>) -> GltfProp<'_> {
+        let zero = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        return GltfProp {
+            name,
+            model,
+            translation: zero,
+            rotation: [0.0, 0.0, 0.0, 1.0],
+            scale: zero,
+            color: None,
+        };
+    }
+
+    #[test]
+    fn props_without_a_model_are_not_instanced() {
+        let props = [
+            prop("models/crate.mdl", Some(0)),
+            prop("models/missing.mdl", None),
+            prop("models/crate.mdl", Some(0)),
+            prop("models/missing.mdl", None),
+            prop("models/barrel.mdl", Some(1)),
+        ];
+        let (single, instanced) = group_props(&props, true);
+
+        let names = single.iter().map(|prop| (prop.name, prop.model));
+        assert_eq!(
+            names.collect::<Vec<_>>(),
+            [
+                ("models/missing.mdl", None),
+                ("models/missing.mdl", None),
+                ("models/barrel.mdl", Some(1))
+            ]
+        );
+        assert_eq!(instanced.len(), 1);
+        assert_eq!(instanced[0].model, 0);
+        assert_eq!(instanced[0].instances.len(), 2);
+    }
+
+    #[test]
+    fn props_with_a_color_are_always_instanced() {
+        let grass = GltfProp {
+            color: Some(Vec3 {
+                x: 0.5,
+                y: 0.5,
+                z: 0.5,
+            }),
+            ..prop("detail/detailsprites", Some(1))
+        };
+        let props = [
+            prop("models/crate.mdl", Some(0)),
+            prop("models/crate.mdl", Some(0)),
+            grass,
+        ];
+        let (single, instanced) = group_props(&props, false);
+
+        assert_eq!(single.len(), 2);
+        assert!(single.iter().all(|prop| prop.model == Some(0)));
+        assert_eq!(instanced.len(), 1);
+        assert_eq!(instanced[0].model, 1);
+        assert_eq!(instanced[0].instances.len(), 1);
+    }
+
+    fn vec3(x: f32, y: f32, z: f32) -> Vec3 {
+        return Vec3 { x, y, z };
+    }
+
+    fn light(emit_type: EmitType, normal: Vec3) -> WorldLight {
+        return WorldLight {
+            origin: vec3(10.0, 20.0, 30.0),
+            intensity: vec3(100.0, 50.0, 0.0),
+            normal,
+            shadow_cast_offset: vec3(0.0, 0.0, 0.0),
+            cluster: 0,
+            emit_type,
+            style: 0,
+            stopdot: 0.0,
+            stopdot2: 0.0,
+            exponent: 0.0,
+            radius: 0.0,
+            constant_attenuation: 0.0,
+            linear_attenuation: 0.0,
+            quadratic_attenuation: 0.0,
+            flags: 0,
+            tex_info: -1,
+            owner: 0,
+        };
+    }
+
+    /// Rotates `vector` by the `[x, y, z, w]` quaternion `rotation`.
+    fn rotate(rotation: [f32; 4], vector: Vec3) -> Vec3 {
+        let axis = vec3(rotation[0], rotation[1], rotation[2]);
+        let t = axis.cross(vector) * 2.0;
+        return vector + t * rotation[3] + axis.cross(t);
+    }
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).dot(&(a - b)) < 1e-10, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn rotation_points_negative_z_along_the_direction() {
+        let negative_z = vec3(0.0, 0.0, -1.0);
+        for direction in [
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, -1.0, 0.0),
+            vec3(0.0, 0.0, 1.0),
+            negative_z,
+            vec3(1.0, 2.0, -3.0),
+        ] {
+            let rotation = rotation_from_negative_z(direction);
+            assert_close(rotate(rotation, negative_z), direction.normalize());
+        }
+        assert_eq!(
+            rotation_from_negative_z(vec3(0.0, 0.0, 0.0)),
+            [0.0, 0.0, 0.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn spotlights_keep_their_cone_and_direction() {
+        let mut spotlight = light(EmitType::Spotlight, vec3(1.0, 0.0, 0.0));
+        spotlight.stopdot = (30.0_f32).to_radians().cos();
+        spotlight.stopdot2 = (45.0_f32).to_radians().cos();
+        spotlight.quadratic_attenuation = 0.5;
+        spotlight.radius = 200.0;
+
+        let gltf = GltfLight::from_world_light(&spotlight).unwrap();
+        let GltfLightKind::Spot {
+            inner_cone_angle,
+            outer_cone_angle,
+        } = gltf.kind
+        else {
+            panic!("expected a spotlight, got {:?}", gltf.kind);
+        };
+        assert!((inner_cone_angle - (30.0_f32).to_radians()).abs() < 1e-5);
+        assert!((outer_cone_angle - (45.0_f32).to_radians()).abs() < 1e-5);
+        assert_eq!(gltf.color, [1.0, 0.5, 0.0]);
+        // 100 / 0.5 at a tenth of the distance
+        assert!((gltf.intensity - 2.0).abs() < 1e-5);
+        assert_eq!(gltf.range, Some(20.0));
+        assert_close(gltf.translation, vec3(1.0, 3.0, -2.0));
+        assert_close(
+            rotate(gltf.rotation, vec3(0.0, 0.0, -1.0)),
+            vec3(1.0, 0.0, 0.0),
+        );
+    }
+
+    #[test]
+    fn skylight_is_a_directional_light_that_is_not_scaled() {
+        let sun = light(EmitType::Skylight, vec3(0.0, 1.0, -1.0));
+        let gltf = GltfLight::from_world_light(&sun).unwrap();
+        assert_eq!(gltf.kind, GltfLightKind::Directional);
+        assert_eq!(gltf.intensity, 100.0);
+        assert_eq!(gltf.range, None);
+        // Shining down and towards Source's +y, which is -z when y is up
+        let direction = vec3(0.0, -1.0, -1.0).normalize();
+        assert_close(rotate(gltf.rotation, vec3(0.0, 0.0, -1.0)), direction);
+    }
+
+    #[test]
+    fn ambient_and_black_lights_are_skipped() {
+        let ambient = light(EmitType::SkyAmbient, vec3(0.0, 0.0, -1.0));
+        assert!(GltfLight::from_world_light(&ambient).is_none());
+        let mut black = light(EmitType::Point, vec3(0.0, 0.0, 0.0));
+        black.intensity = vec3(0.0, 0.0, 0.0);
+        assert!(GltfLight::from_world_light(&black).is_none());
+        let point = light(EmitType::Point, vec3(0.0, 0.0, 0.0));
+        assert!((GltfLight::from_world_light(&point).unwrap().intensity - 1.0).abs() < 1e-5);
+    }
+}
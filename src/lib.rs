@@ -0,0 +1,9 @@
+#![allow(clippy::needless_return)]
+
+pub mod gltf_export;
+pub mod parse_bsp;
+pub mod vector;
+
+#[cfg(feature = "mmap")]
+pub use parse_bsp::MappedBsp;
+pub use parse_bsp::{Bsp, BspError, BspWriter, GoldSrcBsp, Quake3Bsp};
@@ -1,14 +1,443 @@
-use parse_bsp::parse_bsp;
+#![allow(clippy::needless_return)]
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{self, File},
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use bspparse::{
+    gltf_export::{self, GltfLight, GltfModel, GltfProp},
+    parse_bsp::{
+        self, goldsrc, BrushGeometry, DetailSpriteShape, LightmapSelection, MaterialGroup, Pakfile,
+        PakfileWriter, StudioModel,
+    },
+    Bsp, BspError, BspWriter, GoldSrcBsp, Quake3Bsp,
+};
+use comma_format::CommaFormat;
+use image::{DynamicImage, Rgba, RgbaImage};
 
 mod comma_format;
-mod gltf_export;
-mod parse_bsp;
-mod vector;
-
-fn main() -> std::io::Result<()> {
-    //parse_bsp("D:\\steam\\steamapps\\common\\Team Fortress 2\\tf\\maps\\cp_gorge.bsp")
-    //parse_bsp("D:\\steam\\steamapps\\common\\Team Fortress 2\\tf\\maps\\plr_pipeline.bsp")
-    //parse_bsp::parse_bsp("C:\\Users\\Admin\\Documents\\cp_border\\cp_border_011.bsp")
-    parse_bsp("D:\\steam\\steamapps\\common\\Team Fortress 2\\tf\\maps\\cp_junction_final.bsp")
-    //parse_bsp("D:\\steam\\steamapps\\common\\Team Fortress 2\\tf\\maps\\pl_upward.bsp")
+
+#[derive(Default)]
+struct ExportOptions {
+    /// Adds the detail sprites, instanced and colored by their lighting.
+    detail_props: bool,
+    /// Writes props sharing a model as a single instanced node.
+    instance_props: bool,
+    /// Adds the lights the map was compiled with.
+    lights: bool,
+    /// Directories searched for models that are not embedded in the map.
+    content: Vec<PathBuf>,
+    /// Where to write the linear lightmap atlas, as `.hdr` or `.exr`.
+    lightmap: Option<PathBuf>,
+    /// The light styles and bump basis baked into the lightmap, which is
+    /// embedded as the occlusion texture of lit materials, see [`gltf_export::save_mesh`].
+    lightmap_selection: LightmapSelection,
+}
+
+impl ExportOptions {
+    fn parse(options: &[String]) -> Option<Self> {
+        let mut parsed = ExportOptions::default();
+        for option in options {
+            match option.as_str() {
+                "--detail-props" => parsed.detail_props = true,
+                "--instance-props" => parsed.instance_props = true,
+                "--lights" => parsed.lights = true,
+                _ if option.starts_with("--lightmap=") => {
+                    parsed.lightmap = option.strip_prefix("--lightmap=").map(PathBuf::from)
+                }
+                _ if option.starts_with("--light-styles=") => {
+                    parsed.lightmap_selection.styles = option
+                        .strip_prefix("--light-styles=")?
+                        .split(',')
+                        .map(|style| style.parse().ok())
+                        .collect::<Option<_>>()?
+                }
+                _ if option.starts_with("--bump-basis=") => {
+                    parsed.lightmap_selection.bump_basis =
+                        option.strip_prefix("--bump-basis=")?.parse().ok()?
+                }
+                _ => parsed
+                    .content
+                    .push(PathBuf::from(option.strip_prefix("--content=")?)),
+            }
+        }
+        return Some(parsed);
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.as_slice() {
+        [command, filename, directory] if command == "extract" => {
+            return extract(filename, Path::new(directory))
+        }
+        [command, filename, directory, output] if command == "pack" => {
+            return pack(filename, Path::new(directory), output)
+        }
+        [filename, options @ ..] if ExportOptions::parse(options).is_some() => {
+            return export_map(filename, ExportOptions::parse(options).unwrap());
+        }
+        _ => {
+            eprintln!(
+                "Usage: bspparse <map.bsp> [--detail-props] [--instance-props] [--lights] [--content=<directory>]"
+            );
+            eprintln!(
+                "                [--lightmap=<file.hdr|file.exr>] [--light-styles=<style,...>] [--bump-basis=<0-3>]"
+            );
+            eprintln!("       bspparse extract <map.bsp> <directory>");
+            eprintln!("       bspparse pack <map.bsp> <directory> <output.bsp>");
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Writes every file embedded in the pakfile to `directory`.
+fn extract(filename: &str, directory: &Path) -> Result<(), Box<dyn Error>> {
+    let bsp = Bsp::open(filename)?;
+    let pakfile = bsp.pakfile()?;
+
+    for entry in pakfile.entries() {
+        let relative = PathBuf::from(entry.name.replace('\\', "/"));
+        // Don't let a malicious archive write outside of the directory
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            eprintln!("Skipping {}", entry.name);
+            continue;
+        }
+        if entry.name.ends_with('/') {
+            continue;
+        }
+
+        let path = directory.join(relative);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, pakfile.read_entry(entry)?)?;
+        println!(
+            "{} ({} bytes, {:?})",
+            entry.name,
+            CommaFormat(entry.size as usize),
+            entry.compression
+        );
+    }
+
+    return Ok(());
+}
+
+/// Adds every file under `directory` to the pakfile, replacing existing files
+/// with the same path.
+fn pack(filename: &str, directory: &Path, output: &str) -> Result<(), Box<dyn Error>> {
+    let bsp = Bsp::open(filename)?;
+    let mut pakfile = PakfileWriter::from_pakfile(bsp.pakfile()?)?;
+
+    let mut pending = vec![directory.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        if path.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                pending.push(entry?.path());
+            }
+            continue;
+        }
+
+        let relative = path.strip_prefix(directory)?;
+        let name = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        println!("{name}");
+        pakfile.insert(&name, fs::read(&path)?);
+    }
+
+    let mut writer = BspWriter::from_bsp(&bsp)?;
+    writer.set_pakfile(&pakfile)?;
+    writer.save(output)?;
+
+    return Ok(());
+}
+
+/// Exports the world geometry and the placement of the static props.
+fn export_map(filename: &str, options: ExportOptions) -> Result<(), Box<dyn Error>> {
+    // GoldSrc maps have no magic, they start with the version number
+    let mut magic = [0u8; 4];
+    File::open(filename)?.read_exact(&mut magic)?;
+    if goldsrc::SUPPORTED_VERSIONS.contains(&u32::from_le_bytes(magic)) {
+        return export_goldsrc(filename);
+    }
+    if &magic == b"IBSP" {
+        return export_quake3(filename);
+    }
+
+    let bsp = Bsp::open(filename)?;
+
+    println!("BSP Version = {:?}", bsp.version);
+    println!("Lump layout = {:?}", bsp.lump_layout);
+    println!("Byte order = {:?}", bsp.byte_order);
+
+    for (index, lump) in bsp.lumps.iter().enumerate() {
+        println!(
+            "{index:>4} id={:03?} offset={:<14} length={:<14} version={:<8}",
+            lump.id,
+            CommaFormat(lump.offset as usize),
+            CommaFormat(lump.length as usize),
+            lump.version
+        )
+    }
+
+    println!(
+        "Number of entities: {:}",
+        CommaFormat(bsp.entities()?.len())
+    );
+    println!("Number of faces: {:}", CommaFormat(bsp.faces()?.len()));
+    println!("Number of planes: {:}", CommaFormat(bsp.planes()?.len()));
+    println!(
+        "Number of vertexes: {:}",
+        CommaFormat(bsp.vertexes()?.len())
+    );
+    println!("Number of edges: {:}", CommaFormat(bsp.edges()?.len()));
+    println!(
+        "Number of surfedges: {:}",
+        CommaFormat(bsp.surface_edges()?.len())
+    );
+    println!(
+        "Number of static props: {:}",
+        CommaFormat(bsp.static_props()?.props.len())
+    );
+    println!(
+        "Number of detail props: {:}",
+        CommaFormat(bsp.detail_props()?.props.len())
+    );
+    println!(
+        "String data table size: {:}",
+        CommaFormat(bsp.texture_string_table()?.len())
+    );
+
+    let primitive_groups = parse_bsp::to_primitives(&bsp)?;
+
+    // Each model is loaded once for every skin it is used with
+    let mut loaded: Vec<((&str, i32), Option<usize>)> = vec![];
+    let mut models = vec![];
+    let mut props = vec![];
+    for static_prop in &bsp.static_props()?.props {
+        let key = (static_prop.model_name.as_str(), static_prop.skin);
+        let model = match loaded.iter().find(|(loaded_key, _)| *loaded_key == key) {
+            Some(&(_, model)) => model,
+            None => {
+                let model = StudioModel::load(&static_prop.model_name, |name| {
+                    read_game_file(bsp.pakfile()?, &options.content, name)
+                })
+                .and_then(|model| model.to_primitives(static_prop.skin.max(0) as usize));
+                let model = match model {
+                    Ok(model) => {
+                        models.push(model);
+                        Some(models.len() - 1)
+                    }
+                    Err(error) => {
+                        eprintln!("Could not load {}: {error}", static_prop.model_name);
+                        None
+                    }
+                };
+                loaded.push((key, model));
+                model
+            }
+        };
+
+        props.push(GltfProp {
+            model,
+            ..GltfProp::from_static_prop(static_prop)
+        });
+    }
+
+    // Sprites sharing a shape share a model, lit with the light styles of the lightmap
+    if options.detail_props {
+        let material = bsp
+            .entities()?
+            .iter()
+            .find(|entity| entity.classname() == Some("worldspawn"))
+            .and_then(|world| world.get("detailmaterial"))
+            .unwrap_or(parse_bsp::DEFAULT_DETAIL_MATERIAL);
+        let light_styles = match bsp.detail_prop_light_styles(false)? {
+            [] => bsp.detail_prop_light_styles(true)?,
+            light_styles => light_styles,
+        };
+
+        let mut shapes: Vec<(DetailSpriteShape, usize)> = vec![];
+        for detail_prop in &bsp.detail_props()?.props {
+            let Some(shape) = detail_prop.sprite_shape() else {
+                continue;
+            };
+            let model = match shapes.iter().find(|(known, _)| *known == shape) {
+                Some(&(_, model)) => model,
+                None => {
+                    let primitives = bsp.detail_props()?.sprite_primitives(shape)?;
+                    models.push(HashMap::from([(material.to_owned(), primitives)]));
+                    shapes.push((shape, models.len() - 1));
+                    models.len() - 1
+                }
+            };
+            let light = detail_prop.light(light_styles, &options.lightmap_selection.styles)?;
+            props.push(GltfProp {
+                model: Some(model),
+                color: Some(light),
+                ..GltfProp::from_detail_prop(detail_prop, material)
+            });
+        }
+    }
+
+    // Only the normal light style is cached
+    let selected;
+    let mut atlas = BrushGeometry::lightmap_atlas(&bsp)?;
+    if atlas.is_some() && options.lightmap_selection != LightmapSelection::default() {
+        selected = bsp.build_lightmap_atlas(&options.lightmap_selection)?;
+        atlas = Some(&selected);
+    }
+    if let (Some(atlas), Some(path)) = (atlas, &options.lightmap) {
+        atlas.save_linear(path)?;
+    }
+    let lightmap = atlas.map(|atlas| DynamicImage::ImageRgba8(atlas.to_rgba8()));
+
+    // Maps compiled with only HDR lighting have no LDR lights
+    let mut lights = vec![];
+    if options.lights {
+        let world_lights = match bsp.world_lights(false)? {
+            [] => bsp.world_lights(true)?,
+            world_lights => world_lights,
+        };
+        lights.extend(world_lights.iter().filter_map(GltfLight::from_world_light));
+    }
+
+    return export(
+        &primitive_groups,
+        &models,
+        &props,
+        options.instance_props,
+        lightmap.as_ref(),
+        &lights,
+        |name| Ok(image::open(format!("cache/textures/{name}.png"))?),
+    );
+}
+
+/// Reads a file embedded in the map, falling back to the content directories.
+fn read_game_file(pakfile: &Pakfile, content: &[PathBuf], name: &str) -> Result<Vec<u8>, BspError> {
+    if pakfile.entry(name).is_some() {
+        return pakfile.read(name);
+    }
+    for directory in content {
+        if let Ok(data) = fs::read(directory.join(name)) {
+            return Ok(data);
+        }
+    }
+    return Err(BspError::FileNotFound(name.to_owned()));
+}
+
+fn export_goldsrc(filename: &str) -> Result<(), Box<dyn Error>> {
+    let bsp = GoldSrcBsp::open(filename)?;
+
+    println!("BSP Version = {:?}", bsp.version);
+    println!("Number of faces: {:}", CommaFormat(bsp.faces.len()));
+    println!("Number of textures: {:}", CommaFormat(bsp.textures.len()));
+    println!(
+        "Number of clip nodes: {:}",
+        CommaFormat(bsp.clip_nodes.len())
+    );
+
+    let primitive_groups = parse_bsp::to_primitives(&bsp)?;
+
+    // Use the textures embedded in the map, falling back to the cache for
+    // textures stored in WAD files
+    return export(
+        &primitive_groups,
+        &[],
+        &[],
+        false,
+        None,
+        &[],
+        |name| match bsp.texture(name).and_then(|texture| texture.to_image(None)) {
+            Some(image) => Ok(DynamicImage::ImageRgba8(image)),
+            None => Ok(image::open(format!("cache/textures/{name}.png"))?),
+        },
+    );
+}
+
+fn export_quake3(filename: &str) -> Result<(), Box<dyn Error>> {
+    let bsp = Quake3Bsp::open(filename)?;
+
+    println!("BSP Version = {:?}", bsp.version);
+    println!("Number of faces: {:}", CommaFormat(bsp.faces.len()));
+    println!("Number of shaders: {:}", CommaFormat(bsp.shaders.len()));
+
+    let primitive_groups = bsp.to_primitives(8)?;
+
+    return export(&primitive_groups, &[], &[], false, None, &[], |name| {
+        Ok(image::open(format!("cache/textures/{name}.png"))?)
+    });
+}
+
+fn export(
+    primitive_groups: &HashMap<String, MaterialGroup>,
+    models: &[HashMap<String, MaterialGroup>],
+    props: &[GltfProp],
+    instancing: bool,
+    lightmap: Option<&DynamicImage>,
+    lights: &[GltfLight],
+    load_texture: impl Fn(&str) -> Result<DynamicImage, Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    for key in primitive_groups.keys() {
+        println!("{key}")
+    }
+
+    // Models use many materials, missing textures should not stop the export
+    let models = models
+        .iter()
+        .map(|groups| GltfModel {
+            objects: groups
+                .iter()
+                .map(|(name, primitive)| {
+                    let texture = load_texture(name).unwrap_or_else(|_| {
+                        eprintln!("Missing texture {name}");
+                        DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba([255; 4])))
+                    });
+                    gltf_object(name, primitive, texture)
+                })
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+
+    gltf_export::save_mesh(
+        "out.gltf".to_string(),
+        primitive_groups
+            .iter()
+            .map(|(name, primitive)| Ok(gltf_object(name, primitive, load_texture(name)?)))
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?
+            .as_slice(),
+        &models,
+        props,
+        instancing,
+        lightmap,
+        lights,
+    )?;
+
+    return Ok(());
+}
+
+fn gltf_object<'a>(
+    name: &'a str,
+    primitive: &'a MaterialGroup,
+    texture: DynamicImage,
+) -> gltf_export::GltfObject<'a> {
+    return gltf_export::GltfObject {
+        vertexes: &primitive.verticies,
+        normals: &primitive.normals,
+        uvs: &primitive.uvs,
+        lightmap_uvs: &primitive.lightmap_uvs,
+        indices: &primitive.indices,
+        texture,
+        name,
+    };
 }
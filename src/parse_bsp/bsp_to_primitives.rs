@@ -1,51 +1,169 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io::{Read, Seek},
+};
 
 use ordered_float::OrderedFloat;
 
 use crate::vector::{Vec2, Vec3};
 
 use super::{
-    displacement::DisplacementInfo,
+    brush_model::BrushModel,
+    displacement::{DisplacementInfo, DisplacementVertex},
     edge::Edge,
+    error::lookup,
     face::Face,
+    lightmap::LightmapAtlas,
+    plane::Plane,
     surfedges::SurfEdge,
     texdata::TextureData,
     texinfo::{surface_flags, TextureInfo},
     vertex::Vertex,
-    ParsedBspFile,
+    Bsp, BspError,
 };
 
-pub(super) struct MaterialGroup {
+pub struct MaterialGroup {
     pub verticies: Vec<Vec3>,
     pub normals: Vec<Vec3>,
     pub uvs: Vec<Vec2>,
+    /// Coordinates in the lightmap atlas, empty when the geometry has no lightmaps.
+    pub lightmap_uvs: Vec<Vec2>,
     pub indices: Vec<usize>,
 }
 
-pub(super) fn to_primitives(bsp: ParsedBspFile) -> HashMap<String, MaterialGroup> {
+/// Brush geometry in the Source layout, implemented by every map format that can be
+/// triangulated by [`to_primitives`].
+pub trait BrushGeometry {
+    fn faces(&self) -> Result<&[Face], BspError>;
+    fn planes(&self) -> Result<&[Plane], BspError>;
+    fn vertexes(&self) -> Result<&[Vertex], BspError>;
+    fn edges(&self) -> Result<&[Edge], BspError>;
+    fn surface_edges(&self) -> Result<&[SurfEdge], BspError>;
+    fn texture_infos(&self) -> Result<&[TextureInfo], BspError>;
+    fn texture_data(&self) -> Result<&[TextureData], BspError>;
+    fn texture_name(&self, texture_data: TextureData) -> Result<&str, BspError>;
+    fn displacement_info(&self) -> Result<&[DisplacementInfo], BspError>;
+    fn displacement_vertexes(&self) -> Result<&[DisplacementVertex], BspError>;
+    fn brush_models(&self) -> Result<&[BrushModel], BspError>;
+
+    /// The group [`to_primitives`] puts faces using `texture_name` in: the
+    /// top folder of the material in upper case, looking past `maps/<map name>/`
+    /// for materials packed with the map. Materials outside of a folder share
+    /// the `""` group.
+    fn material_category(&self, texture_name: &str) -> String {
+        return match texture_name.split_once('/').unwrap_or(("", "")) {
+            ("maps", k) => k.split('/').nth(1).unwrap_or(k).to_ascii_uppercase(),
+            (a, _) => a.to_ascii_uppercase(),
+        };
+    }
+
+    /// The lightmaps of the faces, `None` for formats or maps without them.
+    fn lightmap_atlas(&self) -> Result<Option<&LightmapAtlas>, BspError> {
+        return Ok(None);
+    }
+}
+
+impl<R: Read + Seek> BrushGeometry for Bsp<R> {
+    fn faces(&self) -> Result<&[Face], BspError> {
+        return Bsp::faces(self);
+    }
+
+    fn planes(&self) -> Result<&[Plane], BspError> {
+        return Bsp::planes(self);
+    }
+
+    fn vertexes(&self) -> Result<&[Vertex], BspError> {
+        return Bsp::vertexes(self);
+    }
+
+    fn edges(&self) -> Result<&[Edge], BspError> {
+        return Bsp::edges(self);
+    }
+
+    fn surface_edges(&self) -> Result<&[SurfEdge], BspError> {
+        return Bsp::surface_edges(self);
+    }
+
+    fn texture_infos(&self) -> Result<&[TextureInfo], BspError> {
+        return Bsp::texture_infos(self);
+    }
+
+    fn texture_data(&self) -> Result<&[TextureData], BspError> {
+        return Bsp::texture_data(self);
+    }
+
+    fn texture_name(&self, texture_data: TextureData) -> Result<&str, BspError> {
+        let table = self.texture_string_table()?;
+        let offset = lookup(
+            table,
+            texture_data.name_index as usize,
+            "texture string table",
+        )?;
+        return self.texture_string_array()?.get_str(offset.0 as usize);
+    }
+
+    fn displacement_info(&self) -> Result<&[DisplacementInfo], BspError> {
+        return Bsp::displacement_info(self);
+    }
+
+    fn displacement_vertexes(&self) -> Result<&[DisplacementVertex], BspError> {
+        return Bsp::displacement_vertexes(self);
+    }
+
+    fn brush_models(&self) -> Result<&[BrushModel], BspError> {
+        return Bsp::brush_models(self);
+    }
+
+    fn lightmap_atlas(&self) -> Result<Option<&LightmapAtlas>, BspError> {
+        if self.lighting()?.is_empty() {
+            return Ok(None);
+        }
+        return Bsp::lightmap_atlas(self).map(Some);
+    }
+}
+
+/// Triangulates the world brush model, grouping the geometry by
+/// [`BrushGeometry::material_category`].
+pub fn to_primitives<G: BrushGeometry>(
+    bsp: &G,
+) -> Result<HashMap<String, MaterialGroup>, BspError> {
     let mut groups: HashMap<String, MaterialGroup> = HashMap::new();
+    let edges = bsp.edges()?;
 
-    let get_edge = |surface_edge: &SurfEdge| -> Edge {
+    let get_edge = |surface_edge: &SurfEdge| -> Result<Edge, BspError> {
         if surface_edge.0 > 0 {
-            return bsp.edges[surface_edge.0 as usize];
+            return Ok(*lookup(edges, surface_edge.0 as usize, "edges")?);
         } else {
-            return bsp.edges[-surface_edge.0 as usize].reverse();
+            return Ok(lookup(edges, surface_edge.0.unsigned_abs() as usize, "edges")?.reverse());
         }
     };
 
-    for (_index, face) in bsp.faces[bsp.brush_models[0].first_face as usize
-        ..bsp.brush_models[0].first_face as usize + bsp.brush_models[0].num_faces as usize]
+    let lightmap_atlas = bsp.lightmap_atlas()?;
+    let world = lookup(bsp.brush_models()?, 0, "brush models")?;
+    let world_faces = range(
+        bsp.faces()?,
+        world.first_face as usize,
+        world.num_faces as usize,
+        "faces",
+    )?;
+
+    for (face_index, face) in (world.first_face as usize..).zip(world_faces) {
+        let face_edges = range(
+            bsp.surface_edges()?,
+            face.first_edge as usize,
+            face.num_edges as usize,
+            "surface edges",
+        )?
         .iter()
-        .enumerate()
-    {
-        let face_edges: Vec<_> = bsp.surface_edges
-            [face.first_edge as usize..(face.first_edge + face.num_edges as u32) as usize]
-            .iter()
-            .map(get_edge)
-            .collect();
-        let normal = bsp.planes[face.planenum as usize].normal;
+        .map(get_edge)
+        .collect::<Result<Vec<_>, _>>()?;
+        let normal = lookup(bsp.planes()?, face.planenum as usize, "planes")?.normal;
 
-        let texture_info = bsp.texture_infos[face.tex_info as usize];
+        let texture_info = *lookup(
+            bsp.texture_infos()?,
+            face.tex_info as usize,
+            "texture infos",
+        )?;
 
         if texture_info.flags & surface_flags::SURF_NODRAW > 0
             || texture_info.flags & surface_flags::SURF_SKIP > 0
@@ -55,91 +173,147 @@ pub(super) fn to_primitives(bsp: ParsedBspFile) -> HashMap<String, MaterialGroup
             continue;
         }
 
-        let texture_data = bsp.texture_data[texture_info.texture_data_index as usize];
-        let texture_name = bsp
-            .texture_string_array
-            .get_str(bsp.texture_string_table[texture_data.name_index as usize].0 as usize)
-            .unwrap();
+        let texture_data = *lookup(
+            bsp.texture_data()?,
+            texture_info.texture_data_index as usize,
+            "texture data",
+        )?;
+        let texture_name = bsp.texture_name(texture_data)?;
         if texture_name.starts_with("TOOL") {
             continue;
         }
-        let category = match texture_name.split_once('/').unwrap_or(("", "")) {
-            ("maps", k) => k.split('/').nth(1).unwrap().to_ascii_uppercase(),
-            (a, _) => a.to_ascii_uppercase(),
-        };
+        let category = bsp.material_category(texture_name);
 
         let group = groups.entry(category).or_insert(MaterialGroup {
             verticies: vec![],
             normals: vec![],
             uvs: vec![],
+            lightmap_uvs: vec![],
             indices: vec![],
         });
+        let lightmap_uv = |position: Vec3| {
+            lightmap_atlas.map(|atlas| atlas.uv(face_index, face, texture_info, position))
+        };
 
         if face.displacement_info != -1 {
             handle_displacement_face(
                 group,
-                &bsp,
+                bsp,
                 *face,
                 normal,
                 texture_info,
                 texture_data,
                 face_edges,
-                bsp.displacement_info[face.displacement_info as usize],
-            )
+                *lookup(
+                    bsp.displacement_info()?,
+                    face.displacement_info as usize,
+                    "displacement info",
+                )?,
+                lightmap_uv,
+            )?
         } else {
-            handle_normal_face(group, &bsp, normal, texture_info, texture_data, face_edges)
+            handle_normal_face(
+                group,
+                bsp,
+                normal,
+                texture_info,
+                texture_data,
+                face_edges,
+                lightmap_uv,
+            )?
         }
     }
 
-    return groups;
+    return Ok(groups);
 }
 
-fn handle_normal_face(
+/// Bounds-checked equivalent of `&items[start..start + count]`.
+fn range<'a, T>(
+    items: &'a [T],
+    start: usize,
+    count: usize,
+    kind: &'static str,
+) -> Result<&'a [T], BspError> {
+    return items
+        .get(start..start + count)
+        .ok_or(BspError::IndexOutOfRange {
+            kind,
+            index: start + count,
+            length: items.len(),
+        });
+}
+
+fn handle_normal_face<G: BrushGeometry>(
     group: &mut MaterialGroup,
-    bsp: &ParsedBspFile,
+    bsp: &G,
     normal: Vec3,
     texture_info: TextureInfo,
     texture_data: TextureData,
     face_edges: Vec<Edge>,
-) {
+    lightmap_uv: impl Fn(Vec3) -> Option<Vec2>,
+) -> Result<(), BspError> {
     let initial_index = group.verticies.len();
 
     let mut push_vertex = |vertex: Vertex| {
         group.verticies.push(vertex.0.to_y_up() * 0.1);
         group.normals.push(normal.to_y_up());
         group.uvs.push(texture_info.get_uv(vertex.0, texture_data));
+        group.lightmap_uvs.extend(lightmap_uv(vertex.0));
     };
 
-    push_vertex(bsp.vertexes[face_edges[0].first as usize]);
+    if face_edges.len() < 3 {
+        return Ok(());
+    }
+
+    push_vertex(*lookup(
+        bsp.vertexes()?,
+        face_edges[0].first as usize,
+        "vertexes",
+    )?);
 
     for (index, edge) in face_edges[1..face_edges.len() - 1].iter().enumerate() {
         group.indices.push(initial_index);
         group.indices.push(initial_index + 2 + 2 * index);
         group.indices.push(initial_index + 1 + 2 * index);
 
-        push_vertex(bsp.vertexes[edge.first as usize]);
-        push_vertex(bsp.vertexes[edge.second as usize]);
+        push_vertex(*lookup(bsp.vertexes()?, edge.first as usize, "vertexes")?);
+        push_vertex(*lookup(bsp.vertexes()?, edge.second as usize, "vertexes")?);
     }
+
+    return Ok(());
 }
 
-fn handle_displacement_face(
+#[allow(clippy::too_many_arguments)]
+fn handle_displacement_face<G: BrushGeometry>(
     group: &mut MaterialGroup,
-    bsp: &ParsedBspFile,
+    bsp: &G,
     _face: Face,
-    normal: Vec3,
+    _normal: Vec3,
     texture_info: TextureInfo,
     texture_data: TextureData,
     face_edges: Vec<Edge>,
     displacement_info: DisplacementInfo,
-) {
+    lightmap_uv: impl Fn(Vec3) -> Option<Vec2>,
+) -> Result<(), BspError> {
     let power = displacement_info.power;
 
     let initial_index = group.verticies.len();
-    assert_eq!(face_edges.len(), 4);
-    assert!(1 <= power && power <= 4);
+    if face_edges.len() != 4 || !(1..=4).contains(&power) {
+        return Err(BspError::InvalidDisplacement {
+            edges: face_edges.len(),
+            power,
+        });
+    }
 
     let edges: [Edge; 4] = face_edges.try_into().unwrap();
-    let mut corners = edges.map(|i| bsp.vertexes[i.first as usize].0);
+    let mut corners = [Vec3 {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    }; 4];
+    for (corner, edge) in corners.iter_mut().zip(edges) {
+        *corner = lookup(bsp.vertexes()?, edge.first as usize, "vertexes")?.0;
+    }
 
     let mut starting_corner = (find_lowest_index(corners, displacement_info)) % 4;
     starting_corner = (2 + starting_corner) % 4;
@@ -161,8 +335,11 @@ fn handle_displacement_face(
     for x in 0..=faces_per_side {
         for y in 0..=faces_per_side {
             let raw_position = interpolate(x, y);
-            let vertex = bsp.displacement_vertexes
-                [displacement_info.vertex_start as usize + x * (faces_per_side + 1) + y];
+            let vertex = lookup(
+                bsp.displacement_vertexes()?,
+                displacement_info.vertex_start as usize + x * (faces_per_side + 1) + y,
+                "displacement vertexes",
+            )?;
 
             // if vertex.length > max_magnitude {
             //     max_magnitude = vertex.length;
@@ -175,6 +352,7 @@ fn handle_displacement_face(
             group
                 .uvs
                 .push(texture_info.get_uv(raw_position, texture_data));
+            group.lightmap_uvs.extend(lightmap_uv(raw_position));
         }
     }
 
@@ -185,7 +363,7 @@ fn handle_displacement_face(
             let corners = [[x + 1, y], [x, y + 1], [x - 1, y], [x, y - 1]]
                 .iter()
                 .enumerate()
-                .filter(|(index, k)| k.iter().all(|m| *m >= 0 && *m <= faces_per_side as isize))
+                .filter(|(_index, k)| k.iter().all(|m| *m >= 0 && *m <= faces_per_side as isize))
                 .map(|(index, t)| {
                     (
                         index,
@@ -236,6 +414,8 @@ fn handle_displacement_face(
             group.indices.extend_from_slice(&indicies);
         }
     }
+
+    return Ok(());
 }
 
 fn find_lowest_index(vertexes: [Vec3; 4], disp: DisplacementInfo) -> usize {
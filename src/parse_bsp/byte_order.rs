@@ -0,0 +1,65 @@
+/// Byte order of the values stored in a BSP file.
+///
+/// PC maps are little endian, the Xbox 360 and PS3 ports of Orange Box games
+/// store everything except the LZMA headers big endian.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    pub fn u16(self, bytes: &[u8]) -> u16 {
+        let bytes = bytes.try_into().unwrap();
+        return match self {
+            ByteOrder::Little => u16::from_le_bytes(bytes),
+            ByteOrder::Big => u16::from_be_bytes(bytes),
+        };
+    }
+
+    pub fn i16(self, bytes: &[u8]) -> i16 {
+        return self.u16(bytes) as i16;
+    }
+
+    pub fn u32(self, bytes: &[u8]) -> u32 {
+        let bytes = bytes.try_into().unwrap();
+        return match self {
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+            ByteOrder::Big => u32::from_be_bytes(bytes),
+        };
+    }
+
+    pub fn i32(self, bytes: &[u8]) -> i32 {
+        return self.u32(bytes) as i32;
+    }
+
+    pub fn f32(self, bytes: &[u8]) -> f32 {
+        return f32::from_bits(self.u32(bytes));
+    }
+
+    pub fn put_u16(self, out: &mut Vec<u8>, value: u16) {
+        out.extend_from_slice(&match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        });
+    }
+
+    pub fn put_i16(self, out: &mut Vec<u8>, value: i16) {
+        self.put_u16(out, value as u16);
+    }
+
+    pub fn put_u32(self, out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&match self {
+            ByteOrder::Little => value.to_le_bytes(),
+            ByteOrder::Big => value.to_be_bytes(),
+        });
+    }
+
+    pub fn put_i32(self, out: &mut Vec<u8>, value: i32) {
+        self.put_u32(out, value as u32);
+    }
+
+    pub fn put_f32(self, out: &mut Vec<u8>, value: f32) {
+        self.put_u32(out, value.to_bits());
+    }
+}
@@ -0,0 +1,50 @@
+/// A lighting sample stored as an 8 bit color with a shared power of two exponent.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ColorRGBExp32 {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub exponent: i8,
+}
+
+impl ColorRGBExp32 {
+    pub(super) fn from_bytes(bytes: [u8; 4]) -> Self {
+        return ColorRGBExp32 {
+            r: bytes[0],
+            g: bytes[1],
+            b: bytes[2],
+            exponent: bytes[3] as i8,
+        };
+    }
+
+    /// The color in linear space, where 1 is the brightest an LDR lightmap can show.
+    pub fn to_linear(self) -> [f32; 3] {
+        let scale = 2f32.powi(self.exponent as i32) / 255.0;
+        return [self.r, self.g, self.b].map(|k| k as f32 * scale);
+    }
+
+    /// The color gamma corrected for display, clamping anything brighter than white.
+    pub fn to_srgb8(self) -> [u8; 3] {
+        return self.to_linear().map(linear_to_srgb8);
+    }
+}
+
+/// Gamma corrects a linear color component for display, clamping it to white.
+pub(super) fn linear_to_srgb8(value: f32) -> u8 {
+    return (value.clamp(0.0, 1.0).powf(1.0 / 2.2) * 255.0).round() as u8;
+}
+
+/// Ambient lighting from each of the six axis directions.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CompressedLightCube {
+    pub colors: [ColorRGBExp32; 6],
+}
+
+impl CompressedLightCube {
+    pub(super) fn from_bytes(bytes: [u8; 24]) -> Self {
+        return CompressedLightCube {
+            colors: [0, 1, 2, 3, 4, 5]
+                .map(|k| ColorRGBExp32::from_bytes(bytes[k * 4..k * 4 + 4].try_into().unwrap())),
+        };
+    }
+}
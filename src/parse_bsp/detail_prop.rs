@@ -0,0 +1,518 @@
+use crate::vector::{Vec2, Vec3};
+
+use super::{
+    bsp_to_primitives::MaterialGroup, color::ColorRGBExp32, error::lookup, parse_vector2,
+    parse_vector3, BspError, ByteOrder,
+};
+
+/// Game lump id of the detail props.
+pub const DETAIL_PROP_LUMP_ID: [u8; 4] = *b"dprp";
+/// Game lump id of the light styles of the detail props.
+pub const DETAIL_PROP_LIGHTING_LUMP_ID: [u8; 4] = *b"dplt";
+/// Game lump id of the HDR light styles of the detail props.
+pub const DETAIL_PROP_LIGHTING_HDR_LUMP_ID: [u8; 4] = *b"dplh";
+
+/// Material used for detail sprites when the world entity does not set `detailmaterial`.
+pub const DEFAULT_DETAIL_MATERIAL: &str = "detail/detailsprites";
+
+/// The contents of the `dprp` game lump.
+#[derive(Clone, Debug, Default)]
+pub struct DetailPropLump {
+    /// Paths of the models used by props of type [`DetailPropType::Model`].
+    pub model_names: Vec<String>,
+    pub sprites: Vec<DetailSprite>,
+    pub props: Vec<DetailProp>,
+}
+
+/// A rectangle of the detail sprite sheet.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct DetailSprite {
+    /// Upper left corner of the quad in world units, relative to the origin.
+    pub upper_left: Vec2,
+    /// Lower right corner of the quad in world units, relative to the origin.
+    pub lower_right: Vec2,
+    pub texture_upper_left: Vec2,
+    pub texture_lower_right: Vec2,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DetailPropType {
+    Model,
+    Sprite,
+    /// Two sprites crossing each other.
+    ShapeCross,
+    /// Three sprites forming a triangle.
+    ShapeTri,
+    Other(u8),
+}
+
+impl DetailPropType {
+    fn from_id(id: u8) -> Self {
+        return match id {
+            0 => DetailPropType::Model,
+            1 => DetailPropType::Sprite,
+            2 => DetailPropType::ShapeCross,
+            3 => DetailPropType::ShapeTri,
+            other => DetailPropType::Other(other),
+        };
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DetailPropOrientation {
+    /// Oriented by the angles of the prop.
+    Normal,
+    /// Always faces the camera.
+    ScreenAligned,
+    /// Faces the camera, only rotating around the vertical axis.
+    ScreenAlignedVertical,
+    Other(u8),
+}
+
+impl DetailPropOrientation {
+    fn from_id(id: u8) -> Self {
+        return match id {
+            0 => DetailPropOrientation::Normal,
+            1 => DetailPropOrientation::ScreenAligned,
+            2 => DetailPropOrientation::ScreenAlignedVertical,
+            other => DetailPropOrientation::Other(other),
+        };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DetailProp {
+    pub origin: Vec3,
+    /// Pitch, yaw and roll in degrees, stored in `x`, `y` and `z`.
+    pub angles: Vec3,
+    /// Index into `model_names` for models, into `sprites` otherwise.
+    pub detail_model: u16,
+    pub leaf: u16,
+    pub lighting: ColorRGBExp32,
+    /// Index of the first light style in the `dplt` or `dplh` lump.
+    pub first_light_style: u32,
+    pub light_style_count: u8,
+    pub sway_amount: u8,
+    pub shape_angle: u8,
+    pub shape_size: u8,
+    pub orientation: DetailPropOrientation,
+    pub prop_type: DetailPropType,
+    /// Scale of sprites.
+    pub scale: f32,
+}
+
+/// Additional lighting of a detail prop from a switchable light.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct DetailPropLightStyle {
+    pub lighting: ColorRGBExp32,
+    pub style: u8,
+}
+
+const DETAIL_PROP_SIZE: usize = 52;
+
+pub(super) fn parse_detail_props(
+    data: &[u8],
+    version: u16,
+    order: ByteOrder,
+) -> Result<DetailPropLump, BspError> {
+    if version != 4 {
+        return Err(BspError::UnsupportedLumpVersion {
+            lump: "detail props",
+            version: version as u32,
+        });
+    }
+
+    let mut position = 0;
+    let mut take_records = |size: usize| -> Result<&[u8], BspError> {
+        let count = data
+            .get(position..position + 4)
+            .map(|bytes| order.u32(bytes) as usize)
+            .ok_or(BspError::BadRecordSize {
+                length: data.len(),
+                record_size: 4,
+            })?;
+        let bytes =
+            data.get(position + 4..position + 4 + count * size)
+                .ok_or(BspError::BadRecordSize {
+                    length: data.len(),
+                    record_size: size,
+                })?;
+        position += 4 + count * size;
+        return Ok(bytes);
+    };
+
+    let model_names = take_records(128)?
+        .chunks_exact(128)
+        .map(|name| {
+            let end = name.iter().position(|&k| k == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..end]).into_owned()
+        })
+        .collect();
+
+    let sprites = take_records(32)?
+        .chunks_exact(32)
+        .map(|bytes| DetailSprite {
+            upper_left: parse_vector2(&bytes[0..8], order),
+            lower_right: parse_vector2(&bytes[8..16], order),
+            texture_upper_left: parse_vector2(&bytes[16..24], order),
+            texture_lower_right: parse_vector2(&bytes[24..32], order),
+        })
+        .collect();
+
+    let props = take_records(DETAIL_PROP_SIZE)?
+        .chunks_exact(DETAIL_PROP_SIZE)
+        .map(|bytes| DetailProp {
+            origin: parse_vector3(&bytes[0..12], order),
+            angles: parse_vector3(&bytes[12..24], order),
+            detail_model: order.u16(&bytes[24..26]),
+            leaf: order.u16(&bytes[26..28]),
+            lighting: ColorRGBExp32::from_bytes(bytes[28..32].try_into().unwrap()),
+            first_light_style: order.u32(&bytes[32..36]),
+            light_style_count: bytes[36],
+            sway_amount: bytes[37],
+            shape_angle: bytes[38],
+            shape_size: bytes[39],
+            orientation: DetailPropOrientation::from_id(bytes[40]),
+            // Followed by 3 bytes of padding
+            prop_type: DetailPropType::from_id(bytes[44]),
+            scale: order.f32(&bytes[48..52]),
+        })
+        .collect();
+
+    return Ok(DetailPropLump {
+        model_names,
+        sprites,
+        props,
+    });
+}
+
+pub(super) fn parse_detail_prop_light_styles(
+    data: &[u8],
+    version: u16,
+    order: ByteOrder,
+) -> Result<Vec<DetailPropLightStyle>, BspError> {
+    if version != 0 {
+        return Err(BspError::UnsupportedLumpVersion {
+            lump: "detail prop lighting",
+            version: version as u32,
+        });
+    }
+
+    let count = data.get(0..4).map_or(0, |bytes| order.u32(bytes)) as usize;
+    return Ok(data
+        .get(4..4 + count * 5)
+        .ok_or(BspError::BadRecordSize {
+            length: data.len(),
+            record_size: 5,
+        })?
+        .chunks_exact(5)
+        .map(|bytes| DetailPropLightStyle {
+            lighting: ColorRGBExp32::from_bytes(bytes[0..4].try_into().unwrap()),
+            style: bytes[4],
+        })
+        .collect());
+}
+
+/// What a sprite prop looks like apart from its placement, props with the
+/// same shape can share a mesh.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct DetailSpriteShape {
+    /// Index into [`DetailPropLump::sprites`].
+    pub sprite: u16,
+    /// 1 for a sprite turned by the angles of the prop, otherwise the number
+    /// of upright quads crossing at the origin.
+    pub quad_count: u8,
+}
+
+impl DetailProp {
+    /// The shape of a sprite prop, `None` for props of type
+    /// [`DetailPropType::Model`] and unknown types.
+    ///
+    /// Sprites facing the camera can't be represented in a static mesh, they
+    /// become two upright quads crossing at the origin instead. Shapes become
+    /// two or three crossing quads, without the tilt given by `shape_angle`.
+    pub fn sprite_shape(&self) -> Option<DetailSpriteShape> {
+        let quad_count = match (self.prop_type, self.orientation) {
+            (DetailPropType::Sprite, DetailPropOrientation::Normal) => 1,
+            (DetailPropType::Sprite, _) | (DetailPropType::ShapeCross, _) => 2,
+            (DetailPropType::ShapeTri, _) => 3,
+            (DetailPropType::Model, _) | (DetailPropType::Other(_), _) => return None,
+        };
+        return Some(DetailSpriteShape {
+            sprite: self.detail_model,
+            quad_count,
+        });
+    }
+
+    /// The angles placing the [`DetailPropLump::sprite_primitives`] of the
+    /// prop's shape. Upright quads only keep the yaw.
+    pub fn sprite_angles(&self) -> Vec3 {
+        if self
+            .sprite_shape()
+            .is_some_and(|shape| shape.quad_count == 1)
+        {
+            return self.angles;
+        }
+        return Vec3 {
+            x: 0.0,
+            y: self.angles.y,
+            z: 0.0,
+        };
+    }
+
+    /// Linear lighting of the prop, adding up the light styles in `styles`.
+    /// Style 0 is the prop's own lighting, others come from `light_styles`,
+    /// the contents of the `dplt` or `dplh` lump.
+    pub fn light(
+        &self,
+        light_styles: &[DetailPropLightStyle],
+        styles: &[u8],
+    ) -> Result<Vec3, BspError> {
+        let to_vec3 = |color: ColorRGBExp32| {
+            let [x, y, z] = color.to_linear();
+            Vec3 { x, y, z }
+        };
+        let mut light = match styles.contains(&0) {
+            true => to_vec3(self.lighting),
+            false => Vec3 {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        };
+
+        let first = self.first_light_style as usize;
+        for index in first..first + self.light_style_count as usize {
+            let light_style = lookup(light_styles, index, "detail prop light styles")?;
+            if styles.contains(&light_style.style) {
+                light += to_vec3(light_style.lighting);
+            }
+        }
+        return Ok(light);
+    }
+}
+
+impl DetailPropLump {
+    /// Builds the double sided quads of `shape` at a scale of 1, textured with
+    /// the detail material. They are centered on the origin and still have to
+    /// be placed with the origin, [`DetailProp::sprite_angles`] and scale of
+    /// each prop.
+    pub fn sprite_primitives(&self, shape: DetailSpriteShape) -> Result<MaterialGroup, BspError> {
+        let mut group = MaterialGroup {
+            verticies: vec![],
+            normals: vec![],
+            uvs: vec![],
+            lightmap_uvs: vec![],
+            indices: vec![],
+        };
+        let sprite = *lookup(&self.sprites, shape.sprite as usize, "detail sprites")?;
+
+        for quad in 0..shape.quad_count {
+            let yaw = Vec3 {
+                x: 0.0,
+                y: 180.0 * quad as f32 / shape.quad_count as f32,
+                z: 0.0,
+            };
+            let [_, right, up] = yaw.angle_vectors();
+            push_sprite_quad(&mut group, sprite, right, up);
+        }
+
+        return Ok(group);
+    }
+}
+
+fn push_sprite_quad(group: &mut MaterialGroup, sprite: DetailSprite, right: Vec3, up: Vec3) {
+    let upper_left = sprite.upper_left;
+    let lower_right = sprite.lower_right;
+    let corner = |x: f32, y: f32| right * x + up * y;
+
+    let corners = [
+        (
+            corner(upper_left.x, upper_left.y),
+            sprite.texture_upper_left,
+        ),
+        (
+            corner(upper_left.x, lower_right.y),
+            Vec2 {
+                x: sprite.texture_upper_left.x,
+                y: sprite.texture_lower_right.y,
+            },
+        ),
+        (
+            corner(lower_right.x, lower_right.y),
+            sprite.texture_lower_right,
+        ),
+        (
+            corner(lower_right.x, upper_left.y),
+            Vec2 {
+                x: sprite.texture_lower_right.x,
+                y: sprite.texture_upper_left.y,
+            },
+        ),
+    ];
+
+    let normal = right.cross(up).normalize();
+    for (side, normal) in [normal, normal * -1.0].into_iter().enumerate() {
+        let initial_index = group.verticies.len();
+        for (position, uv) in corners {
+            group.verticies.push(position.to_y_up() * 0.1);
+            group.normals.push(normal.to_y_up());
+            group.uvs.push(uv);
+        }
+
+        let mut indices = [0, 1, 2, 0, 2, 3].map(|k| initial_index + k);
+        if side == 1 {
+            indices.reverse();
+        }
+        group.indices.extend_from_slice(&indices);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec2(x: f32, y: f32) -> Vec2 {
+        return Vec2 { x, y };
+    }
+
+    fn vec3(x: f32, y: f32, z: f32) -> Vec3 {
+        return Vec3 { x, y, z };
+    }
+
+    fn color(r: u8, g: u8, b: u8) -> ColorRGBExp32 {
+        return ColorRGBExp32 {
+            r,
+            g,
+            b,
+            exponent: 0,
+        };
+    }
+
+    fn grass(prop_type: DetailPropType, orientation: DetailPropOrientation) -> DetailProp {
+        return DetailProp {
+            origin: vec3(100.0, 200.0, 0.0),
+            angles: vec3(10.0, 90.0, 5.0),
+            detail_model: 0,
+            leaf: 0,
+            lighting: color(255, 0, 0),
+            first_light_style: 1,
+            light_style_count: 2,
+            sway_amount: 0,
+            shape_angle: 0,
+            shape_size: 0,
+            orientation,
+            prop_type,
+            scale: 2.0,
+        };
+    }
+
+    fn lump() -> DetailPropLump {
+        return DetailPropLump {
+            model_names: vec![],
+            sprites: vec![DetailSprite {
+                upper_left: vec2(-10.0, 20.0),
+                lower_right: vec2(10.0, 0.0),
+                texture_upper_left: vec2(0.0, 0.0),
+                texture_lower_right: vec2(0.5, 0.25),
+            }],
+            props: vec![],
+        };
+    }
+
+    #[test]
+    fn sprite_shapes() {
+        let shape = |prop_type, orientation| {
+            let prop = grass(prop_type, orientation);
+            return prop
+                .sprite_shape()
+                .map(|shape| (shape.quad_count, prop.sprite_angles()));
+        };
+        let yaw = vec3(0.0, 90.0, 0.0);
+        assert_eq!(
+            shape(DetailPropType::Sprite, DetailPropOrientation::Normal),
+            Some((1, vec3(10.0, 90.0, 5.0)))
+        );
+        assert_eq!(
+            shape(DetailPropType::Sprite, DetailPropOrientation::ScreenAligned),
+            Some((2, yaw))
+        );
+        assert_eq!(
+            shape(DetailPropType::ShapeCross, DetailPropOrientation::Normal),
+            Some((2, yaw))
+        );
+        assert_eq!(
+            shape(DetailPropType::ShapeTri, DetailPropOrientation::Normal),
+            Some((3, yaw))
+        );
+        assert_eq!(
+            shape(DetailPropType::Model, DetailPropOrientation::Normal),
+            None
+        );
+    }
+
+    #[test]
+    fn cross_quads_are_double_sided_and_unscaled() {
+        let shape = DetailSpriteShape {
+            sprite: 0,
+            quad_count: 2,
+        };
+        let group = lump().sprite_primitives(shape).unwrap();
+        assert_eq!(group.verticies.len(), 16);
+        assert_eq!(group.indices.len(), 24);
+        assert!(group.lightmap_uvs.is_empty());
+
+        // The first quad faces forward, spanning the y axis of Source
+        let close = |a: Vec3, b: Vec3| a.distance_squared(&b) < 1e-6;
+        assert!(close(group.verticies[0], vec3(0.0, 2.0, -1.0)));
+        assert!(close(group.verticies[2], vec3(0.0, 0.0, 1.0)));
+        assert_eq!(group.uvs[2], vec2(0.5, 0.25));
+        // The second is turned a quarter around the vertical axis
+        assert!(close(group.verticies[8], vec3(-1.0, 2.0, 0.0)));
+
+        let missing = DetailSpriteShape {
+            sprite: 1,
+            quad_count: 1,
+        };
+        assert!(matches!(
+            lump().sprite_primitives(missing),
+            Err(BspError::IndexOutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn light_adds_up_selected_styles() {
+        let prop = grass(DetailPropType::Sprite, DetailPropOrientation::Normal);
+        let light_styles = [
+            DetailPropLightStyle {
+                lighting: color(0, 0, 255),
+                style: 32,
+            },
+            DetailPropLightStyle {
+                lighting: color(0, 255, 0),
+                style: 32,
+            },
+            DetailPropLightStyle {
+                lighting: color(0, 0, 255),
+                style: 33,
+            },
+        ];
+
+        assert_eq!(
+            prop.light(&light_styles, &[0]).unwrap(),
+            vec3(1.0, 0.0, 0.0)
+        );
+        assert_eq!(
+            prop.light(&light_styles, &[0, 32, 33]).unwrap(),
+            vec3(1.0, 1.0, 1.0)
+        );
+        assert_eq!(
+            prop.light(&light_styles, &[32]).unwrap(),
+            vec3(0.0, 1.0, 0.0)
+        );
+        assert!(matches!(
+            prop.light(&light_styles[..2], &[0]),
+            Err(BspError::IndexOutOfRange { .. })
+        ));
+    }
+}
@@ -2,10 +2,10 @@ use std::io::{Read, Seek};
 
 use crate::vector::Vec3;
 
-use super::{parse_split_lump::parse_split_chunks, parse_vector3, Lump};
+use super::{parse_split_lump::parse_split_chunks, parse_vector3, BspError, ByteOrder, Lump};
 
 #[derive(Copy, Clone, Debug)]
-pub(super) struct DisplacementInfo {
+pub struct DisplacementInfo {
     pub start_position: Vec3,
     pub vertex_start: u32,
     pub triangle_start: u32,
@@ -22,24 +22,26 @@ pub(super) struct DisplacementInfo {
 pub(super) fn parse_displacements<T: Read + Seek>(
     file: &mut T,
     lump: Lump,
-) -> std::io::Result<Vec<DisplacementInfo>> {
+    order: ByteOrder,
+) -> Result<Vec<DisplacementInfo>, BspError> {
+    lump.expect_version(&[0], "displacement info")?;
     parse_split_chunks(file, lump, |bytes: [u8; 176]| DisplacementInfo {
-        start_position: parse_vector3(bytes[0..12].try_into().unwrap()),
-        vertex_start: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
-        triangle_start: u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
-        power: u32::from_le_bytes(bytes[20..24].try_into().unwrap()),
-        minimum_tesselation: u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
-        smoothing_angle: f32::from_le_bytes(bytes[28..32].try_into().unwrap()),
-        contents: u32::from_le_bytes(bytes[32..36].try_into().unwrap()),
-        face: u16::from_le_bytes(bytes[36..38].try_into().unwrap()),
-        lightmap_alpha_start: u32::from_le_bytes(bytes[38..42].try_into().unwrap()),
-        lightmap_sample_start: u32::from_le_bytes(bytes[42..46].try_into().unwrap()),
+        start_position: parse_vector3(&bytes[0..12], order),
+        vertex_start: order.u32(&bytes[12..16]),
+        triangle_start: order.u32(&bytes[16..20]),
+        power: order.u32(&bytes[20..24]),
+        minimum_tesselation: order.u32(&bytes[24..28]),
+        smoothing_angle: order.f32(&bytes[28..32]),
+        contents: order.u32(&bytes[32..36]),
+        face: order.u16(&bytes[36..38]),
+        lightmap_alpha_start: order.u32(&bytes[38..42]),
+        lightmap_sample_start: order.u32(&bytes[42..46]),
         // rest: bytes[46..176].try_into().unwrap(),
     })
 }
 
 #[derive(Copy, Clone)]
-pub(super) struct DisplacementVertex {
+pub struct DisplacementVertex {
     pub direction: Vec3,
     pub length: f32,
     pub alpha: f32,
@@ -48,10 +50,12 @@ pub(super) struct DisplacementVertex {
 pub(super) fn parse_displacement_vertexes<T: Read + Seek>(
     file: &mut T,
     lump: Lump,
-) -> std::io::Result<Vec<DisplacementVertex>> {
+    order: ByteOrder,
+) -> Result<Vec<DisplacementVertex>, BspError> {
+    lump.expect_version(&[0], "displacement vertexes")?;
     parse_split_chunks(file, lump, |bytes: [u8; 20]| DisplacementVertex {
-        direction: parse_vector3(bytes[0..12].try_into().unwrap()),
-        length: f32::from_le_bytes(bytes[12..16].try_into().unwrap()),
-        alpha: f32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+        direction: parse_vector3(&bytes[0..12], order),
+        length: order.f32(&bytes[12..16]),
+        alpha: order.f32(&bytes[16..20]),
     })
 }
@@ -1,11 +1,14 @@
 use std::io::Read;
 use std::io::Seek;
 
-use super::parse_split_lump::parse_split_chunks;
+use super::parse_split_lump::try_parse_split_chunks;
+use super::record::Record;
+use super::BspError;
+use super::ByteOrder;
 use super::Lump;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
-pub(super) struct Edge {
+pub struct Edge {
     pub first: u16,
     pub second: u16,
 }
@@ -19,9 +22,28 @@ impl Edge {
     }
 }
 
-pub(super) fn parse_edges<T: Read + Seek>(file: &mut T, lump: Lump) -> std::io::Result<Vec<Edge>> {
-    parse_split_chunks(file, lump, |bytes: [u8; 4]| Edge {
-        first: u16::from_le_bytes(bytes[0..2].try_into().unwrap()),
-        second: u16::from_le_bytes(bytes[2..4].try_into().unwrap()),
-    })
+pub(super) fn parse_edges<T: Read + Seek>(
+    file: &mut T,
+    lump: Lump,
+    order: ByteOrder,
+) -> Result<Vec<Edge>, BspError> {
+    lump.expect_version(Edge::VERSIONS, Edge::NAME)?;
+    try_parse_split_chunks(file, lump, |bytes: [u8; 4]| Edge::from_bytes(&bytes, order))
+}
+
+impl Record for Edge {
+    const SIZE: usize = 4;
+    const NAME: &'static str = "edges";
+
+    fn from_bytes(bytes: &[u8], order: ByteOrder) -> Result<Self, BspError> {
+        return Ok(Edge {
+            first: order.u16(&bytes[0..2]),
+            second: order.u16(&bytes[2..4]),
+        });
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>, order: ByteOrder) {
+        order.put_u16(out, self.first);
+        order.put_u16(out, self.second);
+    }
 }
@@ -0,0 +1,382 @@
+use std::io::{Read, Seek};
+
+use crate::vector::Vec3;
+
+use super::{parse_split_lump::read_lump, BspError, Lump};
+
+/// An entity from the entity lump.
+///
+/// Keys keep the order they had in the map, and the same key can appear more
+/// than once, as with entity outputs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Entity {
+    pub properties: Vec<(String, String)>,
+}
+
+impl Entity {
+    pub fn new(classname: &str) -> Self {
+        return Entity {
+            properties: vec![("classname".to_owned(), classname.to_owned())],
+        };
+    }
+
+    /// The first value for `key`. Keys are matched ignoring case, like the engine does.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        return self
+            .properties
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str());
+    }
+
+    /// Every value for `key`, in order.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        return self
+            .properties
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str());
+    }
+
+    /// Sets `key` to `value`, replacing every existing value for the key or
+    /// adding it at the end if there was none.
+    pub fn set(&mut self, key: &str, value: &str) {
+        let mut found = false;
+        self.properties.retain_mut(|(k, v)| {
+            if !k.eq_ignore_ascii_case(key) {
+                return true;
+            }
+            if found {
+                return false;
+            }
+            found = true;
+            *v = value.to_owned();
+            return true;
+        });
+        if !found {
+            self.insert(key, value);
+        }
+    }
+
+    /// Adds a value at the end, keeping any existing values for `key`, as
+    /// used for outputs.
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.properties.push((key.to_owned(), value.to_owned()));
+    }
+
+    /// Removes every value for `key`, returning whether there were any.
+    pub fn remove(&mut self, key: &str) -> bool {
+        let length = self.properties.len();
+        self.properties
+            .retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        return self.properties.len() != length;
+    }
+
+    pub fn classname(&self) -> Option<&str> {
+        return self.get("classname");
+    }
+
+    pub fn targetname(&self) -> Option<&str> {
+        return self.get("targetname");
+    }
+
+    /// Parses a value made of three space separated numbers.
+    pub fn get_vec3(&self, key: &str) -> Option<Vec3> {
+        let mut parts = self.get(key)?.split_whitespace().map(str::parse::<f32>);
+        let vector = Vec3 {
+            x: parts.next()?.ok()?,
+            y: parts.next()?.ok()?,
+            z: parts.next()?.ok()?,
+        };
+        return Some(vector);
+    }
+
+    pub fn origin(&self) -> Option<Vec3> {
+        return self.get_vec3("origin");
+    }
+
+    /// Pitch, yaw and roll in degrees, stored in `x`, `y` and `z`.
+    pub fn angles(&self) -> Option<Vec3> {
+        return self.get_vec3("angles");
+    }
+}
+
+pub(super) fn parse_entity_lump<T: Read + Seek>(
+    file: &mut T,
+    lump: Lump,
+) -> Result<Vec<Entity>, BspError> {
+    lump.expect_version(&[0], "entities")?;
+    return parse_entities(&read_lump(file, lump)?);
+}
+
+/// Parses the text of an entity lump, `{ "key" "value" ... }` blocks
+/// optionally followed by a null terminator.
+pub fn parse_entities(text: &[u8]) -> Result<Vec<Entity>, BspError> {
+    let mut entities = vec![];
+    let mut position = 0;
+
+    let skip_whitespace = |position: &mut usize| {
+        while text.get(*position).is_some_and(u8::is_ascii_whitespace) {
+            *position += 1;
+        }
+    };
+    let error = |offset, message| BspError::BadEntityLump { offset, message };
+
+    loop {
+        skip_whitespace(&mut position);
+        match text.get(position) {
+            None | Some(0) => break,
+            Some(b'{') => position += 1,
+            Some(_) => return Err(error(position, "expected '{'")),
+        }
+
+        let mut entity = Entity::default();
+        loop {
+            skip_whitespace(&mut position);
+            match text.get(position) {
+                Some(b'}') => {
+                    position += 1;
+                    break;
+                }
+                Some(b'"') => {}
+                Some(_) => return Err(error(position, "expected '\"' or '}'")),
+                None => return Err(error(position, "unterminated entity")),
+            }
+
+            let key = read_quoted(text, &mut position)?;
+            skip_whitespace(&mut position);
+            if text.get(position) != Some(&b'"') {
+                return Err(error(position, "expected a value"));
+            }
+            let value = read_quoted(text, &mut position)?;
+            entity.properties.push((key, value));
+        }
+        entities.push(entity);
+    }
+
+    return Ok(entities);
+}
+
+/// Serializes entities in the format written by `vbsp`, including the null terminator.
+pub fn write_entities(entities: &[Entity]) -> Result<Vec<u8>, BspError> {
+    let mut out = vec![];
+    for entity in entities {
+        out.extend_from_slice(b"{\n");
+        for (key, value) in &entity.properties {
+            // There is no way to escape a quote
+            for text in [key, value] {
+                if text.contains('"') {
+                    return Err(BspError::QuoteInEntity(text.clone()));
+                }
+            }
+            out.extend_from_slice(format!("\"{key}\" \"{value}\"\n").as_bytes());
+        }
+        out.extend_from_slice(b"}\n");
+    }
+    out.push(0);
+    return Ok(out);
+}
+
+/// Reads the string starting at the opening quote at `position`.
+fn read_quoted(text: &[u8], position: &mut usize) -> Result<String, BspError> {
+    let start = *position + 1;
+    let length = text[start..]
+        .iter()
+        .position(|&k| k == b'"')
+        .ok_or(BspError::BadEntityLump {
+            offset: *position,
+            message: "unterminated string",
+        })?;
+
+    // Guessing the encoding of text that is not UTF-8 would change it when
+    // the lump is written back
+    let string = std::str::from_utf8(&text[start..start + length]).map_err(|error| {
+        BspError::BadEntityLump {
+            offset: start + error.valid_up_to(),
+            message: "invalid UTF-8",
+        }
+    })?;
+    *position = start + length + 1;
+    return Ok(string.to_owned());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entity(properties: &[(&str, &str)]) -> Entity {
+        return Entity {
+            properties: properties
+                .iter()
+                .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+                .collect(),
+        };
+    }
+
+    #[test]
+    fn parses_quoted_keys_and_values() {
+        let text = b"{\n\"classname\" \"worldspawn\"\n\"message\" \"a { b } c\\\\d\"\n}\n\
+            {\"classname\"\t\"logic_relay\" \"OnTrigger\" \"door,Open,,0,-1\"}\n\0";
+        let entities = parse_entities(text).unwrap();
+        assert_eq!(
+            entities,
+            [
+                entity(&[("classname", "worldspawn"), ("message", "a { b } c\\\\d")]),
+                entity(&[
+                    ("classname", "logic_relay"),
+                    ("OnTrigger", "door,Open,,0,-1")
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_empty_blocks_and_lumps() {
+        assert_eq!(
+            parse_entities(b"{\n}\n{}\0").unwrap(),
+            [entity(&[]), entity(&[])]
+        );
+        assert_eq!(parse_entities(b"").unwrap(), []);
+        assert_eq!(parse_entities(b" \n\0").unwrap(), []);
+    }
+
+    #[test]
+    fn rejects_malformed_lumps() {
+        for text in [
+            &b"\"classname\" \"worldspawn\""[..],
+            b"{ \"classname\" }",
+            b"{ \"classname\" \"worldspawn }",
+            b"{ \"classname\" \"worldspawn\"",
+            b"{ classname worldspawn }",
+        ] {
+            assert!(matches!(
+                parse_entities(text),
+                Err(BspError::BadEntityLump { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn writes_the_vbsp_format() {
+        let entities = [entity(&[("classname", "worldspawn")]), entity(&[])];
+        assert_eq!(
+            write_entities(&entities).unwrap(),
+            b"{\n\"classname\" \"worldspawn\"\n}\n{\n}\n\0"
+        );
+    }
+
+    #[test]
+    fn quotes_cannot_be_written() {
+        let entities = [entity(&[("message", "say \"hi\"")])];
+        assert!(matches!(
+            write_entities(&entities),
+            Err(BspError::QuoteInEntity(text)) if text == "say \"hi\""
+        ));
+    }
+
+    #[test]
+    fn round_trips() {
+        let entities = [
+            entity(&[
+                ("classname", "func_door"),
+                ("origin", "0 0 64"),
+                ("OnOpen", "relay,Trigger,,0,-1"),
+                ("OnOpen", "sound,PlaySound,,1,-1"),
+                ("model", "*1"),
+            ]),
+            entity(&[]),
+            entity(&[("classname", "info_target"), ("", ""), ("path", "a\\b {c}")]),
+        ];
+        let written = write_entities(&entities).unwrap();
+        assert_eq!(parse_entities(&written).unwrap(), entities);
+    }
+
+    #[test]
+    fn non_ascii_text_round_trips_or_is_rejected() {
+        let entities = [entity(&[("message", "Café – 東京")])];
+        let written = write_entities(&entities).unwrap();
+        assert_eq!(parse_entities(&written).unwrap(), entities);
+
+        // "Café" in Latin-1
+        let latin1 = b"{\n\"message\" \"Caf\xe9\"\n}\n\0";
+        assert!(matches!(
+            parse_entities(latin1),
+            Err(BspError::BadEntityLump {
+                offset: 16,
+                message: "invalid UTF-8"
+            })
+        ));
+    }
+
+    fn door() -> Entity {
+        return entity(&[
+            ("classname", "func_door"),
+            ("OnOpen", "relay,Trigger,,0,-1"),
+            ("speed", "100"),
+            ("OnOpen", "sound,PlaySound,,1,-1"),
+            ("onopen", "light,TurnOn,,0,-1"),
+        ]);
+    }
+
+    #[test]
+    fn insert_keeps_duplicate_keys_in_order() {
+        let mut door = door();
+        door.insert("OnOpen", "shake,StartShake,,0,-1");
+        assert_eq!(
+            door.get_all("onOPEN").collect::<Vec<_>>(),
+            [
+                "relay,Trigger,,0,-1",
+                "sound,PlaySound,,1,-1",
+                "light,TurnOn,,0,-1",
+                "shake,StartShake,,0,-1"
+            ]
+        );
+        assert_eq!(door.get("OnOpen"), Some("relay,Trigger,,0,-1"));
+    }
+
+    #[test]
+    fn set_replaces_duplicates_in_place() {
+        let mut door = door();
+        door.set("ONOPEN", "relay,Disable,,0,-1");
+        assert_eq!(
+            door,
+            entity(&[
+                ("classname", "func_door"),
+                ("OnOpen", "relay,Disable,,0,-1"),
+                ("speed", "100"),
+            ])
+        );
+    }
+
+    #[test]
+    fn set_adds_missing_keys_at_the_end() {
+        let mut door = door();
+        door.set("targetname", "door1");
+        assert_eq!(door.properties.len(), 6);
+        assert_eq!(
+            door.properties.last().unwrap(),
+            &("targetname".to_owned(), "door1".to_owned())
+        );
+        assert_eq!(door.targetname(), Some("door1"));
+    }
+
+    #[test]
+    fn remove_drops_every_value_and_keeps_the_rest_in_order() {
+        let mut door = door();
+        assert!(door.remove("onopen"));
+        assert_eq!(
+            door,
+            entity(&[("classname", "func_door"), ("speed", "100")])
+        );
+        assert_eq!(door.get_all("OnOpen").count(), 0);
+    }
+
+    #[test]
+    fn remove_of_a_missing_key_changes_nothing() {
+        let original = door();
+        let mut door = original.clone();
+        assert!(!door.remove("targetname"));
+        assert_eq!(door, original);
+        assert_eq!(door.get("targetname"), None);
+    }
+}
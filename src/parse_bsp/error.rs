@@ -0,0 +1,91 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum BspError {
+    #[error("IO error: {0}")]
+    IOError(#[source] std::io::Error),
+    #[error("Bad magic number {0:?}")]
+    BadMagic([u8; 4]),
+    #[error("Unsupported BSP version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Unsupported version {version} of the {lump} lump")]
+    UnsupportedLumpVersion { lump: &'static str, version: u32 },
+    #[error("Lump at offset {offset} with length {length} extends past the end of the file ({file_length} bytes)")]
+    TruncatedLump {
+        offset: u64,
+        length: u64,
+        file_length: u64,
+    },
+    #[error("Lump length {length} is not a multiple of the record size {record_size}")]
+    BadRecordSize { length: usize, record_size: usize },
+    #[error("Lump {0} is compressed and cannot be borrowed")]
+    CompressedLump(usize),
+    #[error("Compressed lump has bad tag {0:?}, expected LZMA")]
+    BadCompressionTag([u8; 4]),
+    #[error("Decompression Error")]
+    DecompressionError(#[from] lzma_rs::error::Error),
+    #[error("Index {index} out of range for {kind} (length {length})")]
+    IndexOutOfRange {
+        kind: &'static str,
+        index: usize,
+        length: usize,
+    },
+    #[error("Expected bool, got {0}")]
+    InvalidBool(u8),
+    #[error("Invalid string")]
+    InvalidString(#[from] std::str::Utf8Error),
+    #[error("Displacement with {edges} edges and power {power} is not supported")]
+    InvalidDisplacement { edges: usize, power: u32 },
+    #[error("Malformed entity lump at byte {offset}: {message}")]
+    BadEntityLump {
+        offset: usize,
+        message: &'static str,
+    },
+    #[error("Entity key or value {0:?} contains a quote")]
+    QuoteInEntity(String),
+    #[error("Malformed pakfile: {0}")]
+    BadPakfile(&'static str),
+    #[error("Pakfile entry uses unsupported compression method {0}")]
+    UnsupportedCompression(u16),
+    #[error("File {0:?} not found")]
+    FileNotFound(String),
+    #[error("BSP file would be larger than 4 GiB")]
+    FileTooLarge,
+    #[error("Malformed model: {0}")]
+    BadModel(&'static str),
+    #[error("Unsupported version {version} of the {file} file")]
+    UnsupportedModelVersion { file: &'static str, version: i32 },
+    #[error("Unknown face type {0}")]
+    UnknownFaceType(i32),
+    #[error("Lightmap size {0:?} is larger than the engine supports")]
+    InvalidLightmapSize([i32; 2]),
+    #[error("Patch with {width}x{height} control points is not supported")]
+    InvalidPatch { width: u32, height: u32 },
+}
+
+impl From<std::io::Error> for BspError {
+    fn from(error: std::io::Error) -> Self {
+        // Errors from the streaming LZMA decoder surface through `Read`, unwrap them again.
+        if error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<lzma_rs::error::Error>())
+        {
+            let inner = error.into_inner().unwrap();
+            return BspError::DecompressionError(*inner.downcast().unwrap());
+        }
+        return BspError::IOError(error);
+    }
+}
+
+/// Bounds-checked indexing that reports which table the index was for.
+pub(crate) fn lookup<'a, T>(
+    items: &'a [T],
+    index: usize,
+    kind: &'static str,
+) -> Result<&'a T, BspError> {
+    return items.get(index).ok_or(BspError::IndexOutOfRange {
+        kind,
+        index,
+        length: items.len(),
+    });
+}
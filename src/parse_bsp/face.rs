@@ -1,35 +1,70 @@
-use super::{into_bool, parse_split_lump::parse_split_chunks, Lump};
+use super::{
+    into_bool, parse_split_lump::try_parse_split_chunks, record::Record, BspError, ByteOrder, Lump,
+};
 use std::io::{Read, Seek};
 
-pub(super) fn parse_faces<T: Read + Seek>(file: &mut T, lump: Lump) -> std::io::Result<Vec<Face>> {
-    parse_split_chunks(file, lump, |data: [u8; 56]| Face {
-        planenum: u16::from_le_bytes(data[0..2].try_into().unwrap()),
-        side: into_bool(data[2]),
-        on_node: into_bool(data[3]),
-        first_edge: u32::from_le_bytes(data[4..8].try_into().unwrap()),
-        num_edges: u16::from_le_bytes(data[8..10].try_into().unwrap()),
-        tex_info: u16::from_le_bytes(data[10..12].try_into().unwrap()),
-        displacement_info: i16::from_le_bytes(data[12..14].try_into().unwrap()),
-        volume_id: u16::from_le_bytes(data[14..16].try_into().unwrap()),
-        styles: data[16..20].try_into().unwrap(),
-        lightmap_offset: u32::from_le_bytes(data[20..24].try_into().unwrap()),
-        area: f32::from_le_bytes(data[24..28].try_into().unwrap()),
-        lightmap_texture_mins_in_luxels: [
-            u32::from_le_bytes(data[28..32].try_into().unwrap()),
-            u32::from_le_bytes(data[32..36].try_into().unwrap()),
-        ],
-        lightmap_texture_size_in_luxels: [
-            u32::from_le_bytes(data[36..40].try_into().unwrap()),
-            u32::from_le_bytes(data[40..44].try_into().unwrap()),
-        ],
-        original_face: u32::from_le_bytes(data[44..48].try_into().unwrap()),
-        number_of_primitives: u16::from_le_bytes(data[48..50].try_into().unwrap()),
-        first_primitive_id: u16::from_le_bytes(data[50..52].try_into().unwrap()),
-        smoothing_groups: u32::from_le_bytes(data[52..56].try_into().unwrap()),
-    })
+pub(super) fn parse_faces<T: Read + Seek>(
+    file: &mut T,
+    lump: Lump,
+    order: ByteOrder,
+) -> Result<Vec<Face>, BspError> {
+    lump.expect_version(Face::VERSIONS, Face::NAME)?;
+    try_parse_split_chunks(file, lump, |data: [u8; 56]| Face::from_bytes(&data, order))
 }
 
-#[derive(Copy, Clone, Debug)]
+impl Record for Face {
+    const SIZE: usize = 56;
+    const NAME: &'static str = "faces";
+    const VERSIONS: &'static [u32] = &[0, 1];
+
+    fn from_bytes(data: &[u8], order: ByteOrder) -> Result<Self, BspError> {
+        return Ok(Face {
+            planenum: order.u16(&data[0..2]),
+            side: into_bool(data[2])?,
+            on_node: into_bool(data[3])?,
+            first_edge: order.u32(&data[4..8]),
+            num_edges: order.u16(&data[8..10]),
+            tex_info: order.u16(&data[10..12]),
+            displacement_info: order.i16(&data[12..14]),
+            volume_id: order.u16(&data[14..16]),
+            styles: data[16..20].try_into().unwrap(),
+            lightmap_offset: order.u32(&data[20..24]),
+            area: order.f32(&data[24..28]),
+            lightmap_texture_mins_in_luxels: [order.i32(&data[28..32]), order.i32(&data[32..36])],
+            lightmap_texture_size_in_luxels: [order.i32(&data[36..40]), order.i32(&data[40..44])],
+            original_face: order.u32(&data[44..48]),
+            number_of_primitives: order.u16(&data[48..50]),
+            first_primitive_id: order.u16(&data[50..52]),
+            smoothing_groups: order.u32(&data[52..56]),
+        });
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>, order: ByteOrder) {
+        order.put_u16(out, self.planenum);
+        out.push(self.side as u8);
+        out.push(self.on_node as u8);
+        order.put_u32(out, self.first_edge);
+        order.put_u16(out, self.num_edges);
+        order.put_u16(out, self.tex_info);
+        order.put_i16(out, self.displacement_info);
+        order.put_u16(out, self.volume_id);
+        out.extend_from_slice(&self.styles);
+        order.put_u32(out, self.lightmap_offset);
+        order.put_f32(out, self.area);
+        for value in self.lightmap_texture_mins_in_luxels {
+            order.put_i32(out, value);
+        }
+        for value in self.lightmap_texture_size_in_luxels {
+            order.put_i32(out, value);
+        }
+        order.put_u32(out, self.original_face);
+        order.put_u16(out, self.number_of_primitives);
+        order.put_u16(out, self.first_primitive_id);
+        order.put_u32(out, self.smoothing_groups);
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub struct Face {
     pub planenum: u16,
     pub side: bool,
@@ -39,13 +74,27 @@ pub struct Face {
     pub tex_info: u16,
     pub displacement_info: i16,
     pub volume_id: u16,
+    /// Light styles of the lightmaps stored for the face, unused slots are 255.
     pub styles: [u8; 4],
+    /// Byte offset of the samples in the lighting lump, `u32::MAX` if the face has no lightmap.
     pub lightmap_offset: u32,
     pub area: f32,
-    pub lightmap_texture_mins_in_luxels: [u32; 2],
-    pub lightmap_texture_size_in_luxels: [u32; 2],
+    pub lightmap_texture_mins_in_luxels: [i32; 2],
+    /// One less than the width and height of the lightmap.
+    pub lightmap_texture_size_in_luxels: [i32; 2],
     pub original_face: u32,
     pub number_of_primitives: u16,
     pub first_primitive_id: u16,
     pub smoothing_groups: u32,
 }
+
+impl Face {
+    /// Number of light styles the face stores lightmaps for.
+    pub fn light_style_count(&self) -> usize {
+        return self
+            .styles
+            .iter()
+            .take_while(|&&style| style != 255)
+            .count();
+    }
+}
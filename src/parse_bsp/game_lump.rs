@@ -0,0 +1,103 @@
+use std::io::{Read, Seek};
+
+use super::{parse_split_lump::read_lump, BspError, ByteOrder, Lump};
+
+/// Game lump flag marking LZMA compressed contents.
+const GAMELUMPFLAG_COMPRESSED: u16 = 0x0001;
+
+/// An entry of the game lump directory.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct GameLump {
+    /// Readable identifier, like `sprp` for static props.
+    pub id: [u8; 4],
+    pub flags: u16,
+    pub version: u16,
+    /// Offset from the start of the file.
+    pub offset: u32,
+    /// Length after decompression.
+    pub length: u32,
+    /// Length in the file, which differs from `length` for compressed lumps.
+    stored_length: u32,
+}
+
+impl GameLump {
+    pub fn is_compressed(&self) -> bool {
+        return self.flags & GAMELUMPFLAG_COMPRESSED > 0;
+    }
+
+    /// Describes the contents as a regular lump, so it can be read with the
+    /// same decompression code.
+    fn as_lump(&self) -> Lump {
+        return Lump {
+            offset: self.offset,
+            length: self.stored_length,
+            version: self.version as u32,
+            // Compressed lumps store their uncompressed size in place of the fourCC
+            id: match self.is_compressed() {
+                true => self.length.to_le_bytes(),
+                false => [0; 4],
+            },
+        };
+    }
+}
+
+pub(super) fn parse_game_lump_directory<T: Read + Seek>(
+    file: &mut T,
+    lump: Lump,
+    order: ByteOrder,
+) -> Result<Vec<GameLump>, BspError> {
+    lump.expect_version(&[0], "game lump")?;
+    if lump.length == 0 {
+        return Ok(vec![]);
+    }
+
+    let data = read_lump(file, lump)?;
+
+    let count = data.get(0..4).map_or(0, |bytes| order.u32(bytes)) as usize;
+    let entries = data
+        .get(4..4 + count * 16)
+        .ok_or(BspError::BadRecordSize {
+            length: data.len(),
+            record_size: 16,
+        })?
+        .chunks_exact(16)
+        .map(|bytes| (bytes, order.u32(&bytes[8..12])))
+        .collect::<Vec<_>>();
+
+    let end_of_game_lump = lump.offset + lump.length;
+    return Ok(entries
+        .iter()
+        .enumerate()
+        .map(|(index, &(bytes, offset))| {
+            let flags = order.u16(&bytes[4..6]);
+            let length = order.u32(&bytes[12..16]);
+            // Compressed lumps only know their size after decompression, they
+            // end where the next one starts
+            let stored_length = if flags & GAMELUMPFLAG_COMPRESSED > 0 {
+                let next = entries
+                    .get(index + 1)
+                    .map_or(end_of_game_lump, |&(_, next)| next);
+                next.saturating_sub(offset)
+            } else {
+                length
+            };
+
+            GameLump {
+                id: order.u32(&bytes[0..4]).to_be_bytes(),
+                flags,
+                version: order.u16(&bytes[6..8]),
+                offset,
+                length,
+                stored_length,
+            }
+        })
+        .collect());
+}
+
+/// Reads the decompressed contents of a game lump.
+pub(super) fn read_game_lump<T: Read + Seek>(
+    file: &mut T,
+    game_lump: GameLump,
+) -> Result<Vec<u8>, BspError> {
+    return read_lump(file, game_lump.as_lump());
+}
@@ -0,0 +1,628 @@
+//! Quake (BSP29) and GoldSrc (BSP30) maps.
+//!
+//! These share most of their record layouts with Source, so the geometry is
+//! converted into the Source types and can be passed to [`to_primitives`].
+//!
+//! [`to_primitives`]: super::to_primitives
+
+use std::{
+    fs::OpenOptions,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom},
+    ops::RangeInclusive,
+    path::Path,
+};
+
+use image::RgbaImage;
+
+use super::{
+    brush_model::BrushModel,
+    bsp_to_primitives::BrushGeometry,
+    displacement::{DisplacementInfo, DisplacementVertex},
+    edge,
+    entity::{self, Entity},
+    error,
+    face::Face,
+    parse_split_lump::{parse_split_chunks, read_lump},
+    parse_vector3,
+    plane::{self, Plane},
+    surfedges::{self, SurfEdge},
+    texdata::TextureData,
+    texinfo::{surface_flags, TextureInfo},
+    vertex::{self, Vertex},
+    BspError, ByteOrder, Edge, Lump,
+};
+use crate::vector::Vec3;
+
+pub mod lump_names {
+    pub const LUMP_ENTITIES: usize = 0;
+    pub const LUMP_PLANES: usize = 1;
+    pub const LUMP_TEXTURES: usize = 2;
+    pub const LUMP_VERTEXES: usize = 3;
+    pub const LUMP_VISIBILITY: usize = 4;
+    pub const LUMP_NODES: usize = 5;
+    pub const LUMP_TEXINFO: usize = 6;
+    pub const LUMP_FACES: usize = 7;
+    pub const LUMP_LIGHTING: usize = 8;
+    pub const LUMP_CLIPNODES: usize = 9;
+    pub const LUMP_LEAFS: usize = 10;
+    pub const LUMP_MARKSURFACES: usize = 11;
+    pub const LUMP_EDGES: usize = 12;
+    pub const LUMP_SURFEDGES: usize = 13;
+    pub const LUMP_MODELS: usize = 14;
+}
+
+pub const HEADER_LUMPS: usize = 15;
+
+/// BSP29 is used by Quake, BSP30 by Half-Life and other GoldSrc games.
+pub const SUPPORTED_VERSIONS: RangeInclusive<u32> = 29..=30;
+
+/// `TEX_SPECIAL`, set on sky, liquid and trigger textures which have no lightmap.
+const TEX_SPECIAL: u32 = 1;
+
+/// A Quake or GoldSrc BSP file.
+///
+/// These maps are small, so every lump is decoded when the file is opened.
+pub struct GoldSrcBsp {
+    pub version: u32,
+    pub lumps: Vec<Lump>,
+    pub entities: Vec<Entity>,
+    pub faces: Vec<Face>,
+    /// The planes of the map, followed by a flipped copy of each plane that
+    /// faces on the back side of a plane refer to instead of setting `side`.
+    pub planes: Vec<Plane>,
+    pub vertexes: Vec<Vertex>,
+    pub edges: Vec<Edge>,
+    pub surface_edges: Vec<SurfEdge>,
+    pub texture_infos: Vec<TextureInfo>,
+    /// One entry per mip texture, `name_index` is the index into `textures`.
+    pub texture_data: Vec<TextureData>,
+    pub textures: Vec<MipTexture>,
+    pub clip_nodes: Vec<ClipNode>,
+    pub brush_models: Vec<BrushModel>,
+}
+
+/// A texture from the miptex lump.
+pub struct MipTexture {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    /// Palette indices of the full size mip level, `None` if the texture is
+    /// stored in an external WAD file.
+    pub pixels: Option<Vec<u8>>,
+    /// The palette embedded in BSP30 textures.
+    pub palette: Option<Vec<[u8; 3]>>,
+}
+
+impl MipTexture {
+    /// Decodes the full size mip level.
+    ///
+    /// BSP29 textures have no palette of their own, so `fallback` (the
+    /// contents of Quake's `gfx/palette.lmp`) is used instead. Returns `None`
+    /// if the pixels or a palette are missing.
+    pub fn to_image(&self, fallback: Option<&[[u8; 3]]>) -> Option<RgbaImage> {
+        let pixels = self.pixels.as_ref()?;
+        let palette = self.palette.as_deref().or(fallback)?;
+        // Textures starting with `{` use the last palette entry for transparency
+        let transparent = self.name.starts_with('{');
+
+        let mut image = RgbaImage::new(self.width, self.height);
+        for (pixel, &index) in image.pixels_mut().zip(pixels) {
+            let [r, g, b] = palette.get(index as usize).copied().unwrap_or([0; 3]);
+            let alpha = if transparent && index == 255 { 0 } else { 255 };
+            *pixel = image::Rgba([r, g, b, alpha]);
+        }
+        return Some(image);
+    }
+}
+
+pub struct ClipNode {
+    pub plane: i32,
+    /// Child clip nodes, negative values are leaf contents.
+    pub children: [i16; 2],
+}
+
+impl GoldSrcBsp {
+    /// Opens the BSP file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, BspError> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        return GoldSrcBsp::from_reader(BufReader::new(file));
+    }
+
+    /// Reads a BSP file that is already loaded into memory.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BspError> {
+        return GoldSrcBsp::from_reader(Cursor::new(bytes));
+    }
+
+    /// Reads a BSP file from any seekable source.
+    pub fn from_reader<R: Read + Seek>(mut file: R) -> Result<Self, BspError> {
+        let (version, lumps) = read_header(&mut file)?;
+        let order = ByteOrder::Little;
+
+        let textures = parse_textures(&mut file, lumps[lump_names::LUMP_TEXTURES], version)?;
+        let texture_data = textures
+            .iter()
+            .enumerate()
+            .map(|(index, texture)| TextureData {
+                reflectivity: Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                name_index: index as u32,
+                width: texture.width.max(1),
+                height: texture.height.max(1),
+                view_width: texture.width,
+                view_height: texture.height,
+            })
+            .collect();
+
+        let mut planes = plane::parse_planes(&mut file, lumps[lump_names::LUMP_PLANES], order)?;
+        let plane_count = planes.len();
+        let flipped = planes
+            .iter()
+            .map(|plane| Plane {
+                normal: plane.normal * -1.0,
+                distance: -plane.distance,
+                axis: plane.axis,
+            })
+            .collect::<Vec<_>>();
+        planes.extend(flipped);
+
+        let texture_infos =
+            parse_texture_info(&mut file, lumps[lump_names::LUMP_TEXINFO], &textures)?;
+
+        return Ok(GoldSrcBsp {
+            version,
+            entities: entity::parse_entity_lump(&mut file, lumps[lump_names::LUMP_ENTITIES])?,
+            faces: parse_faces(&mut file, lumps[lump_names::LUMP_FACES], plane_count)?,
+            planes,
+            vertexes: vertex::parse_vertices(&mut file, lumps[lump_names::LUMP_VERTEXES], order)?,
+            edges: edge::parse_edges(&mut file, lumps[lump_names::LUMP_EDGES], order)?,
+            surface_edges: surfedges::parse_surf_edges(
+                &mut file,
+                lumps[lump_names::LUMP_SURFEDGES],
+                order,
+            )?,
+            texture_infos,
+            texture_data,
+            textures,
+            clip_nodes: parse_split_chunks(
+                &mut file,
+                lumps[lump_names::LUMP_CLIPNODES],
+                |bytes: [u8; 8]| ClipNode {
+                    plane: order.i32(&bytes[0..4]),
+                    children: [order.i16(&bytes[4..6]), order.i16(&bytes[6..8])],
+                },
+            )?,
+            brush_models: parse_split_chunks(
+                &mut file,
+                lumps[lump_names::LUMP_MODELS],
+                |bytes: [u8; 64]| BrushModel {
+                    min: parse_vector3(&bytes[0..12], order),
+                    max: parse_vector3(&bytes[12..24], order),
+                    origin: parse_vector3(&bytes[24..36], order),
+                    // Hull 0 is the one used for rendering
+                    head_node: order.u32(&bytes[36..40]),
+                    first_face: order.u32(&bytes[56..60]),
+                    num_faces: order.u32(&bytes[60..64]),
+                },
+            )?,
+            lumps,
+        });
+    }
+
+    /// Looks up a texture by name, ignoring case like the engine does.
+    pub fn texture(&self, name: &str) -> Option<&MipTexture> {
+        return self
+            .textures
+            .iter()
+            .find(|texture| texture.name.eq_ignore_ascii_case(name));
+    }
+}
+
+impl BrushGeometry for GoldSrcBsp {
+    fn faces(&self) -> Result<&[Face], BspError> {
+        return Ok(&self.faces);
+    }
+
+    fn planes(&self) -> Result<&[Plane], BspError> {
+        return Ok(&self.planes);
+    }
+
+    fn vertexes(&self) -> Result<&[Vertex], BspError> {
+        return Ok(&self.vertexes);
+    }
+
+    fn edges(&self) -> Result<&[Edge], BspError> {
+        return Ok(&self.edges);
+    }
+
+    fn surface_edges(&self) -> Result<&[SurfEdge], BspError> {
+        return Ok(&self.surface_edges);
+    }
+
+    fn texture_infos(&self) -> Result<&[TextureInfo], BspError> {
+        return Ok(&self.texture_infos);
+    }
+
+    fn texture_data(&self) -> Result<&[TextureData], BspError> {
+        return Ok(&self.texture_data);
+    }
+
+    fn texture_name(&self, texture_data: TextureData) -> Result<&str, BspError> {
+        return Ok(
+            &error::lookup(&self.textures, texture_data.name_index as usize, "textures")?.name,
+        );
+    }
+
+    fn displacement_info(&self) -> Result<&[DisplacementInfo], BspError> {
+        return Ok(&[]);
+    }
+
+    fn displacement_vertexes(&self) -> Result<&[DisplacementVertex], BspError> {
+        return Ok(&[]);
+    }
+
+    fn brush_models(&self) -> Result<&[BrushModel], BspError> {
+        return Ok(&self.brush_models);
+    }
+
+    /// Textures are not stored in folders, each one gets its own group.
+    fn material_category(&self, texture_name: &str) -> String {
+        return texture_name.to_ascii_uppercase();
+    }
+}
+
+fn read_header<R: Read + Seek>(file: &mut R) -> Result<(u32, Vec<Lump>), BspError> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut header_bytes = [0u8; 4 + HEADER_LUMPS * 8];
+    file.read_exact(&mut header_bytes)?;
+
+    let version = u32::from_le_bytes(header_bytes[0..4].try_into().unwrap());
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return Err(BspError::UnsupportedVersion(version));
+    }
+
+    let lumps = header_bytes[4..]
+        .chunks_exact(8)
+        .map(|bytes| Lump {
+            offset: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            length: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            version: 0,
+            id: [0; 4],
+        })
+        .collect();
+
+    return Ok((version, lumps));
+}
+
+fn parse_faces<R: Read + Seek>(
+    file: &mut R,
+    lump: Lump,
+    plane_count: usize,
+) -> Result<Vec<Face>, BspError> {
+    let order = ByteOrder::Little;
+    parse_split_chunks(file, lump, |bytes: [u8; 20]| {
+        let side = order.u16(&bytes[2..4]) != 0;
+        let planenum = order.u16(&bytes[0..2]) as usize;
+
+        Face {
+            planenum: (planenum + if side { plane_count } else { 0 }) as u16,
+            side: false,
+            on_node: false,
+            first_edge: order.u32(&bytes[4..8]),
+            num_edges: order.u16(&bytes[8..10]),
+            tex_info: order.u16(&bytes[10..12]),
+            displacement_info: -1,
+            volume_id: 0,
+            styles: bytes[12..16].try_into().unwrap(),
+            lightmap_offset: order.u32(&bytes[16..20]),
+            area: 0.0,
+            lightmap_texture_mins_in_luxels: [0, 0],
+            lightmap_texture_size_in_luxels: [0, 0],
+            original_face: 0,
+            number_of_primitives: 0,
+            first_primitive_id: 0,
+            smoothing_groups: 0,
+        }
+    })
+}
+
+fn parse_texture_info<R: Read + Seek>(
+    file: &mut R,
+    lump: Lump,
+    textures: &[MipTexture],
+) -> Result<Vec<TextureInfo>, BspError> {
+    let order = ByteOrder::Little;
+    parse_split_chunks(file, lump, |bytes: [u8; 40]| {
+        // Lightmaps are projected with the same vectors as the texture
+        let vectors = [0, 1].map(|i| {
+            (
+                parse_vector3(&bytes[i * 16..i * 16 + 12], order),
+                order.f32(&bytes[i * 16 + 12..i * 16 + 16]),
+            )
+        });
+        let texture_data_index = order.u32(&bytes[32..36]);
+        let name = textures
+            .get(texture_data_index as usize)
+            .map_or("", |texture| texture.name.as_str());
+
+        TextureInfo {
+            texture_vectors: vectors,
+            lightmap_vectors: vectors,
+            flags: surface_flags_from_name(name, order.u32(&bytes[36..40])),
+            texture_data_index,
+        }
+    })
+}
+
+/// GoldSrc marks tool textures by name only, translate them into the
+/// equivalent Source surface flags.
+fn surface_flags_from_name(name: &str, flags: u32) -> u32 {
+    let name = name.to_ascii_lowercase();
+    if name.starts_with("sky") {
+        return surface_flags::SURF_SKY;
+    }
+    match name.as_str() {
+        "hint" => return surface_flags::SURF_HINT,
+        "skip" => return surface_flags::SURF_SKIP,
+        "aaatrigger" | "clip" | "null" | "origin" | "bevel" | "trigger" => {
+            return surface_flags::SURF_NODRAW
+        }
+        _ => {}
+    }
+    if name.starts_with('*') || name.starts_with('!') {
+        return surface_flags::SURF_WARP;
+    }
+    if flags & TEX_SPECIAL > 0 {
+        return surface_flags::SURF_NOLIGHT;
+    }
+    return 0;
+}
+
+fn parse_textures<R: Read + Seek>(
+    file: &mut R,
+    lump: Lump,
+    version: u32,
+) -> Result<Vec<MipTexture>, BspError> {
+    let data = read_lump(file, lump)?;
+    if data.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let count = read_u32(&data, 0)? as usize;
+    return (0..count)
+        .map(|index| {
+            let offset = read_u32(&data, 4 + index * 4)? as i32;
+            if offset < 0 {
+                // Texture was left out of the map entirely
+                return Ok(MipTexture {
+                    name: String::new(),
+                    width: 0,
+                    height: 0,
+                    pixels: None,
+                    palette: None,
+                });
+            }
+            return parse_mip_texture(&data, offset as usize, version);
+        })
+        .collect();
+}
+
+fn parse_mip_texture(data: &[u8], offset: usize, version: u32) -> Result<MipTexture, BspError> {
+    let name_bytes = miptex_bytes(data, offset, 16)?;
+    let name_end = name_bytes.iter().position(|&k| k == 0).unwrap_or(16);
+    let name = String::from_utf8_lossy(&name_bytes[..name_end]).into_owned();
+
+    let width = read_u32(data, offset + 16)?;
+    let height = read_u32(data, offset + 20)?;
+    let mut mip_offsets = [0usize; 4];
+    for (index, mip_offset) in mip_offsets.iter_mut().enumerate() {
+        *mip_offset = read_u32(data, offset + 24 + index * 4)? as usize;
+    }
+
+    // Textures from a WAD file only store the header
+    if mip_offsets[0] == 0 {
+        return Ok(MipTexture {
+            name,
+            width,
+            height,
+            pixels: None,
+            palette: None,
+        });
+    }
+
+    let pixel_count = width as usize * height as usize;
+    let pixels = miptex_bytes(data, offset + mip_offsets[0], pixel_count)?.to_vec();
+
+    let mut palette = None;
+    if version == 30 {
+        let palette_offset = offset + mip_offsets[3] + (width / 8 * (height / 8)) as usize;
+        let colors = u16::from_le_bytes(miptex_bytes(data, palette_offset, 2)?.try_into().unwrap());
+        palette = Some(
+            miptex_bytes(data, palette_offset + 2, colors as usize * 3)?
+                .chunks_exact(3)
+                .map(|color| [color[0], color[1], color[2]])
+                .collect(),
+        );
+    }
+
+    return Ok(MipTexture {
+        name,
+        width,
+        height,
+        pixels: Some(pixels),
+        palette,
+    });
+}
+
+fn miptex_bytes(data: &[u8], start: usize, length: usize) -> Result<&[u8], BspError> {
+    return data
+        .get(start..start + length)
+        .ok_or(BspError::IndexOutOfRange {
+            kind: "miptex data",
+            index: start + length,
+            length: data.len(),
+        });
+}
+
+fn read_u32(data: &[u8], start: usize) -> Result<u32, BspError> {
+    return Ok(ByteOrder::Little.u32(miptex_bytes(data, start, 4)?));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_bsp::to_primitives;
+
+    fn put_u32s(out: &mut Vec<u8>, values: &[u32]) {
+        for &value in values {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn put_f32s(out: &mut Vec<u8>, values: &[f32]) {
+        for &value in values {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn mip_texture_header(out: &mut Vec<u8>, name: &str, size: u32, mip_offsets: [u32; 4]) {
+        let mut name_bytes = name.as_bytes().to_vec();
+        name_bytes.resize(16, 0);
+        out.extend(name_bytes);
+        put_u32s(out, &[size, size]);
+        put_u32s(out, &mip_offsets);
+    }
+
+    /// Two textures: an 8x8 checkerboard with its own palette and a sky
+    /// texture stored in a WAD file.
+    fn textures_lump() -> Vec<u8> {
+        let mut out = vec![];
+        put_u32s(&mut out, &[2, 12, 12 + 40 + 64 + 16 + 4 + 1 + 2 + 6]);
+
+        mip_texture_header(&mut out, "WALL", 8, [40, 40 + 64, 40 + 80, 40 + 84]);
+        out.extend((0..64).map(|k| ((k + k / 8) % 2) as u8));
+        out.extend([0; 16 + 4 + 1]);
+        out.extend([2, 0]);
+        out.extend([255, 0, 0, 0, 0, 255]);
+
+        mip_texture_header(&mut out, "sky", 16, [0; 4]);
+        return out;
+    }
+
+    /// A square on the floor, drawn once from below with the wall texture and
+    /// once from above with the sky.
+    fn build_map() -> Vec<u8> {
+        let mut planes = vec![];
+        put_f32s(&mut planes, &[0.0, 0.0, 1.0, 0.0]);
+        put_u32s(&mut planes, &[2]);
+
+        let mut vertexes = vec![];
+        for [x, y] in [[0.0, 0.0], [64.0, 0.0], [64.0, 64.0], [0.0, 64.0]] {
+            put_f32s(&mut vertexes, &[x, y, 0.0]);
+        }
+
+        let mut texture_infos = vec![];
+        for texture in [0, 1] {
+            put_f32s(
+                &mut texture_infos,
+                &[1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0],
+            );
+            put_u32s(&mut texture_infos, &[texture, 0]);
+        }
+
+        let mut faces = vec![];
+        for (side, texture_info) in [(1u16, 0u16), (0, 1)] {
+            for value in [0, side] {
+                faces.extend(value.to_le_bytes());
+            }
+            put_u32s(&mut faces, &[1]);
+            for value in [4, texture_info] {
+                faces.extend(value.to_le_bytes());
+            }
+            faces.extend([0, 255, 255, 255]);
+            put_u32s(&mut faces, &[u32::MAX]);
+        }
+
+        let edges = [0, 0, 0, 1, 1, 2, 2, 3, 3, 0]
+            .into_iter()
+            .flat_map(|k: u16| k.to_le_bytes())
+            .collect();
+        let mut surface_edges = vec![];
+        put_u32s(&mut surface_edges, &[0, 1, 2, 3, 4]);
+
+        let mut models = vec![];
+        put_f32s(&mut models, &[0.0; 9]);
+        put_u32s(&mut models, &[0, 0, 0, 0, 0, 0, 2]);
+
+        let lumps = [
+            (
+                lump_names::LUMP_ENTITIES,
+                b"{\n\"classname\" \"worldspawn\"\n}\n\0".to_vec(),
+            ),
+            (lump_names::LUMP_PLANES, planes),
+            (lump_names::LUMP_TEXTURES, textures_lump()),
+            (lump_names::LUMP_VERTEXES, vertexes),
+            (lump_names::LUMP_TEXINFO, texture_infos),
+            (lump_names::LUMP_FACES, faces),
+            (lump_names::LUMP_EDGES, edges),
+            (lump_names::LUMP_SURFEDGES, surface_edges),
+            (lump_names::LUMP_MODELS, models),
+        ];
+        let mut directory = vec![[0; 2]; HEADER_LUMPS];
+        let mut body = vec![];
+        for (index, data) in lumps {
+            directory[index] = [
+                (4 + HEADER_LUMPS * 8 + body.len()) as u32,
+                data.len() as u32,
+            ];
+            body.extend(data);
+            body.resize(body.len().next_multiple_of(4), 0);
+        }
+
+        let mut out = vec![];
+        put_u32s(&mut out, &[30]);
+        for fields in directory {
+            put_u32s(&mut out, &fields);
+        }
+        out.extend(body);
+        return out;
+    }
+
+    #[test]
+    fn reads_bsp30_maps() {
+        let bsp = GoldSrcBsp::from_bytes(&build_map()).unwrap();
+        assert_eq!(bsp.version, 30);
+        assert_eq!(bsp.entities[0].classname(), Some("worldspawn"));
+
+        let wall = bsp.texture("wall").unwrap();
+        let image = wall.to_image(None).unwrap();
+        assert_eq!(image.dimensions(), (8, 8));
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(image.get_pixel(1, 0).0, [0, 0, 255, 255]);
+        assert_eq!(image.get_pixel(1, 1).0, [255, 0, 0, 255]);
+        let sky = &bsp.textures[1];
+        assert_eq!((sky.name.as_str(), sky.width), ("sky", 16));
+        assert!(sky.to_image(None).is_none());
+
+        assert_eq!(bsp.texture_infos[0].flags, 0);
+        assert_eq!(bsp.texture_infos[1].flags, surface_flags::SURF_SKY);
+        assert_eq!(bsp.texture_data[1].name_index, 1);
+
+        // Faces on the back of a plane use its flipped copy
+        assert_eq!(bsp.planes.len(), 2);
+        assert_eq!([bsp.faces[0].planenum, bsp.faces[1].planenum], [1, 0]);
+        assert_eq!(bsp.planes[1].normal.z, -1.0);
+        assert_eq!(bsp.faces[0].num_edges, 4);
+    }
+
+    #[test]
+    fn textures_are_grouped_by_name() {
+        let bsp = GoldSrcBsp::from_bytes(&build_map()).unwrap();
+        let groups = to_primitives(&bsp).unwrap();
+        assert_eq!(groups.keys().collect::<Vec<_>>(), ["WALL"]);
+        assert_eq!(groups["WALL"].indices.len(), 6);
+        assert!(groups["WALL"].lightmap_uvs.is_empty());
+    }
+}
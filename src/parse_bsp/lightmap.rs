@@ -0,0 +1,479 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Read, Seek},
+    path::Path,
+};
+
+use image::{codecs::hdr::HdrEncoder, DynamicImage, ImageError, Rgb, Rgb32FImage, RgbaImage};
+
+use crate::vector::{Vec2, Vec3};
+
+use super::{
+    color::{linear_to_srgb8, ColorRGBExp32},
+    error::lookup,
+    face::Face,
+    parse_split_lump::parse_split_chunks,
+    texinfo::{surface_flags, TextureInfo},
+    BspError, Lump,
+};
+
+/// Luxels copied around the edges of each lightmap, so filtering does not
+/// bleed into the neighbouring faces.
+const PADDING: u32 = 1;
+
+/// Most luxels along either side of a lightmap. Displacements can have up to
+/// 128 luxels between their edges, plus one for the last edge.
+const MAX_LIGHTMAP_SIZE: u32 = 128 + 1;
+
+/// Lightmaps stored for each light style of faces with bumped lighting: the
+/// flat lightmap followed by one for each of the three bump basis directions.
+pub const BUMPED_LIGHTMAP_COUNT: usize = 4;
+
+/// Which of the lightmaps stored for each face go into an atlas.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct LightmapSelection {
+    /// Light styles added together at full brightness. Style 0 is the normal
+    /// lighting, switchable lights use styles 32 and up.
+    pub styles: Vec<u8>,
+    /// 0 for the flat lightmap, 1 to 3 for one of the bump basis directions.
+    /// Faces without bumped lighting always use the flat lightmap.
+    pub bump_basis: usize,
+}
+
+impl Default for LightmapSelection {
+    fn default() -> Self {
+        return LightmapSelection {
+            styles: vec![0],
+            bump_basis: 0,
+        };
+    }
+}
+
+pub(super) fn parse_lighting<T: Read + Seek>(
+    file: &mut T,
+    lump: Lump,
+) -> Result<Vec<ColorRGBExp32>, BspError> {
+    lump.expect_version(&[0, 1], "lighting")?;
+    return parse_split_chunks(file, lump, ColorRGBExp32::from_bytes);
+}
+
+/// The lightmaps of every face packed into a single image.
+pub struct LightmapAtlas {
+    /// Linear lighting, where 1 is the brightest an LDR lightmap can show.
+    pub image: Rgb32FImage,
+    /// Position of the first luxel of each face, `None` for faces without a lightmap.
+    positions: Vec<Option<[u32; 2]>>,
+}
+
+impl LightmapAtlas {
+    /// Packs the lightmaps chosen by `selection` of every face with a lightmap
+    /// into rows, tallest first. A white luxel at the start is used by faces
+    /// without one.
+    pub fn build(
+        faces: &[Face],
+        texture_infos: &[TextureInfo],
+        samples: &[ColorRGBExp32],
+        selection: &LightmapSelection,
+    ) -> Result<Self, BspError> {
+        let sizes = faces
+            .iter()
+            .map(lightmap_size)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut order = (0..faces.len())
+            .filter(|&index| sizes[index].is_some())
+            .collect::<Vec<_>>();
+        order.sort_by_key(|&index| std::cmp::Reverse(sizes[index].unwrap()[1]));
+
+        let padded = |[width, height]: [u32; 2]| [width + 2 * PADDING, height + 2 * PADDING];
+        let area = order
+            .iter()
+            .map(|&index| padded(sizes[index].unwrap()))
+            .map(|[width, height]| width as u64 * height as u64)
+            .sum::<u64>();
+        let widest = order
+            .iter()
+            .map(|&index| padded(sizes[index].unwrap())[0])
+            .max()
+            .unwrap_or(0);
+        let atlas_width = ((area as f64).sqrt().ceil() as u32)
+            .next_power_of_two()
+            .max(widest)
+            .max(1 + 2 * PADDING);
+
+        // The white luxel goes first, then rows of faces
+        let mut positions = vec![None; faces.len()];
+        let mut cursor = [1 + 2 * PADDING, 0];
+        let mut row_height = 1 + 2 * PADDING;
+        for &index in &order {
+            let [width, height] = padded(sizes[index].unwrap());
+            if cursor[0] + width > atlas_width {
+                cursor = [0, cursor[1] + row_height];
+                row_height = 0;
+            }
+            positions[index] = Some([cursor[0] + PADDING, cursor[1] + PADDING]);
+            cursor[0] += width;
+            row_height = row_height.max(height);
+        }
+
+        let mut image = Rgb32FImage::new(atlas_width, cursor[1] + row_height);
+        for y in 0..1 + 2 * PADDING {
+            for x in 0..1 + 2 * PADDING {
+                image.put_pixel(x, y, Rgb([1.0; 3]));
+            }
+        }
+
+        for &index in &order {
+            let [width, height] = sizes[index].unwrap();
+            let [left, top] = positions[index].unwrap();
+            let face = &faces[index];
+            let texture_info = *lookup(texture_infos, face.tex_info as usize, "texture infos")?;
+            let bump_basis = match lightmaps_per_style(texture_info) {
+                1 => 0,
+                _ => selection.bump_basis,
+            };
+
+            let mut luxels = vec![[0.0; 3]; (width * height) as usize];
+            for style_slot in 0..face.light_style_count() {
+                if !selection.styles.contains(&face.styles[style_slot]) {
+                    continue;
+                }
+                let Some(lightmap) =
+                    face_lightmap(face, texture_info, samples, style_slot, bump_basis)?
+                else {
+                    continue;
+                };
+                for (luxel, sample) in luxels.iter_mut().zip(lightmap) {
+                    let color = sample.to_linear();
+                    *luxel = [0, 1, 2].map(|k| luxel[k] + color[k]);
+                }
+            }
+
+            // Padding repeats the closest luxel on the edge
+            for y in 0..height + 2 * PADDING {
+                for x in 0..width + 2 * PADDING {
+                    let source_x = x.saturating_sub(PADDING).min(width - 1);
+                    let source_y = y.saturating_sub(PADDING).min(height - 1);
+                    let color = luxels[(source_y * width + source_x) as usize];
+                    image.put_pixel(left - PADDING + x, top - PADDING + y, Rgb(color));
+                }
+            }
+        }
+
+        return Ok(LightmapAtlas { image, positions });
+    }
+
+    /// The atlas gamma corrected for display, clamping anything brighter than white.
+    pub fn to_rgba8(&self) -> RgbaImage {
+        return RgbaImage::from_fn(self.image.width(), self.image.height(), |x, y| {
+            let Rgb([r, g, b]) = *self.image.get_pixel(x, y);
+            return [r, g, b, 1.0].map(linear_to_srgb8).into();
+        });
+    }
+
+    /// Writes the linear atlas to `path` as a Radiance `.hdr` file or, for any
+    /// other extension, in the format the `image` crate picks for it, such as
+    /// OpenEXR for `.exr`.
+    pub fn save_linear<P: AsRef<Path>>(&self, path: P) -> Result<(), ImageError> {
+        let path = path.as_ref();
+        let is_hdr = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("hdr"));
+        if !is_hdr {
+            return DynamicImage::ImageRgb32F(self.image.clone()).save(path);
+        }
+
+        let pixels = self.image.pixels().copied().collect::<Vec<_>>();
+        return HdrEncoder::new(BufWriter::new(File::create(path)?)).encode(
+            &pixels,
+            self.image.width() as usize,
+            self.image.height() as usize,
+        );
+    }
+
+    /// Coordinates in the atlas of a point on the face at `face_index`.
+    pub fn uv(
+        &self,
+        face_index: usize,
+        face: &Face,
+        texture_info: TextureInfo,
+        position: Vec3,
+    ) -> Vec2 {
+        let atlas_size = Vec2 {
+            x: 1.0 / self.image.width() as f32,
+            y: 1.0 / self.image.height() as f32,
+        };
+        let Some(Some([left, top])) = self.positions.get(face_index) else {
+            let center = (PADDING as f32) + 0.5;
+            return Vec2 {
+                x: center,
+                y: center,
+            } * atlas_size;
+        };
+
+        let [width, height] = face.lightmap_texture_size_in_luxels.map(|k| k as f32 + 1.0);
+        let uv = texture_info.get_lightmap_uv(position, face);
+        return Vec2 {
+            x: *left as f32 + uv.x * width,
+            y: *top as f32 + uv.y * height,
+        } * atlas_size;
+    }
+}
+
+/// The samples of one lightmap of `face`, row by row: the one for the light
+/// style in slot `style_slot` of [`Face::styles`] and the bump basis
+/// `bump_basis`, 0 being the flat lightmap. `None` if the face has no such
+/// lightmap.
+pub fn face_lightmap<'a>(
+    face: &Face,
+    texture_info: TextureInfo,
+    samples: &'a [ColorRGBExp32],
+    style_slot: usize,
+    bump_basis: usize,
+) -> Result<Option<&'a [ColorRGBExp32]>, BspError> {
+    let Some([width, height]) = lightmap_size(face)? else {
+        return Ok(None);
+    };
+    let per_style = lightmaps_per_style(texture_info);
+    if style_slot >= face.light_style_count() || bump_basis >= per_style {
+        return Ok(None);
+    }
+
+    let luxel_count = (width * height) as usize;
+    let first =
+        face.lightmap_offset as usize / 4 + (style_slot * per_style + bump_basis) * luxel_count;
+    return samples
+        .get(first..first + luxel_count)
+        .map(Some)
+        .ok_or(BspError::IndexOutOfRange {
+            kind: "lighting",
+            index: first + luxel_count,
+            length: samples.len(),
+        });
+}
+
+fn lightmaps_per_style(texture_info: TextureInfo) -> usize {
+    return match texture_info.flags & surface_flags::SURF_BUMPLIGHT {
+        0 => 1,
+        _ => BUMPED_LIGHTMAP_COUNT,
+    };
+}
+
+/// Width and height of the lightmap of `face` in luxels, if it has one.
+fn lightmap_size(face: &Face) -> Result<Option<[u32; 2]>, BspError> {
+    let size = face.lightmap_texture_size_in_luxels;
+    if face.lightmap_offset == u32::MAX || face.styles[0] == 255 || size.iter().any(|&k| k < 0) {
+        return Ok(None);
+    }
+    if size.iter().any(|&k| k as u32 >= MAX_LIGHTMAP_SIZE) {
+        return Err(BspError::InvalidLightmapSize(size));
+    }
+    return Ok(Some(size.map(|k| k as u32 + 1)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_bsp::tests::test_face;
+
+    /// Lightmap coordinates follow the world x and y axes, one luxel per unit.
+    fn texture_info() -> TextureInfo {
+        let axis = |x, y| Vec3 { x, y, z: 0.0 };
+        return TextureInfo {
+            texture_vectors: [(axis(1.0, 0.0), 0.0), (axis(0.0, 1.0), 0.0)],
+            lightmap_vectors: [(axis(1.0, 0.0), 0.0), (axis(0.0, 1.0), 0.0)],
+            flags: 0,
+            texture_data_index: 0,
+        };
+    }
+
+    fn lit_face(lightmap_offset: u32, size: [i32; 2]) -> Face {
+        let mut face = test_face(0, lightmap_offset);
+        face.lightmap_texture_size_in_luxels = size;
+        return face;
+    }
+
+    /// Samples whose red channel counts up from `first`.
+    fn samples(first: u8, count: usize) -> Vec<ColorRGBExp32> {
+        return (0..count)
+            .map(|k| ColorRGBExp32::from_bytes([first + k as u8, 0, 0, 0]))
+            .collect();
+    }
+
+    #[test]
+    fn lightmap_uv_is_relative_to_the_luxel_mins() {
+        let mut face = lit_face(0, [3, 1]);
+        face.lightmap_texture_mins_in_luxels = [10, -2];
+        let uv = texture_info().get_lightmap_uv(
+            Vec3 {
+                x: 11.0,
+                y: -2.0,
+                z: 5.0,
+            },
+            &face,
+        );
+        assert_eq!(
+            uv,
+            Vec2 {
+                x: 1.5 / 4.0,
+                y: 0.5 / 2.0
+            }
+        );
+    }
+
+    #[test]
+    fn atlas_places_tallest_faces_first() {
+        let faces = [
+            lit_face(0, [3, 0]),
+            lit_face(u32::MAX, [0, 0]),
+            lit_face(4 * 4, [1, 2]),
+        ];
+        let atlas = LightmapAtlas::build(
+            &faces,
+            &[texture_info()],
+            &samples(1, 4 + 6),
+            &LightmapSelection::default(),
+        )
+        .unwrap();
+
+        // The white luxel and the 2x3 face share the first row, the 4x1 face
+        // wraps onto the next one.
+        assert_eq!(atlas.positions, [Some([1, 6]), None, Some([4, 1])]);
+        assert_eq!(atlas.image.dimensions(), (8, 8));
+        assert_eq!(atlas.image.get_pixel(1, 1), &Rgb([1.0; 3]));
+        let red = |x, y| atlas.image.get_pixel(x, y)[0] * 255.0;
+        assert_eq!(red(4, 1), 5.0);
+        assert_eq!(red(5, 3), 10.0);
+        // Padding repeats the edge luxels
+        assert_eq!(red(3, 0), 5.0);
+        assert_eq!(red(6, 4), 10.0);
+        assert_eq!(
+            [red(0, 6), red(1, 6), red(4, 6), red(5, 6)],
+            [1.0, 1.0, 4.0, 4.0]
+        );
+
+        let info = texture_info();
+        let corner = Vec3 {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        assert_eq!(
+            atlas.uv(2, &faces[2], info, corner),
+            Vec2 {
+                x: 4.5 / 8.0,
+                y: 1.5 / 8.0
+            }
+        );
+        assert_eq!(
+            atlas.uv(0, &faces[0], info, Vec3 { x: 3.0, ..corner }),
+            Vec2 {
+                x: 4.5 / 8.0,
+                y: 6.5 / 8.0
+            }
+        );
+        assert_eq!(
+            atlas.uv(1, &faces[1], info, corner),
+            Vec2 {
+                x: 1.5 / 8.0,
+                y: 1.5 / 8.0
+            }
+        );
+    }
+
+    #[test]
+    fn oversized_lightmaps_are_an_error() {
+        let lighting = samples(0, 1);
+        for size in [[129, 0], [0, 200], [i32::MAX, i32::MAX]] {
+            let faces = [lit_face(0, size)];
+            assert!(matches!(
+                face_lightmap(&faces[0], texture_info(), &lighting, 0, 0),
+                Err(BspError::InvalidLightmapSize(s)) if s == size
+            ));
+            assert!(matches!(
+                LightmapAtlas::build(&faces, &[texture_info()], &lighting, &Default::default()),
+                Err(BspError::InvalidLightmapSize(_))
+            ));
+        }
+        assert!(matches!(
+            face_lightmap(
+                &lit_face(0, [-1, i32::MIN]),
+                texture_info(),
+                &lighting,
+                0,
+                0
+            ),
+            Ok(None)
+        ));
+    }
+
+    /// A 2x1 lightmap with bumped lighting and two light styles, whose
+    /// samples start at index 2, and a flat 1x1 lightmap at sample 18.
+    fn bumped_faces() -> ([Face; 2], [TextureInfo; 2]) {
+        let mut bumped = lit_face(2 * 4, [1, 0]);
+        bumped.styles = [0, 32, 255, 255];
+        let mut flat = lit_face(18 * 4, [0, 0]);
+        flat.tex_info = 1;
+
+        let mut bumped_info = texture_info();
+        bumped_info.flags = surface_flags::SURF_BUMPLIGHT;
+        return ([bumped, flat], [bumped_info, texture_info()]);
+    }
+
+    fn red(lightmap: Option<&[ColorRGBExp32]>) -> Vec<u8> {
+        return lightmap.unwrap().iter().map(|sample| sample.r).collect();
+    }
+
+    #[test]
+    fn bumped_lightmaps_are_grouped_by_style() {
+        let ([face, flat], [bumped_info, flat_info]) = bumped_faces();
+        let lighting = samples(0, 19);
+        let lightmap = |slot, basis| face_lightmap(&face, bumped_info, &lighting, slot, basis);
+
+        // Each style stores the flat lightmap and then the three bump basis ones
+        assert_eq!(red(lightmap(0, 0).unwrap()), [2, 3]);
+        assert_eq!(red(lightmap(0, 3).unwrap()), [8, 9]);
+        assert_eq!(red(lightmap(1, 0).unwrap()), [10, 11]);
+        assert_eq!(red(lightmap(1, 2).unwrap()), [14, 15]);
+        assert!(lightmap(1, 4).unwrap().is_none());
+        assert!(lightmap(2, 0).unwrap().is_none());
+
+        let flat_lightmap = |basis| face_lightmap(&flat, flat_info, &lighting, 0, basis);
+        assert_eq!(red(flat_lightmap(0).unwrap()), [18]);
+        assert!(flat_lightmap(1).unwrap().is_none());
+
+        assert!(matches!(
+            face_lightmap(&face, bumped_info, &lighting[..17], 1, 3),
+            Err(BspError::IndexOutOfRange {
+                kind: "lighting",
+                index: 18,
+                length: 17
+            })
+        ));
+    }
+
+    #[test]
+    fn atlas_adds_the_selected_styles() {
+        let (faces, texture_infos) = bumped_faces();
+        let lighting = samples(0, 19);
+        let atlas_red = |styles: &[u8], bump_basis| {
+            let selection = LightmapSelection {
+                styles: styles.to_vec(),
+                bump_basis,
+            };
+            let atlas =
+                LightmapAtlas::build(&faces, &texture_infos, &lighting, &selection).unwrap();
+            let red = |face: usize, x| {
+                let [left, top] = atlas.positions[face].unwrap();
+                return (atlas.image.get_pixel(left + x, top)[0] * 255.0).round();
+            };
+            return [red(0, 0), red(0, 1), red(1, 0)];
+        };
+
+        assert_eq!(atlas_red(&[0], 0), [2.0, 3.0, 18.0]);
+        assert_eq!(atlas_red(&[32], 0), [10.0, 11.0, 0.0]);
+        // Faces without bumped lighting always use their flat lightmap
+        assert_eq!(atlas_red(&[0, 32], 3), [8.0 + 16.0, 9.0 + 17.0, 18.0]);
+        assert_eq!(atlas_red(&[], 0), [0.0; 3]);
+    }
+}
@@ -0,0 +1,163 @@
+use std::{fs::File, io::Cursor, path::Path};
+
+use memmap2::Mmap;
+
+use super::{
+    error::lookup,
+    lit_face_lumps, lump_names, read_header,
+    record::{Record, RecordSlice},
+    BspError, ByteOrder, Edge, Face, Lump, LumpLayout, Plane, SurfEdge, Vertex,
+};
+
+/// A memory mapped BSP file.
+///
+/// Uncompressed lumps are borrowed straight from the mapping, so even very
+/// large maps can be queried without copying every record into a `Vec`.
+pub struct MappedBsp {
+    map: Mmap,
+    pub byte_order: ByteOrder,
+    pub version: u32,
+    pub lump_layout: LumpLayout,
+    pub lumps: Vec<Lump>,
+    pub map_revision: u32,
+}
+
+impl MappedBsp {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, BspError> {
+        let file = File::open(path)?;
+        // Safety: the mapping is only ever read, callers must not modify the
+        // file while it is open.
+        let map = unsafe { Mmap::map(&file)? };
+        let header = read_header(&mut Cursor::new(&map[..]))?;
+
+        return Ok(MappedBsp {
+            map,
+            byte_order: header.byte_order,
+            version: header.version,
+            lump_layout: header.lump_layout,
+            lumps: header.lumps,
+            map_revision: header.map_revision,
+        });
+    }
+
+    /// The raw bytes of an uncompressed lump.
+    pub fn lump_data(&self, index: usize) -> Result<&[u8], BspError> {
+        let lump = *lookup(&self.lumps, index, "lumps")?;
+        if lump.is_compressed() {
+            return Err(BspError::CompressedLump(index));
+        }
+
+        let start = lump.offset as usize;
+        let end = start + lump.length as usize;
+        return self.map.get(start..end).ok_or(BspError::TruncatedLump {
+            offset: lump.offset as u64,
+            length: lump.length as u64,
+            file_length: self.map.len() as u64,
+        });
+    }
+
+    pub fn records<T: Record>(&self, index: usize) -> Result<RecordSlice<'_, T>, BspError> {
+        lookup(&self.lumps, index, "lumps")?.expect_version(T::VERSIONS, T::NAME)?;
+        return RecordSlice::new(self.lump_data(index)?, self.byte_order);
+    }
+
+    /// The faces, from the HDR lump for maps with only HDR lighting, matching
+    /// [`Bsp::faces`](super::Bsp::faces).
+    pub fn faces(&self) -> Result<RecordSlice<'_, Face>, BspError> {
+        return self.records(lit_face_lumps(&self.lumps).0);
+    }
+
+    pub fn planes(&self) -> Result<RecordSlice<'_, Plane>, BspError> {
+        return self.records(lump_names::LUMP_PLANES);
+    }
+
+    pub fn vertexes(&self) -> Result<RecordSlice<'_, Vertex>, BspError> {
+        return self.records(lump_names::LUMP_VERTEXES);
+    }
+
+    pub fn edges(&self) -> Result<RecordSlice<'_, Edge>, BspError> {
+        return self.records(lump_names::LUMP_EDGES);
+    }
+
+    pub fn surface_edges(&self) -> Result<RecordSlice<'_, SurfEdge>, BspError> {
+        return self.records(lump_names::LUMP_SURFEDGES);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parse_bsp::{
+            tests::{build_bsp, records_lump, test_face},
+            Bsp,
+        },
+        vector::Vec3,
+    };
+
+    /// Writes `data` to a file named after `test` and maps it.
+    fn map_file(test: &str, data: &[u8]) -> MappedBsp {
+        let path = std::env::temp_dir().join(format!("bspparse-{test}-{}.bsp", std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let bsp = MappedBsp::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        return bsp.unwrap();
+    }
+
+    fn test_map() -> Vec<u8> {
+        let order = ByteOrder::Little;
+        let vertexes = [0.0, 1.0, 2.0].map(|x| Vertex(Vec3 { x, y: -x, z: 8.0 }));
+        let edges = [[0, 1], [1, 2], [2, 0]].map(|[first, second]| Edge { first, second });
+        return build_bsp(&[
+            (
+                lump_names::LUMP_FACES,
+                1,
+                records_lump(&[test_face(0, 0), test_face(1, 4)], order),
+            ),
+            (lump_names::LUMP_VERTEXES, 0, records_lump(&vertexes, order)),
+            (lump_names::LUMP_EDGES, 0, records_lump(&edges, order)),
+            (
+                lump_names::LUMP_SURFEDGES,
+                0,
+                records_lump(&[SurfEdge(1), SurfEdge(-2)], order),
+            ),
+        ]);
+    }
+
+    fn collect<T: Record>(records: Result<RecordSlice<'_, T>, BspError>) -> Vec<T> {
+        return records.unwrap().iter().collect::<Result<_, _>>().unwrap();
+    }
+
+    #[test]
+    fn records_match_the_owned_parser() {
+        let data = test_map();
+        let mapped = map_file("records", &data);
+        let owned = Bsp::from_bytes(&data).unwrap();
+        assert_eq!(mapped.lumps, owned.lumps);
+        assert_eq!(mapped.map_revision, owned.map_revision);
+
+        assert_eq!(collect(mapped.faces()), owned.faces().unwrap());
+        assert_eq!(collect(mapped.vertexes()), owned.vertexes().unwrap());
+        assert_eq!(collect(mapped.edges()), owned.edges().unwrap());
+        assert_eq!(
+            collect(mapped.surface_edges()),
+            owned.surface_edges().unwrap()
+        );
+        assert_eq!(mapped.faces().unwrap().get(1).unwrap().planenum, 1);
+        assert!(mapped.planes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn compressed_lumps_cannot_be_borrowed() {
+        let mut data = test_map();
+        let id = 8 + lump_names::LUMP_EDGES * 16 + 12;
+        data[id..id + 4].copy_from_slice(&24_u32.to_le_bytes());
+        let mapped = map_file("compressed", &data);
+        assert!(mapped.lumps[lump_names::LUMP_EDGES].is_compressed());
+        assert!(matches!(
+            mapped.edges(),
+            Err(BspError::CompressedLump(lump_names::LUMP_EDGES))
+        ));
+        assert_eq!(mapped.vertexes().unwrap().len(), 3);
+    }
+}
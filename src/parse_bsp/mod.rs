@@ -1,279 +1,1025 @@
 mod bsp_to_primitives;
+mod byte_order;
+mod color;
+mod detail_prop;
 mod displacement;
 mod edge;
+mod entity;
+mod error;
 mod face;
+mod game_lump;
+pub mod goldsrc;
+mod lightmap;
+#[cfg(feature = "mmap")]
+mod mapped;
+mod pakfile;
 mod parse_split_lump;
 mod plane;
+pub mod quake3;
+mod record;
+mod static_prop;
+pub mod studio_model;
 mod texdata;
 mod texinfo;
 mod texture_string_array;
 mod vertex;
 mod vis_node_leaf;
+mod world_light;
+mod writer;
 
-use crate::{
-    comma_format::CommaFormat,
-    gltf_export,
-    vector::{Vec2, Vec3},
-};
+use crate::vector::{Vec2, Vec3};
 use std::{
+    cell::{OnceCell, RefCell},
     fs::{File, OpenOptions},
-    io::*,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom},
+    ops::RangeInclusive,
+    path::Path,
 };
 
-use self::{
+#[cfg(feature = "mmap")]
+pub use self::mapped::MappedBsp;
+pub use self::{
     brush_model::BrushModel,
+    bsp_to_primitives::{to_primitives, BrushGeometry, MaterialGroup},
+    byte_order::ByteOrder,
+    color::{ColorRGBExp32, CompressedLightCube},
+    detail_prop::{
+        DetailProp, DetailPropLightStyle, DetailPropLump, DetailPropOrientation, DetailPropType,
+        DetailSprite, DetailSpriteShape, DEFAULT_DETAIL_MATERIAL, DETAIL_PROP_LIGHTING_HDR_LUMP_ID,
+        DETAIL_PROP_LIGHTING_LUMP_ID, DETAIL_PROP_LUMP_ID,
+    },
     displacement::{DisplacementInfo, DisplacementVertex},
     edge::Edge,
+    entity::{parse_entities, write_entities, Entity},
+    error::BspError,
     face::Face,
+    game_lump::GameLump,
+    goldsrc::GoldSrcBsp,
+    lightmap::{face_lightmap, LightmapAtlas, LightmapSelection, BUMPED_LIGHTMAP_COUNT},
+    pakfile::{CompressionMethod, Pakfile, PakfileEntry, PakfileWriter},
     plane::Plane,
+    quake3::Quake3Bsp,
+    record::{Record, RecordSlice},
+    static_prop::{static_prop_flags, Solidity, StaticProp, StaticPropLump, STATIC_PROP_LUMP_ID},
+    studio_model::{StudioMesh, StudioModel},
     surfedges::SurfEdge,
     texdata::TextureData,
-    texinfo::TextureInfo,
+    texinfo::{surface_flags, TextureInfo},
     texture_string_array::{TextureDataStringArray, TextureString},
     vertex::Vertex,
+    vis_node_leaf::{VisLeaf, VisNode},
+    world_light::{EmitType, WorldLight},
+    writer::BspWriter,
 };
 
 mod brush_model;
 mod surfedges;
 
-#[allow(unused)]
-mod lump_names {
-    pub(crate) const LUMP_ENTITIES: usize = 0;
-    pub(crate) const LUMP_PLANES: usize = 1;
-    pub(crate) const LUMP_TEXDATA: usize = 2;
-    pub(crate) const LUMP_VERTEXES: usize = 3;
-    pub(crate) const LUMP_VISIBILITY: usize = 4;
-    pub(crate) const LUMP_NODES: usize = 5;
-    pub(crate) const LUMP_TEXINFO: usize = 6;
-    pub(crate) const LUMP_FACES: usize = 7;
-    pub(crate) const LUMP_LIGHTING: usize = 8;
-    pub(crate) const LUMP_OCCLUSION: usize = 9;
-    pub(crate) const LUMP_LEAFS: usize = 10;
-    pub(crate) const LUMP_FACEIDS: usize = 11;
-    pub(crate) const LUMP_EDGES: usize = 12;
-    pub(crate) const LUMP_SURFEDGES: usize = 13;
-    pub(crate) const LUMP_MODELS: usize = 14;
-    pub(crate) const LUMP_WORLDLIGHTS: usize = 15;
-    pub(crate) const LUMP_LEAFFACES: usize = 16;
-    pub(crate) const LUMP_LEAFBRUSHES: usize = 17;
-    pub(crate) const LUMP_BRUSHES: usize = 18;
-    pub(crate) const LUMP_BRUSHSIDES: usize = 19;
-    pub(crate) const LUMP_AREAS: usize = 20;
-    pub(crate) const LUMP_AREAPORTALS: usize = 21;
-    pub(crate) const LUMP_PORTALS: usize = 22;
-    pub(crate) const LUMP_CLUSTERS: usize = 23;
-    pub(crate) const LUMP_PORTALVERTS: usize = 24;
-    pub(crate) const LUMP_CLUSTERPORTALS: usize = 25;
-    pub(crate) const LUMP_DISPINFO: usize = 26;
-    pub(crate) const LUMP_ORIGINALFACES: usize = 27;
-    pub(crate) const LUMP_PHYSDISP: usize = 28;
-    pub(crate) const LUMP_PHYSCOLLIDE: usize = 29;
-    pub(crate) const LUMP_VERTNORMALS: usize = 30;
-    pub(crate) const LUMP_VERTNORMALINDICES: usize = 31;
-    pub(crate) const LUMP_DISP_LIGHTMAP_ALPHAS: usize = 32;
-    pub(crate) const LUMP_DISP_VERTS: usize = 33;
-    pub(crate) const LUMP_DISP_LIGHTMAP_SAMPLE_POSITIONS: usize = 34;
-    pub(crate) const LUMP_GAME_LUMP: usize = 35;
-    pub(crate) const LUMP_LEAFWATERDATA: usize = 36;
-    pub(crate) const LUMP_PRIMITIVES: usize = 37;
-    pub(crate) const LUMP_PRIMVERTS: usize = 38;
-    pub(crate) const LUMP_PRIMINDICES: usize = 39;
-    pub(crate) const LUMP_PAKFILE: usize = 40;
-    pub(crate) const LUMP_CLIPPORTALVERTS: usize = 41;
-    pub(crate) const LUMP_CUBEMAPS: usize = 42;
-    pub(crate) const LUMP_TEXDATA_STRING_DATA: usize = 43;
-    pub(crate) const LUMP_TEXDATA_STRING_TABLE: usize = 44;
-    pub(crate) const LUMP_OVERLAYS: usize = 45;
-    pub(crate) const LUMP_LEAFMINDISTTOWATER: usize = 46;
-    pub(crate) const LUMP_FACE_MACRO_TEXTURE_INFO: usize = 47;
-    pub(crate) const LUMP_DISP_TRIS: usize = 48;
-    pub(crate) const LUMP_PHYSCOLLIDESURFACE: usize = 49;
-    pub(crate) const LUMP_WATEROVERLAYS: usize = 50;
-    pub(crate) const LUMP_LIGHTMAPPAGES: usize = 51;
-    pub(crate) const LUMP_LIGHTMAPPAGEINFOS: usize = 52;
-    pub(crate) const LUMP_LIGHTING_HDR: usize = 53;
-    pub(crate) const LUMP_WORLDLIGHTS_HDR: usize = 54;
-    pub(crate) const LUMP_LEAF_AMBIENT_LIGHTING_HDR: usize = 55;
-    pub(crate) const LUMP_LEAF_AMBIENT_LIGHTING: usize = 56;
-    pub(crate) const LUMP_XZIPPAKFILE: usize = 57;
-    pub(crate) const LUMP_FACES_HDR: usize = 58;
-    pub(crate) const LUMP_MAP_FLAGS: usize = 59;
-    pub(crate) const LUMP_OVERLAY_FADES: usize = 60;
-    pub(crate) const LUMP_OVERLAY_SYSTEM_LEVELS: usize = 61;
-    pub(crate) const LUMP_PHYSLEVEL: usize = 62;
-    pub(crate) const LUMP_DISP_MULTIBLEND: usize = 63;
+pub mod lump_names {
+    pub const LUMP_ENTITIES: usize = 0;
+    pub const LUMP_PLANES: usize = 1;
+    pub const LUMP_TEXDATA: usize = 2;
+    pub const LUMP_VERTEXES: usize = 3;
+    pub const LUMP_VISIBILITY: usize = 4;
+    pub const LUMP_NODES: usize = 5;
+    pub const LUMP_TEXINFO: usize = 6;
+    pub const LUMP_FACES: usize = 7;
+    pub const LUMP_LIGHTING: usize = 8;
+    pub const LUMP_OCCLUSION: usize = 9;
+    pub const LUMP_LEAFS: usize = 10;
+    pub const LUMP_FACEIDS: usize = 11;
+    pub const LUMP_EDGES: usize = 12;
+    pub const LUMP_SURFEDGES: usize = 13;
+    pub const LUMP_MODELS: usize = 14;
+    pub const LUMP_WORLDLIGHTS: usize = 15;
+    pub const LUMP_LEAFFACES: usize = 16;
+    pub const LUMP_LEAFBRUSHES: usize = 17;
+    pub const LUMP_BRUSHES: usize = 18;
+    pub const LUMP_BRUSHSIDES: usize = 19;
+    pub const LUMP_AREAS: usize = 20;
+    pub const LUMP_AREAPORTALS: usize = 21;
+    pub const LUMP_PORTALS: usize = 22;
+    pub const LUMP_CLUSTERS: usize = 23;
+    pub const LUMP_PORTALVERTS: usize = 24;
+    pub const LUMP_CLUSTERPORTALS: usize = 25;
+    pub const LUMP_DISPINFO: usize = 26;
+    pub const LUMP_ORIGINALFACES: usize = 27;
+    pub const LUMP_PHYSDISP: usize = 28;
+    pub const LUMP_PHYSCOLLIDE: usize = 29;
+    pub const LUMP_VERTNORMALS: usize = 30;
+    pub const LUMP_VERTNORMALINDICES: usize = 31;
+    pub const LUMP_DISP_LIGHTMAP_ALPHAS: usize = 32;
+    pub const LUMP_DISP_VERTS: usize = 33;
+    pub const LUMP_DISP_LIGHTMAP_SAMPLE_POSITIONS: usize = 34;
+    pub const LUMP_GAME_LUMP: usize = 35;
+    pub const LUMP_LEAFWATERDATA: usize = 36;
+    pub const LUMP_PRIMITIVES: usize = 37;
+    pub const LUMP_PRIMVERTS: usize = 38;
+    pub const LUMP_PRIMINDICES: usize = 39;
+    pub const LUMP_PAKFILE: usize = 40;
+    pub const LUMP_CLIPPORTALVERTS: usize = 41;
+    pub const LUMP_CUBEMAPS: usize = 42;
+    pub const LUMP_TEXDATA_STRING_DATA: usize = 43;
+    pub const LUMP_TEXDATA_STRING_TABLE: usize = 44;
+    pub const LUMP_OVERLAYS: usize = 45;
+    pub const LUMP_LEAFMINDISTTOWATER: usize = 46;
+    pub const LUMP_FACE_MACRO_TEXTURE_INFO: usize = 47;
+    pub const LUMP_DISP_TRIS: usize = 48;
+    pub const LUMP_PHYSCOLLIDESURFACE: usize = 49;
+    pub const LUMP_WATEROVERLAYS: usize = 50;
+    pub const LUMP_LIGHTMAPPAGES: usize = 51;
+    pub const LUMP_LIGHTMAPPAGEINFOS: usize = 52;
+    pub const LUMP_LIGHTING_HDR: usize = 53;
+    pub const LUMP_WORLDLIGHTS_HDR: usize = 54;
+    pub const LUMP_LEAF_AMBIENT_LIGHTING_HDR: usize = 55;
+    pub const LUMP_LEAF_AMBIENT_LIGHTING: usize = 56;
+    pub const LUMP_XZIPPAKFILE: usize = 57;
+    pub const LUMP_FACES_HDR: usize = 58;
+    pub const LUMP_MAP_FLAGS: usize = 59;
+    pub const LUMP_OVERLAY_FADES: usize = 60;
+    pub const LUMP_OVERLAY_SYSTEM_LEVELS: usize = 61;
+    pub const LUMP_PHYSLEVEL: usize = 62;
+    pub const LUMP_DISP_MULTIBLEND: usize = 63;
 }
 
-const HEADER_LUMPS: usize = 64;
-
-struct ParsedBspFile {
-    faces: Vec<Face>,
-    planes: Vec<Plane>,
-    vertexes: Vec<Vertex>,
-    edges: Vec<Edge>,
-    surface_edges: Vec<SurfEdge>,
-    texture_infos: Vec<TextureInfo>,
-    texture_data: Vec<TextureData>,
-    texture_string_array: TextureDataStringArray,
-    texture_string_table: Vec<TextureString>,
-    displacement_info: Vec<DisplacementInfo>,
-    displacement_vertexes: Vec<DisplacementVertex>,
-    brush_models: Vec<BrushModel>,
+pub const HEADER_LUMPS: usize = 64;
+
+/// Source engine BSP versions this parser understands. Older maps store
+/// faces and other records in layouts the parsers don't decode.
+///
+/// Version 21 maps differ from version 20 ones in the order of the lump
+/// directory fields, see [`LumpLayout`], and in the versions of the leaf,
+/// static prop and game lumps they use, which are read like in any other map.
+/// The records this crate decodes are otherwise unchanged. Lumps of a version
+/// the parsers don't know are rejected with
+/// [`BspError::UnsupportedLumpVersion`] instead of being guessed at.
+pub const SUPPORTED_VERSIONS: RangeInclusive<u32> = 19..=21;
+
+/// A handle to a Source engine BSP file.
+///
+/// Only the header is read up front; each lump is decoded the first time it is
+/// accessed and cached for subsequent calls.
+pub struct Bsp<R> {
+    file: RefCell<R>,
+    pub byte_order: ByteOrder,
+    pub version: u32,
+    pub lump_layout: LumpLayout,
+    pub lumps: Vec<Lump>,
+    pub map_revision: u32,
+    entities: OnceCell<Vec<Entity>>,
+    faces: OnceCell<Vec<Face>>,
+    planes: OnceCell<Vec<Plane>>,
+    vertexes: OnceCell<Vec<Vertex>>,
+    edges: OnceCell<Vec<Edge>>,
+    surface_edges: OnceCell<Vec<SurfEdge>>,
+    texture_infos: OnceCell<Vec<TextureInfo>>,
+    texture_data: OnceCell<Vec<TextureData>>,
+    texture_string_array: OnceCell<TextureDataStringArray>,
+    texture_string_table: OnceCell<Vec<TextureString>>,
+    displacement_info: OnceCell<Vec<DisplacementInfo>>,
+    displacement_vertexes: OnceCell<Vec<DisplacementVertex>>,
+    brush_models: OnceCell<Vec<BrushModel>>,
+    nodes: OnceCell<Vec<VisNode>>,
+    leafs: OnceCell<Vec<VisLeaf>>,
+    pakfile: OnceCell<Pakfile>,
+    game_lumps: OnceCell<Vec<GameLump>>,
+    static_props: OnceCell<StaticPropLump>,
+    detail_props: OnceCell<DetailPropLump>,
+    detail_prop_light_styles: OnceCell<Vec<DetailPropLightStyle>>,
+    detail_prop_light_styles_hdr: OnceCell<Vec<DetailPropLightStyle>>,
+    lighting: OnceCell<Vec<ColorRGBExp32>>,
+    lightmap_atlas: OnceCell<LightmapAtlas>,
+    world_lights: OnceCell<Vec<WorldLight>>,
+    world_lights_hdr: OnceCell<Vec<WorldLight>>,
 }
 
-pub fn parse_bsp(filename: &str) -> Result<()> {
-    let mut file = OpenOptions::new().read(true).open(filename)?;
-
-    let (version, lumps) = read_header(&mut file)?;
-
-    println!("BSP Version = {version:?}");
-
-    for (index, lump) in lumps.iter().enumerate() {
-        println!(
-            "{index:>4} id={:03?} offset={:<14} length={:<14} version={:<8}",
-            lump.id,
-            CommaFormat(lump.offset as usize),
-            CommaFormat(lump.length as usize),
-            lump.version
-        )
-    }
-
-    let parsed_file = ParsedBspFile {
-        faces: face::parse_faces(&mut file, lumps[lump_names::LUMP_FACES])?,
-        planes: plane::parse_planes(&mut file, lumps[lump_names::LUMP_PLANES])?,
-        vertexes: vertex::parse_vertices(&mut file, lumps[lump_names::LUMP_VERTEXES])?,
-        edges: edge::parse_edges(&mut file, lumps[lump_names::LUMP_EDGES])?,
-        surface_edges: surfedges::parse_surf_edges(&mut file, lumps[lump_names::LUMP_SURFEDGES])?,
-        texture_infos: texinfo::parse_texture_info(&mut file, lumps[lump_names::LUMP_TEXINFO])?,
-        texture_data: texdata::parse_texture_data(&mut file, lumps[lump_names::LUMP_TEXDATA])?,
-        texture_string_array: texture_string_array::parse_texture_data_string_array(
-            &mut file,
-            lumps[lump_names::LUMP_TEXDATA_STRING_DATA],
-        )?,
-        texture_string_table: texture_string_array::parse_texture_data_string_table(
-            &mut file,
-            lumps[lump_names::LUMP_TEXDATA_STRING_TABLE],
-        )?,
-        displacement_info: displacement::parse_displacements(
-            &mut file,
-            lumps[lump_names::LUMP_DISPINFO],
-        )?,
-        displacement_vertexes: displacement::parse_displacement_vertexes(
-            &mut file,
-            lumps[lump_names::LUMP_DISP_VERTS],
-        )?,
-        brush_models: brush_model::parse_bush_model(&mut file, lumps[lump_names::LUMP_MODELS])?,
-    };
-    println!("Number of faces: {:}", CommaFormat(parsed_file.faces.len()));
-    println!(
-        "Number of planes: {:}",
-        CommaFormat(parsed_file.planes.len())
-    );
-    println!(
-        "Number of vertexes: {:}",
-        CommaFormat(parsed_file.vertexes.len())
-    );
-    println!("Number of edges: {:}", CommaFormat(parsed_file.edges.len()));
-    println!(
-        "Number of surfedges: {:}",
-        CommaFormat(parsed_file.surface_edges.len())
-    );
-    println!(
-        "String data table size: {:}",
-        CommaFormat(parsed_file.texture_string_table.len())
-    );
-
-    let primitive_groups = bsp_to_primitives::to_primitives(parsed_file);
-
-    for key in primitive_groups.keys() {
-        println!("{key}")
-    }
-
-    gltf_export::save_mesh(
-        "out.gltf".to_string(),
-        primitive_groups
+impl Bsp<BufReader<File>> {
+    /// Opens the BSP file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, BspError> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        return Bsp::from_reader(BufReader::new(file));
+    }
+}
+
+impl<'a> Bsp<Cursor<&'a [u8]>> {
+    /// Reads a BSP file that is already loaded into memory.
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, BspError> {
+        return Bsp::from_reader(Cursor::new(bytes));
+    }
+}
+
+impl<R: Read + Seek> Bsp<R> {
+    /// Reads the header of a BSP file from any seekable source.
+    pub fn from_reader(mut file: R) -> Result<Self, BspError> {
+        let header = read_header(&mut file)?;
+
+        return Ok(Bsp {
+            file: RefCell::new(file),
+            byte_order: header.byte_order,
+            version: header.version,
+            lump_layout: header.lump_layout,
+            lumps: header.lumps,
+            map_revision: header.map_revision,
+            entities: OnceCell::new(),
+            faces: OnceCell::new(),
+            planes: OnceCell::new(),
+            vertexes: OnceCell::new(),
+            edges: OnceCell::new(),
+            surface_edges: OnceCell::new(),
+            texture_infos: OnceCell::new(),
+            texture_data: OnceCell::new(),
+            texture_string_array: OnceCell::new(),
+            texture_string_table: OnceCell::new(),
+            displacement_info: OnceCell::new(),
+            displacement_vertexes: OnceCell::new(),
+            brush_models: OnceCell::new(),
+            nodes: OnceCell::new(),
+            leafs: OnceCell::new(),
+            pakfile: OnceCell::new(),
+            game_lumps: OnceCell::new(),
+            static_props: OnceCell::new(),
+            detail_props: OnceCell::new(),
+            detail_prop_light_styles: OnceCell::new(),
+            detail_prop_light_styles_hdr: OnceCell::new(),
+            lighting: OnceCell::new(),
+            lightmap_atlas: OnceCell::new(),
+            world_lights: OnceCell::new(),
+            world_lights_hdr: OnceCell::new(),
+        });
+    }
+
+    fn cached<'a, T>(
+        &'a self,
+        cell: &'a OnceCell<T>,
+        parse: impl FnOnce(&mut R, &[Lump]) -> Result<T, BspError>,
+    ) -> Result<&'a T, BspError> {
+        if let Some(value) = cell.get() {
+            return Ok(value);
+        }
+        let value = parse(&mut self.file.borrow_mut(), &self.lumps)?;
+        return Ok(cell.get_or_init(|| value));
+    }
+
+    /// Reads the decompressed contents of the lump at `index`, without caching.
+    pub fn lump_bytes(&self, index: usize) -> Result<Vec<u8>, BspError> {
+        let lump = *error::lookup(&self.lumps, index, "lumps")?;
+        return parse_split_lump::read_lump(&mut *self.file.borrow_mut(), lump);
+    }
+
+    /// Reads the lump at `index` exactly as it is stored in the file, without
+    /// decompressing it.
+    pub fn raw_lump_bytes(&self, index: usize) -> Result<Vec<u8>, BspError> {
+        let lump = *error::lookup(&self.lumps, index, "lumps")?;
+        return self.read_bytes(lump.offset as u64, lump.length as u64);
+    }
+
+    /// Reads `length` bytes starting at `offset` in the file.
+    pub(super) fn read_bytes(&self, offset: u64, length: u64) -> Result<Vec<u8>, BspError> {
+        let file_length = self.file_length()?;
+        if offset + length > file_length {
+            return Err(BspError::TruncatedLump {
+                offset,
+                length,
+                file_length,
+            });
+        }
+
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut out = vec![0; length as usize];
+        file.read_exact(&mut out)?;
+        return Ok(out);
+    }
+
+    pub(super) fn file_length(&self) -> Result<u64, BspError> {
+        return Ok(self.file.borrow_mut().seek(SeekFrom::End(0))?);
+    }
+
+    pub fn entities(&self) -> Result<&[Entity], BspError> {
+        return self
+            .cached(&self.entities, |file, lumps| {
+                entity::parse_entity_lump(file, lumps[lump_names::LUMP_ENTITIES])
+            })
+            .map(Vec::as_slice);
+    }
+
+    /// The faces, from the HDR lump for maps with only HDR lighting, see
+    /// [`Bsp::lighting`].
+    pub fn faces(&self) -> Result<&[Face], BspError> {
+        return self
+            .cached(&self.faces, |file, lumps| {
+                let (faces, _) = lit_face_lumps(lumps);
+                face::parse_faces(file, lumps[faces], self.byte_order)
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn planes(&self) -> Result<&[Plane], BspError> {
+        return self
+            .cached(&self.planes, |file, lumps| {
+                plane::parse_planes(file, lumps[lump_names::LUMP_PLANES], self.byte_order)
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn vertexes(&self) -> Result<&[Vertex], BspError> {
+        return self
+            .cached(&self.vertexes, |file, lumps| {
+                vertex::parse_vertices(file, lumps[lump_names::LUMP_VERTEXES], self.byte_order)
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn edges(&self) -> Result<&[Edge], BspError> {
+        return self
+            .cached(&self.edges, |file, lumps| {
+                edge::parse_edges(file, lumps[lump_names::LUMP_EDGES], self.byte_order)
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn surface_edges(&self) -> Result<&[SurfEdge], BspError> {
+        return self
+            .cached(&self.surface_edges, |file, lumps| {
+                surfedges::parse_surf_edges(
+                    file,
+                    lumps[lump_names::LUMP_SURFEDGES],
+                    self.byte_order,
+                )
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn texture_infos(&self) -> Result<&[TextureInfo], BspError> {
+        return self
+            .cached(&self.texture_infos, |file, lumps| {
+                texinfo::parse_texture_info(file, lumps[lump_names::LUMP_TEXINFO], self.byte_order)
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn texture_data(&self) -> Result<&[TextureData], BspError> {
+        return self
+            .cached(&self.texture_data, |file, lumps| {
+                texdata::parse_texture_data(file, lumps[lump_names::LUMP_TEXDATA], self.byte_order)
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn texture_string_array(&self) -> Result<&TextureDataStringArray, BspError> {
+        return self.cached(&self.texture_string_array, |file, lumps| {
+            texture_string_array::parse_texture_data_string_array(
+                file,
+                lumps[lump_names::LUMP_TEXDATA_STRING_DATA],
+            )
+        });
+    }
+
+    pub fn texture_string_table(&self) -> Result<&[TextureString], BspError> {
+        return self
+            .cached(&self.texture_string_table, |file, lumps| {
+                texture_string_array::parse_texture_data_string_table(
+                    file,
+                    lumps[lump_names::LUMP_TEXDATA_STRING_TABLE],
+                    self.byte_order,
+                )
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn displacement_info(&self) -> Result<&[DisplacementInfo], BspError> {
+        return self
+            .cached(&self.displacement_info, |file, lumps| {
+                displacement::parse_displacements(
+                    file,
+                    lumps[lump_names::LUMP_DISPINFO],
+                    self.byte_order,
+                )
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn displacement_vertexes(&self) -> Result<&[DisplacementVertex], BspError> {
+        return self
+            .cached(&self.displacement_vertexes, |file, lumps| {
+                displacement::parse_displacement_vertexes(
+                    file,
+                    lumps[lump_names::LUMP_DISP_VERTS],
+                    self.byte_order,
+                )
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn brush_models(&self) -> Result<&[BrushModel], BspError> {
+        return self
+            .cached(&self.brush_models, |file, lumps| {
+                brush_model::parse_bush_model(file, lumps[lump_names::LUMP_MODELS], self.byte_order)
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn nodes(&self) -> Result<&[VisNode], BspError> {
+        return self
+            .cached(&self.nodes, |file, lumps| {
+                vis_node_leaf::parse_vis_node(file, lumps[lump_names::LUMP_NODES], self.byte_order)
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn leafs(&self) -> Result<&[VisLeaf], BspError> {
+        return self
+            .cached(&self.leafs, |file, lumps| {
+                vis_node_leaf::parse_vis_leaf(file, lumps[lump_names::LUMP_LEAFS], self.byte_order)
+            })
+            .map(Vec::as_slice);
+    }
+
+    /// The directory of the game specific lumps stored inside the game lump.
+    pub fn game_lumps(&self) -> Result<&[GameLump], BspError> {
+        return self
+            .cached(&self.game_lumps, |file, lumps| {
+                game_lump::parse_game_lump_directory(
+                    file,
+                    lumps[lump_names::LUMP_GAME_LUMP],
+                    self.byte_order,
+                )
+            })
+            .map(Vec::as_slice);
+    }
+
+    pub fn game_lump(&self, id: [u8; 4]) -> Result<Option<GameLump>, BspError> {
+        return Ok(self
+            .game_lumps()?
             .iter()
-            .map(|(name, primitive)| gltf_export::GltfObject {
-                vertexes: &primitive.verticies,
-                normals: &primitive.normals,
-                uvs: &primitive.uvs,
-                indices: &primitive.indices,
-                texture: image::open(format!("cache\\textures\\{name}.png"))
-                    .expect(&format!("{name}")),
-                name,
+            .find(|game_lump| game_lump.id == id)
+            .copied());
+    }
+
+    /// Reads the decompressed contents of a game lump, without caching.
+    pub fn game_lump_bytes(&self, game_lump: GameLump) -> Result<Vec<u8>, BspError> {
+        return game_lump::read_game_lump(&mut *self.file.borrow_mut(), game_lump);
+    }
+
+    /// Parses a game lump the first time it is accessed, using the default
+    /// value if the map does not have it.
+    fn cached_game_lump<'a, T: Default>(
+        &'a self,
+        cell: &'a OnceCell<T>,
+        id: [u8; 4],
+        parse: impl FnOnce(&[u8], GameLump) -> Result<T, BspError>,
+    ) -> Result<&'a T, BspError> {
+        if let Some(value) = cell.get() {
+            return Ok(value);
+        }
+        let value = match self.game_lump(id)? {
+            Some(game_lump) => parse(&self.game_lump_bytes(game_lump)?, game_lump)?,
+            None => T::default(),
+        };
+        return Ok(cell.get_or_init(|| value));
+    }
+
+    /// The static props, empty if the map has no `sprp` game lump.
+    pub fn static_props(&self) -> Result<&StaticPropLump, BspError> {
+        return self.cached_game_lump(&self.static_props, STATIC_PROP_LUMP_ID, |data, lump| {
+            static_prop::parse_static_props(data, lump.version, self.version, self.byte_order)
+        });
+    }
+
+    /// The detail props, like grass and foliage, empty if the map has no
+    /// `dprp` game lump.
+    pub fn detail_props(&self) -> Result<&DetailPropLump, BspError> {
+        return self.cached_game_lump(&self.detail_props, DETAIL_PROP_LUMP_ID, |data, lump| {
+            detail_prop::parse_detail_props(data, lump.version, self.byte_order)
+        });
+    }
+
+    /// The light styles referenced by the detail props, from the `dplh` game
+    /// lump if `hdr` is set and `dplt` otherwise.
+    pub fn detail_prop_light_styles(&self, hdr: bool) -> Result<&[DetailPropLightStyle], BspError> {
+        let (cell, id) = match hdr {
+            true => (
+                &self.detail_prop_light_styles_hdr,
+                DETAIL_PROP_LIGHTING_HDR_LUMP_ID,
+            ),
+            false => (&self.detail_prop_light_styles, DETAIL_PROP_LIGHTING_LUMP_ID),
+        };
+        return self
+            .cached_game_lump(cell, id, |data, lump| {
+                detail_prop::parse_detail_prop_light_styles(data, lump.version, self.byte_order)
+            })
+            .map(Vec::as_slice);
+    }
+
+    /// The lightmap samples of every face, empty if the map was not lit.
+    ///
+    /// Maps with only HDR lighting use the HDR lighting along with the HDR
+    /// faces, whose lightmap offsets point into it. Maps without HDR faces
+    /// count as unlit, the LDR faces were not baked against the HDR samples.
+    pub fn lighting(&self) -> Result<&[ColorRGBExp32], BspError> {
+        return self
+            .cached(&self.lighting, |file, lumps| {
+                let (_, lighting) = lit_face_lumps(lumps);
+                lightmap::parse_lighting(file, lumps[lighting])
             })
-            .collect::<Vec<_>>()
-            .as_slice(),
-    )
-    .unwrap();
+            .map(Vec::as_slice);
+    }
+
+    /// The flat lightmaps of the normal light style of every face packed into
+    /// one image.
+    pub fn lightmap_atlas(&self) -> Result<&LightmapAtlas, BspError> {
+        if let Some(atlas) = self.lightmap_atlas.get() {
+            return Ok(atlas);
+        }
+        let atlas = self.build_lightmap_atlas(&LightmapSelection::default())?;
+        return Ok(self.lightmap_atlas.get_or_init(|| atlas));
+    }
+
+    /// Packs the lightmaps chosen by `selection` into one image, without caching.
+    pub fn build_lightmap_atlas(
+        &self,
+        selection: &LightmapSelection,
+    ) -> Result<LightmapAtlas, BspError> {
+        return LightmapAtlas::build(
+            self.faces()?,
+            self.texture_infos()?,
+            self.lighting()?,
+            selection,
+        );
+    }
+
+    /// One lightmap of the face at `face_index`, see [`face_lightmap`].
+    pub fn face_lightmap(
+        &self,
+        face_index: usize,
+        style_slot: usize,
+        bump_basis: usize,
+    ) -> Result<Option<&[ColorRGBExp32]>, BspError> {
+        let face = error::lookup(self.faces()?, face_index, "faces")?;
+        let texture_info = *error::lookup(
+            self.texture_infos()?,
+            face.tex_info as usize,
+            "texture infos",
+        )?;
+        return face_lightmap(face, texture_info, self.lighting()?, style_slot, bump_basis);
+    }
+
+    /// The lights used to compile the lighting, from the HDR lump if `hdr` is set.
+    pub fn world_lights(&self, hdr: bool) -> Result<&[WorldLight], BspError> {
+        let (cell, index) = match hdr {
+            true => (&self.world_lights_hdr, lump_names::LUMP_WORLDLIGHTS_HDR),
+            false => (&self.world_lights, lump_names::LUMP_WORLDLIGHTS),
+        };
+        return self
+            .cached(cell, |file, lumps| {
+                world_light::parse_world_lights(file, lumps[index], self.byte_order)
+            })
+            .map(Vec::as_slice);
+    }
 
-    return Ok(());
+    /// The zip archive of files embedded in the map.
+    pub fn pakfile(&self) -> Result<&Pakfile, BspError> {
+        if let Some(pakfile) = self.pakfile.get() {
+            return Ok(pakfile);
+        }
+        let pakfile = Pakfile::from_bytes(self.lump_bytes(lump_names::LUMP_PAKFILE)?)?;
+        return Ok(self.pakfile.get_or_init(|| pakfile));
+    }
+}
+
+/// Size of the header: magic, version, the lump directory and the map revision.
+const HEADER_SIZE: u64 = 8 + HEADER_LUMPS as u64 * 16 + 4;
+
+/// The order of the fields in each entry of the lump directory.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LumpLayout {
+    /// `offset, length, version, fourCC`, used by most Source games.
+    Standard,
+    /// `version, offset, length, fourCC`, used by Left 4 Dead 2 and some other version 21 maps.
+    Left4Dead2,
 }
 
-fn read_header(file: &mut File) -> Result<(u32, Vec<Lump>)> {
+struct Header {
+    byte_order: ByteOrder,
+    version: u32,
+    lump_layout: LumpLayout,
+    lumps: Vec<Lump>,
+    map_revision: u32,
+}
+
+fn read_header<R: Read + Seek>(file: &mut R) -> Result<Header, BspError> {
+    let file_length = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
     let mut header_bytes = [0u8; 8];
     file.read_exact(&mut header_bytes)?;
 
-    let ident = u32::from_le_bytes(header_bytes[0..4].try_into().unwrap());
+    let ident: [u8; 4] = header_bytes[0..4].try_into().unwrap();
+
+    // Magic Number, console maps store it byte swapped along with everything else
+    let byte_order = match &ident {
+        b"VBSP" => ByteOrder::Little,
+        b"PSBV" => ByteOrder::Big,
+        _ => return Err(BspError::BadMagic(ident)),
+    };
+    let version = byte_order.u32(&header_bytes[4..8]);
+    if !SUPPORTED_VERSIONS.contains(&version) {
+        return Err(BspError::UnsupportedVersion(version));
+    }
 
-    // Magic Number
-    assert_eq!(ident, u32::from_le_bytes(*b"VBSP"));
-    let version = u32::from_le_bytes(header_bytes[4..8].try_into().unwrap());
+    let mut lump_bytes = vec![[0u8; 16]; HEADER_LUMPS];
+    for bytes in lump_bytes.iter_mut() {
+        file.read_exact(bytes)?;
+    }
+    let mut revision_bytes = [0u8; 4];
+    file.read_exact(&mut revision_bytes)?;
 
-    let mut lumps: Vec<Lump> = vec![];
+    let read_lumps = |layout| -> Vec<Lump> {
+        lump_bytes
+            .iter()
+            .map(|k| read_lump(k, layout, byte_order))
+            .collect()
+    };
 
-    for _i in 0..HEADER_LUMPS {
-        lumps.push(read_lump(file)?);
+    // Both layouts are used by version 21 maps, usually only one of them will
+    // put every lump after the header and inside the file. If both do, the
+    // standard layout wins.
+    let mut lump_layout = LumpLayout::Standard;
+    let mut lumps = read_lumps(lump_layout);
+    if version == 21 && !lumps_in_bounds(&lumps, file_length) {
+        let alternate = read_lumps(LumpLayout::Left4Dead2);
+        if lumps_in_bounds(&alternate, file_length) {
+            lump_layout = LumpLayout::Left4Dead2;
+            lumps = alternate;
+        }
     }
 
-    return Ok((version, lumps));
+    return Ok(Header {
+        byte_order,
+        version,
+        lump_layout,
+        lumps,
+        map_revision: byte_order.u32(&revision_bytes),
+    });
 }
 
-fn read_lump(file: &mut File) -> Result<Lump> {
-    let mut lump_bytes = [0u8; 16];
-    file.read_exact(&mut lump_bytes)?;
+fn lumps_in_bounds(lumps: &[Lump], file_length: u64) -> bool {
+    return lumps.iter().all(|lump| {
+        lump.length == 0
+            || (lump.offset as u64 >= HEADER_SIZE
+                && lump.offset as u64 + lump.length as u64 <= file_length)
+    });
+}
+
+fn read_lump(lump_bytes: &[u8; 16], layout: LumpLayout, byte_order: ByteOrder) -> Lump {
+    let field = |index: usize| byte_order.u32(&lump_bytes[index * 4..index * 4 + 4]);
 
-    return Ok(Lump {
-        offset: u32::from_le_bytes(lump_bytes[0..4].try_into().unwrap()),
-        length: u32::from_le_bytes(lump_bytes[4..8].try_into().unwrap()),
-        version: u32::from_le_bytes(lump_bytes[8..12].try_into().unwrap()),
+    let (offset, length, version) = match layout {
+        LumpLayout::Standard => (field(0), field(1), field(2)),
+        LumpLayout::Left4Dead2 => (field(1), field(2), field(0)),
+    };
+
+    return Lump {
+        offset,
+        length,
+        version,
         id: lump_bytes[12..16].try_into().unwrap(),
-    });
+    };
 }
 
-fn into_bool(x: u8) -> bool {
+fn into_bool(x: u8) -> Result<bool, BspError> {
     match x {
-        0 => false,
-        1 => true,
-        _ => panic!("Expected bool, got {x}"),
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(BspError::InvalidBool(x)),
     }
 }
 
+/// Indexes of the face and lighting lumps, always picked together so the
+/// lightmap offsets of the faces match the samples: the HDR pair for maps
+/// without LDR faces or lighting that have HDR faces, the LDR pair otherwise.
+fn lit_face_lumps(lumps: &[Lump]) -> (usize, usize) {
+    let is_empty = |index: usize| lumps[index].length == 0;
+    let ldr_complete = !is_empty(lump_names::LUMP_FACES) && !is_empty(lump_names::LUMP_LIGHTING);
+    if !ldr_complete && !is_empty(lump_names::LUMP_FACES_HDR) {
+        return (lump_names::LUMP_FACES_HDR, lump_names::LUMP_LIGHTING_HDR);
+    }
+    return (lump_names::LUMP_FACES, lump_names::LUMP_LIGHTING);
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
-struct Lump {
-    offset: u32,
-    length: u32,
-    version: u32,
-    id: [u8; 4],
+pub struct Lump {
+    pub offset: u32,
+    pub length: u32,
+    pub version: u32,
+    pub id: [u8; 4],
 }
 
 impl Lump {
-    fn is_compressed(self) -> bool {
+    pub fn is_compressed(self) -> bool {
         return self.id != [0; 4];
     }
+
+    /// Rejects lumps stored in a layout this parser does not know.
+    fn expect_version(self, versions: &[u32], name: &'static str) -> Result<(), BspError> {
+        if !versions.contains(&self.version) {
+            return Err(BspError::UnsupportedLumpVersion {
+                lump: name,
+                version: self.version,
+            });
+        }
+        return Ok(());
+    }
 }
 
-fn parse_vector3(bytes: [u8; 12]) -> Vec3 {
+fn parse_vector3(bytes: &[u8], order: ByteOrder) -> Vec3 {
     return Vec3 {
-        x: f32::from_le_bytes(bytes[0..4].try_into().unwrap()),
-        y: f32::from_le_bytes(bytes[4..8].try_into().unwrap()),
-        z: f32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+        x: order.f32(&bytes[0..4]),
+        y: order.f32(&bytes[4..8]),
+        z: order.f32(&bytes[8..12]),
     };
 }
 
-fn parse_vector2(bytes: [u8; 8]) -> Vec2 {
+fn write_vector3(out: &mut Vec<u8>, vector: Vec3, order: ByteOrder) {
+    order.put_f32(out, vector.x);
+    order.put_f32(out, vector.y);
+    order.put_f32(out, vector.z);
+}
+
+fn parse_vector2(bytes: &[u8], order: ByteOrder) -> Vec2 {
     return Vec2 {
-        x: f32::from_le_bytes(bytes[0..4].try_into().unwrap()),
-        y: f32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        x: order.f32(&bytes[0..4]),
+        y: order.f32(&bytes[4..8]),
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a little endian version 20 map, the lumps are stored in the
+    /// order given as `(index, version, data)`.
+    pub(super) fn build_bsp(lumps: &[(usize, u32, Vec<u8>)]) -> Vec<u8> {
+        return build_bsp_in(ByteOrder::Little, lumps);
+    }
+
+    /// [`build_bsp`] with the header in `order`, the lump data is stored as given.
+    fn build_bsp_in(order: ByteOrder, lumps: &[(usize, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut directory = vec![[0; 3]; HEADER_LUMPS];
+        let mut body = vec![];
+        for (index, version, data) in lumps {
+            let offset = HEADER_SIZE as u32 + body.len() as u32;
+            directory[*index] = [offset, data.len() as u32, *version];
+            body.extend_from_slice(data);
+            body.resize(body.len().next_multiple_of(4), 0);
+        }
+
+        let mut out = match order {
+            ByteOrder::Little => b"VBSP".to_vec(),
+            ByteOrder::Big => b"PSBV".to_vec(),
+        };
+        order.put_u32(&mut out, 20);
+        for fields in directory {
+            for field in fields {
+                order.put_u32(&mut out, field);
+            }
+            out.extend_from_slice(&[0; 4]);
+        }
+        order.put_u32(&mut out, 1);
+        out.extend(body);
+        return out;
+    }
+
+    /// A face with a 1x1 lightmap, `planenum` tells the faces apart.
+    pub(super) fn test_face(planenum: u16, lightmap_offset: u32) -> Face {
+        return Face {
+            planenum,
+            side: false,
+            on_node: false,
+            first_edge: 0,
+            num_edges: 0,
+            tex_info: 0,
+            displacement_info: -1,
+            volume_id: 0,
+            styles: [0, 255, 255, 255],
+            lightmap_offset,
+            area: 0.0,
+            lightmap_texture_mins_in_luxels: [0, 0],
+            lightmap_texture_size_in_luxels: [0, 0],
+            original_face: 0,
+            number_of_primitives: 0,
+            first_primitive_id: 0,
+            smoothing_groups: 0,
+        };
+    }
+
+    fn faces_lump(faces: &[Face]) -> Vec<u8> {
+        return records_lump(faces, ByteOrder::Little);
+    }
+
+    pub(super) fn records_lump<T: Record>(records: &[T], order: ByteOrder) -> Vec<u8> {
+        let mut out = vec![];
+        for record in records {
+            record.write_bytes(&mut out, order);
+        }
+        return out;
+    }
+
+    fn lighting_lump(samples: usize) -> Vec<u8> {
+        return (0..samples).flat_map(|k| [k as u8, 0, 0, 0]).collect();
+    }
+
+    /// A version 21 map with a planes and an entity lump, with the lump
+    /// directory in `layout`.
+    fn version_21_map(layout: LumpLayout) -> Vec<u8> {
+        let mut data = build_bsp(&[
+            (lump_names::LUMP_PLANES, 0, vec![0; 40]),
+            (lump_names::LUMP_ENTITIES, 0, b"{\n}\n\0".to_vec()),
+        ]);
+        data[4..8].copy_from_slice(&21_u32.to_le_bytes());
+        if layout == LumpLayout::Left4Dead2 {
+            for entry in data[8..HEADER_SIZE as usize - 4].chunks_exact_mut(16) {
+                entry[0..12].rotate_right(4);
+            }
+        }
+        return data;
+    }
+
+    #[test]
+    fn reads_both_version_21_lump_layouts() {
+        for layout in [LumpLayout::Standard, LumpLayout::Left4Dead2] {
+            let data = version_21_map(layout);
+            let bsp = Bsp::from_bytes(&data).unwrap();
+            assert_eq!(bsp.lump_layout, layout);
+            assert_eq!(bsp.lumps[lump_names::LUMP_PLANES].length, 40);
+            assert_eq!(bsp.planes().unwrap().len(), 2);
+            assert_eq!(bsp.entities().unwrap().len(), 1);
+            assert_eq!(
+                writer::BspWriter::from_bsp(&bsp)
+                    .unwrap()
+                    .to_bytes()
+                    .unwrap(),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn ambiguous_version_21_directory_uses_the_standard_layout() {
+        // Read the other way around, the planes are an empty lump at offset
+        // 1040, so both readings put every lump inside the file
+        let mut data = build_bsp(&[(lump_names::LUMP_PLANES, 0, vec![0; 52 * 20])]);
+        data[4..8].copy_from_slice(&21_u32.to_le_bytes());
+        let bsp = Bsp::from_bytes(&data).unwrap();
+        assert_eq!(bsp.lump_layout, LumpLayout::Standard);
+        assert_eq!(
+            bsp.lumps[lump_names::LUMP_PLANES].offset,
+            HEADER_SIZE as u32
+        );
+        assert_eq!(bsp.planes().unwrap().len(), 52);
+    }
+
+    #[test]
+    fn version_20_maps_always_use_the_standard_layout() {
+        let mut data = version_21_map(LumpLayout::Left4Dead2);
+        data[4..8].copy_from_slice(&20_u32.to_le_bytes());
+        let bsp = Bsp::from_bytes(&data).unwrap();
+        assert_eq!(bsp.lump_layout, LumpLayout::Standard);
+        assert_eq!(bsp.lumps[lump_names::LUMP_PLANES].offset, 0);
+        assert_eq!(bsp.lumps[lump_names::LUMP_PLANES].version, 40);
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        for version in [17_u32, 18, 22] {
+            let mut data = build_bsp(&[]);
+            data[4..8].copy_from_slice(&version.to_le_bytes());
+            assert!(matches!(
+                Bsp::from_bytes(&data),
+                Err(BspError::UnsupportedVersion(v)) if v == version
+            ));
+        }
+    }
+
+    #[test]
+    fn truncated_header_reports_the_io_error() {
+        let error = Bsp::from_bytes(&build_bsp(&[])[..100]).err().unwrap();
+        assert!(matches!(error, BspError::IOError(_)));
+        assert!(std::error::Error::source(&error).is_some());
+        assert_eq!(error.to_string(), "IO error: failed to fill whole buffer");
+    }
+
+    #[test]
+    fn materials_are_grouped_by_folder() {
+        let data = build_bsp(&[]);
+        let bsp = Bsp::from_bytes(&data).unwrap();
+        for (name, category) in [
+            ("concrete/wall01", "CONCRETE"),
+            ("maps/de_test/metal/floor_-64_0_32", "METAL"),
+            ("maps/cubemap", "CUBEMAP"),
+            ("wall01", ""),
+        ] {
+            assert_eq!(bsp.material_category(name), category);
+        }
+    }
+
+    #[test]
+    fn big_endian_maps_match_little_endian_ones() {
+        let mut face = test_face(3, 8);
+        face.side = true;
+        face.first_edge = 0x0102_0304;
+        face.num_edges = 0x0506;
+        face.displacement_info = -2;
+        face.area = 1.5;
+        face.lightmap_texture_mins_in_luxels = [-16, 32];
+        face.lightmap_texture_size_in_luxels = [7, 15];
+        let vertexes = [
+            Vertex(Vec3 {
+                x: 1.0,
+                y: -2.5,
+                z: 1e6,
+            }),
+            Vertex(Vec3 {
+                x: 0.0,
+                y: 0.125,
+                z: -64.0,
+            }),
+        ];
+        let edges = [Edge {
+            first: 0x0102,
+            second: 0x0201,
+        }];
+
+        let build = |order| {
+            build_bsp_in(
+                order,
+                &[
+                    (lump_names::LUMP_FACES, 1, records_lump(&[face], order)),
+                    (lump_names::LUMP_VERTEXES, 0, records_lump(&vertexes, order)),
+                    (lump_names::LUMP_EDGES, 0, records_lump(&edges, order)),
+                ],
+            )
+        };
+        let little = build(ByteOrder::Little);
+        let big = build(ByteOrder::Big);
+        assert_ne!(little, big);
+
+        let little = Bsp::from_bytes(&little).unwrap();
+        let big = Bsp::from_bytes(&big).unwrap();
+        assert_eq!(big.byte_order, ByteOrder::Big);
+        assert_eq!(big.lumps, little.lumps);
+        assert_eq!(big.vertexes().unwrap(), vertexes);
+        assert_eq!(big.vertexes().unwrap(), little.vertexes().unwrap());
+        assert_eq!(big.faces().unwrap(), [face]);
+        assert_eq!(big.faces().unwrap(), little.faces().unwrap());
+        assert_eq!(big.edges().unwrap(), little.edges().unwrap());
+    }
+
+    #[test]
+    fn ldr_faces_are_not_lit_by_hdr_lighting() {
+        let data = build_bsp(&[
+            (lump_names::LUMP_FACES, 1, faces_lump(&[test_face(1, 4)])),
+            (lump_names::LUMP_LIGHTING_HDR, 1, lighting_lump(1)),
+        ]);
+        let bsp = Bsp::from_bytes(&data).unwrap();
+
+        assert_eq!(bsp.faces().unwrap()[0].planenum, 1);
+        assert!(bsp.lighting().unwrap().is_empty());
+    }
+
+    #[test]
+    fn hdr_lighting_comes_with_hdr_faces() {
+        let data = build_bsp(&[
+            (lump_names::LUMP_FACES, 1, faces_lump(&[test_face(1, 0)])),
+            (
+                lump_names::LUMP_FACES_HDR,
+                1,
+                faces_lump(&[test_face(2, 4)]),
+            ),
+            (lump_names::LUMP_LIGHTING_HDR, 1, lighting_lump(2)),
+            (lump_names::LUMP_TEXINFO, 0, vec![0; 72]),
+        ]);
+        let bsp = Bsp::from_bytes(&data).unwrap();
+
+        assert_eq!(bsp.faces().unwrap()[0].planenum, 2);
+        assert_eq!(bsp.lighting().unwrap().len(), 2);
+        assert_eq!(bsp.face_lightmap(0, 0, 0).unwrap().unwrap()[0].r, 1);
+    }
+
+    #[test]
+    fn ldr_faces_and_lighting_are_preferred() {
+        let data = build_bsp(&[
+            (lump_names::LUMP_FACES, 1, faces_lump(&[test_face(1, 0)])),
+            (lump_names::LUMP_LIGHTING, 1, lighting_lump(1)),
+            (
+                lump_names::LUMP_FACES_HDR,
+                1,
+                faces_lump(&[test_face(2, 0)]),
+            ),
+            (lump_names::LUMP_LIGHTING_HDR, 1, lighting_lump(3)),
+        ]);
+        let bsp = Bsp::from_bytes(&data).unwrap();
+
+        assert_eq!(bsp.faces().unwrap()[0].planenum, 1);
+        assert_eq!(bsp.lighting().unwrap().len(), 1);
+    }
+}
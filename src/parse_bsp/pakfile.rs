@@ -0,0 +1,446 @@
+use std::io::{Cursor, Read};
+
+use super::{
+    parse_split_lump::{read_length, LzmaReader},
+    BspError, ByteOrder,
+};
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x02014b50;
+const LOCAL_FILE_HEADER: u32 = 0x04034b50;
+/// 1980-01-01 in MS-DOS format, the earliest date a zip file can hold.
+const DOS_EPOCH_DATE: u16 = (1 << 5) | 1;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CompressionMethod {
+    Stored,
+    /// Used by the pakfiles of newer games, each entry starts with its own
+    /// LZMA properties.
+    Lzma,
+    Other(u16),
+}
+
+impl CompressionMethod {
+    fn from_id(id: u16) -> Self {
+        return match id {
+            0 => CompressionMethod::Stored,
+            14 => CompressionMethod::Lzma,
+            other => CompressionMethod::Other(other),
+        };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PakfileEntry {
+    pub name: String,
+    pub compression: CompressionMethod,
+    pub crc32: u32,
+    pub compressed_size: u32,
+    pub size: u32,
+    local_header_offset: u32,
+}
+
+/// The zip archive stored in the pakfile lump.
+pub struct Pakfile {
+    data: Vec<u8>,
+    entries: Vec<PakfileEntry>,
+}
+
+impl Pakfile {
+    /// Reads the central directory of a zip archive.
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, BspError> {
+        if data.is_empty() {
+            return Ok(Pakfile {
+                data,
+                entries: vec![],
+            });
+        }
+
+        // The end of central directory record is followed by a comment of at
+        // most 65535 bytes
+        let search_start = data.len().saturating_sub(22 + u16::MAX as usize);
+        let end = (search_start..data.len().saturating_sub(21))
+            .rev()
+            .find(|&offset| read_u32(&data, offset).is_ok_and(|k| k == END_OF_CENTRAL_DIRECTORY))
+            .ok_or(BspError::BadPakfile("missing end of central directory"))?;
+
+        let count = read_u16(&data, end + 10)? as usize;
+        let mut position = read_u32(&data, end + 16)? as usize;
+
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            if read_u32(&data, position)? != CENTRAL_DIRECTORY_HEADER {
+                return Err(BspError::BadPakfile("bad central directory header"));
+            }
+            let name_length = read_u16(&data, position + 28)? as usize;
+            let extra_length = read_u16(&data, position + 30)? as usize;
+            let comment_length = read_u16(&data, position + 32)? as usize;
+
+            entries.push(PakfileEntry {
+                name: String::from_utf8_lossy(slice(&data, position + 46, name_length)?)
+                    .into_owned(),
+                compression: CompressionMethod::from_id(read_u16(&data, position + 10)?),
+                crc32: read_u32(&data, position + 16)?,
+                compressed_size: read_u32(&data, position + 20)?,
+                size: read_u32(&data, position + 24)?,
+                local_header_offset: read_u32(&data, position + 42)?,
+            });
+            position += 46 + name_length + extra_length + comment_length;
+        }
+
+        return Ok(Pakfile { data, entries });
+    }
+
+    pub fn entries(&self) -> &[PakfileEntry] {
+        return &self.entries;
+    }
+
+    /// Finds an entry by path, ignoring case and the direction of slashes like
+    /// the engine's file system does.
+    pub fn entry(&self, name: &str) -> Option<&PakfileEntry> {
+        let normalize = |path: &str| path.replace('\\', "/").to_ascii_lowercase();
+        let name = normalize(name);
+        return self
+            .entries
+            .iter()
+            .find(|entry| normalize(&entry.name) == name);
+    }
+
+    /// Reads the decompressed contents of the file at `name`.
+    pub fn read(&self, name: &str) -> Result<Vec<u8>, BspError> {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| BspError::FileNotFound(name.to_owned()))?;
+        return self.read_entry(entry);
+    }
+
+    pub fn read_entry(&self, entry: &PakfileEntry) -> Result<Vec<u8>, BspError> {
+        let header = entry.local_header_offset as usize;
+        if read_u32(&self.data, header)? != LOCAL_FILE_HEADER {
+            return Err(BspError::BadPakfile("bad local file header"));
+        }
+        let name_length = read_u16(&self.data, header + 26)? as usize;
+        let extra_length = read_u16(&self.data, header + 28)? as usize;
+        let start = header + 30 + name_length + extra_length;
+        let data = slice(&self.data, start, entry.compressed_size as usize)?;
+
+        match entry.compression {
+            CompressionMethod::Stored => return Ok(data.to_vec()),
+            CompressionMethod::Lzma => {
+                // LZMA SDK version, the size of the properties and then the properties
+                let properties_size = read_u16(data, 2)? as usize;
+                let properties: [u8; 5] = slice(data, 4, 5)?.try_into().unwrap();
+
+                let compressed = data
+                    .get(4 + properties_size.max(5)..)
+                    .ok_or(BspError::BadPakfile("missing LZMA properties"))?;
+                let mut source = Cursor::new(compressed);
+                let reader =
+                    LzmaReader::new((&mut source).take(u64::MAX), properties, entry.size as u64)?;
+                return read_length(reader, entry.size as usize);
+            }
+            CompressionMethod::Other(id) => return Err(BspError::UnsupportedCompression(id)),
+        }
+    }
+}
+
+fn slice(data: &[u8], start: usize, length: usize) -> Result<&[u8], BspError> {
+    return data.get(start..start + length).ok_or(BspError::BadPakfile(
+        "record extends past the end of the pakfile",
+    ));
+}
+
+/// Zip archives are always little endian, even in console maps.
+fn read_u16(data: &[u8], start: usize) -> Result<u16, BspError> {
+    return Ok(ByteOrder::Little.u16(slice(data, start, 2)?));
+}
+
+fn read_u32(data: &[u8], start: usize) -> Result<u32, BspError> {
+    return Ok(ByteOrder::Little.u32(slice(data, start, 4)?));
+}
+
+/// Builds a new pakfile, like `bspzip` does.
+///
+/// Files are stored uncompressed, which every engine version can load.
+pub struct PakfileWriter {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl PakfileWriter {
+    pub fn new() -> Self {
+        return PakfileWriter { files: vec![] };
+    }
+
+    /// Starts from the decompressed contents of an existing pakfile.
+    pub fn from_pakfile(pakfile: &Pakfile) -> Result<Self, BspError> {
+        let files = pakfile
+            .entries()
+            .iter()
+            .map(|entry| Ok((entry.name.clone(), pakfile.read_entry(entry)?)))
+            .collect::<Result<Vec<_>, BspError>>()?;
+        return Ok(PakfileWriter { files });
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        return self.files.iter().map(|(name, _)| name.as_str());
+    }
+
+    /// Adds a file, replacing any existing file with the same path.
+    pub fn insert(&mut self, name: &str, data: Vec<u8>) {
+        let name = name.replace('\\', "/");
+        match self.position(&name) {
+            Some(index) => self.files[index].1 = data,
+            None => self.files.push((name, data)),
+        }
+    }
+
+    /// Removes a file, returning whether it was present.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let Some(index) = self.position(name) else {
+            return false;
+        };
+        self.files.remove(index);
+        return true;
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        let normalize = |path: &str| path.replace('\\', "/").to_ascii_lowercase();
+        let name = normalize(name);
+        return self
+            .files
+            .iter()
+            .position(|(file_name, _)| normalize(file_name) == name);
+    }
+
+    /// Encodes the files as a zip archive.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BspError> {
+        // Zip archives without the zip64 extension count files and name
+        // lengths in 16 bits
+        let file_count = u16::try_from(self.files.len())
+            .map_err(|_| BspError::BadPakfile("more than 65535 files"))?;
+
+        let mut out = vec![];
+        let mut central_directory = vec![];
+        for (name, data) in &self.files {
+            let offset = u32::try_from(out.len()).map_err(|_| BspError::FileTooLarge)?;
+            let size = u32::try_from(data.len()).map_err(|_| BspError::FileTooLarge)?;
+            let crc32 = crc32fast::hash(data);
+            let name_length = u16::try_from(name.len())
+                .map_err(|_| BspError::BadPakfile("file name longer than 65535 bytes"))?;
+
+            // Fields shared by the local header and the central directory:
+            // version needed, flags, method, time, date, crc, sizes and the name length
+            let mut common = vec![];
+            for value in [10u16, 0, 0, 0, DOS_EPOCH_DATE] {
+                common.extend_from_slice(&value.to_le_bytes());
+            }
+            for value in [crc32, size, size] {
+                common.extend_from_slice(&value.to_le_bytes());
+            }
+            common.extend_from_slice(&name_length.to_le_bytes());
+
+            out.extend_from_slice(&LOCAL_FILE_HEADER.to_le_bytes());
+            out.extend_from_slice(&common);
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(data);
+
+            central_directory.extend_from_slice(&CENTRAL_DIRECTORY_HEADER.to_le_bytes());
+            central_directory.extend_from_slice(&20u16.to_le_bytes());
+            central_directory.extend_from_slice(&common);
+            // Extra and comment lengths, disk number and attributes
+            central_directory.extend_from_slice(&[0; 12]);
+            central_directory.extend_from_slice(&offset.to_le_bytes());
+            central_directory.extend_from_slice(name.as_bytes());
+        }
+
+        let directory_offset = u32::try_from(out.len()).map_err(|_| BspError::FileTooLarge)?;
+        let directory_size =
+            u32::try_from(central_directory.len()).map_err(|_| BspError::FileTooLarge)?;
+        out.extend_from_slice(&central_directory);
+
+        out.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        for value in [0u16, 0, file_count, file_count] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&directory_size.to_le_bytes());
+        out.extend_from_slice(&directory_offset.to_le_bytes());
+        out.extend_from_slice(&0u16.to_le_bytes());
+
+        return Ok(out);
+    }
+}
+
+impl Default for PakfileWriter {
+    fn default() -> Self {
+        return PakfileWriter::new();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_pakfile() -> Pakfile {
+        let mut writer = PakfileWriter::new();
+        writer.insert("materials/test.vmt", b"LightmappedGeneric {}".to_vec());
+        writer.insert("sound\\ambient\\wind.wav", vec![1, 2, 3, 4, 5]);
+        return Pakfile::from_bytes(writer.to_bytes().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn lists_entries() {
+        let pakfile = test_pakfile();
+        let entries = pakfile.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, "materials/test.vmt");
+        assert_eq!(entries[0].size, 21);
+        assert_eq!(entries[0].compressed_size, 21);
+        assert_eq!(entries[0].compression, CompressionMethod::Stored);
+        assert_eq!(entries[0].crc32, crc32fast::hash(b"LightmappedGeneric {}"));
+        assert_eq!(entries[1].name, "sound/ambient/wind.wav");
+    }
+
+    #[test]
+    fn reads_entries_ignoring_case_and_slashes() {
+        let pakfile = test_pakfile();
+        assert_eq!(
+            pakfile.read("MATERIALS\\Test.vmt").unwrap(),
+            b"LightmappedGeneric {}"
+        );
+        assert_eq!(
+            pakfile.read("sound/ambient/wind.wav").unwrap(),
+            [1, 2, 3, 4, 5]
+        );
+        assert!(matches!(
+            pakfile.read("materials/missing.vmt"),
+            Err(BspError::FileNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn empty_lump_has_no_entries() {
+        assert!(Pakfile::from_bytes(vec![]).unwrap().entries().is_empty());
+        assert!(matches!(
+            Pakfile::from_bytes(vec![0; 64]),
+            Err(BspError::BadPakfile(_))
+        ));
+    }
+
+    #[test]
+    fn adds_replaces_and_removes_entries() {
+        let mut writer = PakfileWriter::from_pakfile(&test_pakfile()).unwrap();
+        writer.insert("Materials\\TEST.vmt", b"UnlitGeneric {}".to_vec());
+        writer.insert("models/crate.mdl", vec![9; 100]);
+        assert!(writer.remove("SOUND\\ambient\\wind.wav"));
+        assert!(!writer.remove("sound/ambient/wind.wav"));
+        assert_eq!(
+            writer.names().collect::<Vec<_>>(),
+            ["materials/test.vmt", "models/crate.mdl"]
+        );
+
+        let pakfile = Pakfile::from_bytes(writer.to_bytes().unwrap()).unwrap();
+        let names = pakfile.entries().iter().map(|entry| entry.name.as_str());
+        assert_eq!(
+            names.collect::<Vec<_>>(),
+            ["materials/test.vmt", "models/crate.mdl"]
+        );
+        assert_eq!(
+            pakfile.read("materials/test.vmt").unwrap(),
+            b"UnlitGeneric {}"
+        );
+        assert_eq!(pakfile.read("models/crate.mdl").unwrap(), [9; 100]);
+        assert!(pakfile.entry("sound/ambient/wind.wav").is_none());
+
+        // Writing the result again changes nothing
+        let rewritten = PakfileWriter::from_pakfile(&pakfile).unwrap();
+        assert_eq!(rewritten.to_bytes().unwrap(), writer.to_bytes().unwrap());
+    }
+
+    /// A zip with a single LZMA entry holding `data`, whose central directory
+    /// claims it is `size` bytes long.
+    fn lzma_pakfile(data: &[u8], size: u32) -> Vec<u8> {
+        let mut standard = vec![];
+        lzma_rs::lzma_compress(&mut &data[..], &mut standard).unwrap();
+        // SDK version, properties size and properties, then the raw stream
+        let mut entry = vec![9, 20, 5, 0];
+        entry.extend_from_slice(&standard[0..5]);
+        entry.extend_from_slice(&standard[13..]);
+
+        let name = b"materials/test.vmt";
+        let mut common = vec![];
+        for value in [20u16, 0, 14, 0, DOS_EPOCH_DATE] {
+            common.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [crc32fast::hash(data), entry.len() as u32, size] {
+            common.extend_from_slice(&value.to_le_bytes());
+        }
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+
+        let mut out = LOCAL_FILE_HEADER.to_le_bytes().to_vec();
+        out.extend_from_slice(&common);
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(name);
+        out.extend_from_slice(&entry);
+
+        let central_directory = out.len() as u32;
+        out.extend_from_slice(&CENTRAL_DIRECTORY_HEADER.to_le_bytes());
+        out.extend_from_slice(&20u16.to_le_bytes());
+        out.extend_from_slice(&common);
+        out.extend_from_slice(&[0; 12]);
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(name);
+        let central_directory_size = out.len() as u32 - central_directory;
+
+        out.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        for value in [0u16, 0, 1, 1] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [central_directory_size, central_directory] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&0u16.to_le_bytes());
+        return out;
+    }
+
+    #[test]
+    fn reads_lzma_entries() {
+        let data = b"VertexLitGeneric { $basetexture models/crate }".repeat(10);
+        let pakfile = Pakfile::from_bytes(lzma_pakfile(&data, data.len() as u32)).unwrap();
+        assert_eq!(pakfile.entries()[0].compression, CompressionMethod::Lzma);
+        assert_eq!(pakfile.read("materials/test.vmt").unwrap(), data);
+    }
+
+    #[test]
+    fn inflated_lzma_entry_size_is_an_error() {
+        let data = b"VertexLitGeneric {}".repeat(10);
+        let pakfile = Pakfile::from_bytes(lzma_pakfile(&data, u32::MAX)).unwrap();
+        assert!(matches!(
+            pakfile.read("materials/test.vmt"),
+            Err(BspError::DecompressionError(_))
+        ));
+    }
+
+    #[test]
+    fn too_many_files_or_a_long_name_is_an_error() {
+        let mut pakfile = PakfileWriter {
+            files: (0..=u16::MAX as usize)
+                .map(|k| (k.to_string(), vec![]))
+                .collect(),
+        };
+        assert!(matches!(
+            pakfile.to_bytes(),
+            Err(BspError::BadPakfile("more than 65535 files"))
+        ));
+        pakfile.files.pop();
+        let written = Pakfile::from_bytes(pakfile.to_bytes().unwrap()).unwrap();
+        assert_eq!(written.entries().len(), 65535);
+
+        let mut pakfile = PakfileWriter::new();
+        pakfile.insert(&"a".repeat(u16::MAX as usize + 1), vec![1]);
+        assert!(matches!(
+            pakfile.to_bytes(),
+            Err(BspError::BadPakfile("file name longer than 65535 bytes"))
+        ));
+    }
+}
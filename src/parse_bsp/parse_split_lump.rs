@@ -1,10 +1,20 @@
-use std::io::{BufReader, Cursor, Read, Seek, Write};
+use std::io::{Read, Seek, SeekFrom, Take, Write};
 
-use super::Lump;
+use lzma_rs::decompress::{Options, Stream, UnpackedSize};
+
+use super::{BspError, Lump};
+
+/// Size of the compressed blocks fed to the LZMA decoder at a time.
+const COMPRESSED_BLOCK_SIZE: usize = 16 * 1024;
+
+/// Most memory reserved before reading a lump, beyond this buffers grow as
+/// the data arrives. The size of compressed lumps comes from their header,
+/// corrupt maps can claim up to 4 GiB.
+const MAX_PREALLOCATION: usize = 16 * 1024 * 1024;
 
 pub(super) enum ChunkReader<'a, T: Read> {
     Uncompressed(&'a mut T),
-    Compressed(Cursor<Vec<u8>>),
+    Compressed(Box<LzmaReader<'a, T>>),
 }
 
 impl<'a, T: Read> Read for ChunkReader<'a, T> {
@@ -16,35 +26,144 @@ impl<'a, T: Read> Read for ChunkReader<'a, T> {
     }
 }
 
-pub(super) fn decompress_stream<FileType: Read + Seek>(
+/// Decodes an LZMA compressed lump incrementally as it is read.
+///
+/// The decoder only hands out output when its dictionary fills up or the
+/// stream ends, so besides the dictionary this holds whatever it flushed for
+/// the last block of input. Lumps smaller than the dictionary are decoded in
+/// one go when the input runs out.
+pub(super) struct LzmaReader<'a, T: Read> {
+    source: Take<&'a mut T>,
+    decoder: Option<Stream<Vec<u8>>>,
+    output: Vec<u8>,
+    position: usize,
+}
+
+impl<'a, T: Read> LzmaReader<'a, T> {
+    /// Decodes a raw LZMA stream that is stored without the standard header.
+    pub(super) fn new(
+        source: Take<&'a mut T>,
+        properties: [u8; 5],
+        actual_size: u64,
+    ) -> std::io::Result<Self> {
+        let mut standard_header: [u8; 13] = [0; 13];
+        standard_header[0..5].copy_from_slice(&properties);
+        standard_header[5..13].copy_from_slice(&actual_size.to_le_bytes());
+
+        let mut decoder = Stream::new_with_options(
+            &Options {
+                unpacked_size: UnpackedSize::ReadHeaderButUseProvided(Some(actual_size)),
+                ..Default::default()
+            },
+            vec![],
+        );
+        decoder.write_all(&standard_header).map_err(decoder_error)?;
+
+        return Ok(LzmaReader {
+            source,
+            decoder: Some(decoder),
+            output: vec![],
+            position: 0,
+        });
+    }
+
+    /// Feeds the decoder until it produces more output or the input runs out.
+    fn fill_output(&mut self) -> std::io::Result<()> {
+        let mut block = [0u8; COMPRESSED_BLOCK_SIZE];
+        while let Some(decoder) = self.decoder.as_mut() {
+            let read = self.source.read(&mut block)?;
+
+            // The decoder stops accepting input once it has produced the expected
+            // number of bytes, any trailing end marker is ignored.
+            let mut consumed = 0;
+            while consumed < read {
+                match decoder
+                    .write(&block[consumed..read])
+                    .map_err(decoder_error)?
+                {
+                    0 => break,
+                    count => consumed += count,
+                }
+            }
+
+            if read == 0 || consumed < read {
+                let decoder = self.decoder.take().unwrap();
+                self.output = decoder.finish().map_err(decompression_error)?;
+                self.position = 0;
+                return Ok(());
+            }
+
+            let output = decoder.get_output_mut().unwrap();
+            if !output.is_empty() {
+                self.output = std::mem::take(output);
+                self.position = 0;
+                return Ok(());
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Wraps `error` so it can pass through [`Read`], converting the
+/// [`std::io::Error`] back into a [`BspError`] unwraps it again.
+fn decompression_error(error: lzma_rs::error::Error) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, error);
+}
+
+/// The decoder's [`Write`] implementation reports corrupt data as plain
+/// [`std::io::Error`]s, writing into a `Vec` can't fail otherwise.
+fn decoder_error(error: std::io::Error) -> std::io::Error {
+    return decompression_error(lzma_rs::error::Error::LzmaError(error.to_string()));
+}
+
+impl<'a, T: Read> Read for LzmaReader<'a, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.output.len() {
+            self.fill_output()?;
+        }
+
+        let available = &self.output[self.position.min(self.output.len())..];
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.position += count;
+        return Ok(count);
+    }
+}
+
+fn decompress_stream<FileType: Read + Seek>(
     file: &mut FileType,
     lump: Lump,
-) -> std::io::Result<(ChunkReader<'_, FileType>, usize)> {
-    file.seek(std::io::SeekFrom::Start(lump.offset as u64))?;
+) -> Result<(ChunkReader<'_, FileType>, usize), BspError> {
+    let file_length = file.seek(SeekFrom::End(0))?;
+    if lump.offset as u64 + lump.length as u64 > file_length {
+        return Err(BspError::TruncatedLump {
+            offset: lump.offset as u64,
+            length: lump.length as u64,
+            file_length,
+        });
+    }
+
+    file.seek(SeekFrom::Start(lump.offset as u64))?;
     if lump.is_compressed() {
         let mut header_bytes = [0u8; 17];
         file.read_exact(&mut header_bytes)?;
 
-        assert_eq!(&header_bytes[0..4], b"LZMA");
+        let tag: [u8; 4] = header_bytes[0..4].try_into().unwrap();
+        if &tag != b"LZMA" {
+            return Err(BspError::BadCompressionTag(tag));
+        }
 
         let actual_size = u32::from_le_bytes(header_bytes[4..8].try_into().unwrap());
         let compressed_size = u32::from_le_bytes(header_bytes[8..12].try_into().unwrap());
 
         let properties: [u8; 5] = header_bytes[12..17].try_into().unwrap();
 
-        let mut standard_header: [u8; 13] = [0; 13];
-        let mut standard_writer: &mut [u8] = &mut standard_header;
-        standard_writer.write_all(&properties)?;
-        standard_writer.write_all(&(actual_size as u64).to_le_bytes())?;
-
-        let mut file = BufReader::new(standard_header.chain(file.take(compressed_size as u64)));
-
-        // todo: A more efficient stream adapter
-        let mut output = vec![];
-        lzma_rs::lzma_decompress(&mut file, &mut output).unwrap();
-
         return Ok((
-            ChunkReader::Compressed(Cursor::new(output)),
+            ChunkReader::Compressed(Box::new(LzmaReader::new(
+                file.take(compressed_size as u64),
+                properties,
+                actual_size as u64,
+            )?)),
             actual_size as usize,
         ));
     } else {
@@ -52,16 +171,50 @@ pub(super) fn decompress_stream<FileType: Read + Seek>(
     }
 }
 
+/// Reads the decompressed contents of `lump`.
+pub(super) fn read_lump<FileType: Read + Seek>(
+    file: &mut FileType,
+    lump: Lump,
+) -> Result<Vec<u8>, BspError> {
+    let (stream, length) = decompress_stream(file, lump)?;
+    return read_length(stream, length);
+}
+
+/// Reads `length` bytes, growing the buffer as they arrive instead of
+/// trusting a length that came from the file.
+pub(super) fn read_length<T: Read>(stream: T, length: usize) -> Result<Vec<u8>, BspError> {
+    let mut out = Vec::with_capacity(length.min(MAX_PREALLOCATION));
+    stream.take(length as u64).read_to_end(&mut out)?;
+    if out.len() < length {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    return Ok(out);
+}
+
 pub(super) fn parse_split_chunks<
     T,
     FileType: Read + Seek,
     const LENGTH: usize,
     Function: FnMut([u8; LENGTH]) -> T,
+>(
+    file: &mut FileType,
+    lump: Lump,
+    mut f: Function,
+) -> Result<Vec<T>, BspError> {
+    try_parse_split_chunks(file, lump, |data| Ok(f(data)))
+}
+
+/// Like [`parse_split_chunks`], but for records that can fail to parse.
+pub(super) fn try_parse_split_chunks<
+    T,
+    FileType: Read + Seek,
+    const LENGTH: usize,
+    Function: FnMut([u8; LENGTH]) -> Result<T, BspError>,
 >(
     file: &mut FileType,
     lump: Lump,
     f: Function,
-) -> std::io::Result<Vec<T>> {
+) -> Result<Vec<T>, BspError> {
     let (mut decompressed_stream, length) = decompress_stream(file, lump)?;
     chunks_from_uncompressed_file(&mut decompressed_stream, length, f)
 }
@@ -70,21 +223,108 @@ fn chunks_from_uncompressed_file<
     T,
     FileType: Read,
     const LENGTH: usize,
-    Function: FnMut([u8; LENGTH]) -> T,
+    Function: FnMut([u8; LENGTH]) -> Result<T, BspError>,
 >(
     file: &mut FileType,
     length: usize,
     mut f: Function,
-) -> std::io::Result<Vec<T>> {
-    assert!(length % LENGTH == 0);
+) -> Result<Vec<T>, BspError> {
+    if !length.is_multiple_of(LENGTH) {
+        return Err(BspError::BadRecordSize {
+            length,
+            record_size: LENGTH,
+        });
+    }
 
-    let mut out: Vec<T> = Vec::with_capacity(length as usize / LENGTH);
+    let mut out: Vec<T> = Vec::with_capacity(length.min(MAX_PREALLOCATION) / LENGTH);
     for _i in 0..length / LENGTH {
         let mut data = [0u8; LENGTH];
         file.read_exact(&mut data)?;
 
-        out.push(f(data));
+        out.push(f(data)?);
     }
 
     return Ok(out);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// `data` in a compressed lump as Source stores it, at offset 0 of the file.
+    fn compressed_lump(data: &[u8]) -> (Vec<u8>, Lump) {
+        let mut standard = vec![];
+        lzma_rs::lzma_compress(&mut &data[..], &mut standard).unwrap();
+        // The standard header is 5 property bytes and the 8 byte unpacked size
+        let (properties, stream) = (&standard[0..5], &standard[13..]);
+
+        let mut file = b"LZMA".to_vec();
+        file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file.extend_from_slice(&(stream.len() as u32).to_le_bytes());
+        file.extend_from_slice(properties);
+        file.extend_from_slice(stream);
+        let lump = Lump {
+            offset: 0,
+            length: file.len() as u32,
+            version: 0,
+            id: (data.len() as u32).to_le_bytes(),
+        };
+        return (file, lump);
+    }
+
+    #[test]
+    fn decodes_compressed_lump() {
+        let data = (0..5000u32)
+            .flat_map(|k| (k % 300).to_le_bytes())
+            .collect::<Vec<_>>();
+        let (file, lump) = compressed_lump(&data);
+
+        let records = parse_split_chunks(&mut Cursor::new(file), lump, u32::from_le_bytes).unwrap();
+        assert_eq!(records, (0..5000).map(|k| k % 300).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn corrupt_lump_is_a_decompression_error() {
+        let data = (0..5000u32)
+            .flat_map(|k| (k % 300).to_le_bytes())
+            .collect::<Vec<_>>();
+        let (mut file, lump) = compressed_lump(&data);
+        for byte in &mut file[40..] {
+            *byte = !*byte;
+        }
+
+        let result = parse_split_chunks(&mut Cursor::new(file), lump, u32::from_le_bytes);
+        assert!(matches!(result, Err(BspError::DecompressionError(_))));
+    }
+
+    #[test]
+    fn truncated_lump_is_a_decompression_error() {
+        let data = (0..5000u32)
+            .flat_map(|k| k.to_le_bytes())
+            .collect::<Vec<_>>();
+        let (mut file, mut lump) = compressed_lump(&data);
+        let stream_length = (file.len() - 17) as u32 / 2;
+        file[8..12].copy_from_slice(&stream_length.to_le_bytes());
+        lump.length = 17 + stream_length;
+
+        let result = parse_split_chunks(&mut Cursor::new(file), lump, u32::from_le_bytes);
+        assert!(matches!(result, Err(BspError::DecompressionError(_))));
+    }
+
+    #[test]
+    fn inflated_size_is_not_allocated_up_front() {
+        let data = (0..5000u32)
+            .flat_map(|k| k.to_le_bytes())
+            .collect::<Vec<_>>();
+        let (mut file, lump) = compressed_lump(&data);
+        file[4..8].copy_from_slice(&(u32::MAX - 3).to_le_bytes());
+
+        // The stream ends long before the claimed size
+        let result = read_lump(&mut Cursor::new(&file), lump);
+        assert!(matches!(result, Err(BspError::DecompressionError(_))));
+        let result = parse_split_chunks(&mut Cursor::new(&file), lump, u32::from_le_bytes);
+        assert!(matches!(result, Err(BspError::DecompressionError(_))));
+    }
+}
@@ -1,10 +1,13 @@
 use std::io::{Read, Seek};
 
-use crate::{parse_bsp::parse_vector3, vector::Vec3};
+use crate::{
+    parse_bsp::{parse_vector3, write_vector3},
+    vector::Vec3,
+};
 
-use super::{parse_split_lump::parse_split_chunks, Lump};
+use super::{parse_split_lump::try_parse_split_chunks, record::Record, BspError, ByteOrder, Lump};
 
-pub(super) struct Plane {
+pub struct Plane {
     pub normal: Vec3,
     pub distance: f32,
     pub axis: u32,
@@ -13,10 +16,29 @@ pub(super) struct Plane {
 pub(super) fn parse_planes<T: Read + Seek>(
     file: &mut T,
     lump: Lump,
-) -> std::io::Result<Vec<Plane>> {
-    parse_split_chunks(file, lump, |bytes: [u8; 20]| Plane {
-        normal: parse_vector3(bytes[0..12].try_into().unwrap()),
-        distance: f32::from_le_bytes(bytes[12..16].try_into().unwrap()),
-        axis: u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+    order: ByteOrder,
+) -> Result<Vec<Plane>, BspError> {
+    lump.expect_version(Plane::VERSIONS, Plane::NAME)?;
+    try_parse_split_chunks(file, lump, |bytes: [u8; 20]| {
+        Plane::from_bytes(&bytes, order)
     })
 }
+
+impl Record for Plane {
+    const SIZE: usize = 20;
+    const NAME: &'static str = "planes";
+
+    fn from_bytes(bytes: &[u8], order: ByteOrder) -> Result<Self, BspError> {
+        return Ok(Plane {
+            normal: parse_vector3(&bytes[0..12], order),
+            distance: order.f32(&bytes[12..16]),
+            axis: order.u32(&bytes[16..20]),
+        });
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>, order: ByteOrder) {
+        write_vector3(out, self.normal, order);
+        order.put_f32(out, self.distance);
+        order.put_u32(out, self.axis);
+    }
+}
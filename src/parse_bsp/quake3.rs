@@ -0,0 +1,543 @@
+//! id Tech 3 (Quake 3) maps, which use the `IBSP` magic.
+//!
+//! Surfaces reference vertices directly instead of going through edges, and
+//! curved surfaces are stored as bezier patches that have to be tessellated.
+
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use super::{
+    bsp_to_primitives::MaterialGroup,
+    entity::{self, Entity},
+    error::lookup,
+    parse_split_lump::{parse_split_chunks, try_parse_split_chunks},
+    parse_vector2, parse_vector3, BspError, ByteOrder, Lump,
+};
+use crate::vector::{Vec2, Vec3};
+
+pub mod lump_names {
+    pub const LUMP_ENTITIES: usize = 0;
+    pub const LUMP_SHADERS: usize = 1;
+    pub const LUMP_PLANES: usize = 2;
+    pub const LUMP_NODES: usize = 3;
+    pub const LUMP_LEAFS: usize = 4;
+    pub const LUMP_LEAFSURFACES: usize = 5;
+    pub const LUMP_LEAFBRUSHES: usize = 6;
+    pub const LUMP_MODELS: usize = 7;
+    pub const LUMP_BRUSHES: usize = 8;
+    pub const LUMP_BRUSHSIDES: usize = 9;
+    pub const LUMP_DRAWVERTS: usize = 10;
+    pub const LUMP_DRAWINDEXES: usize = 11;
+    pub const LUMP_FOGS: usize = 12;
+    pub const LUMP_SURFACES: usize = 13;
+    pub const LUMP_LIGHTMAPS: usize = 14;
+    pub const LUMP_LIGHTGRID: usize = 15;
+    pub const LUMP_VISIBILITY: usize = 16;
+}
+
+pub const HEADER_LUMPS: usize = 17;
+
+pub const VERSION: u32 = 46;
+
+#[allow(unused)]
+pub mod surface_flags {
+    pub const SURF_NODAMAGE: u32 = 0x0001;
+    pub const SURF_SLICK: u32 = 0x0002;
+    pub const SURF_SKY: u32 = 0x0004;
+    pub const SURF_LADDER: u32 = 0x0008;
+    pub const SURF_NOIMPACT: u32 = 0x0010;
+    pub const SURF_NOMARKS: u32 = 0x0020;
+    pub const SURF_FLESH: u32 = 0x0040;
+    pub const SURF_NODRAW: u32 = 0x0080;
+    pub const SURF_HINT: u32 = 0x0100;
+    pub const SURF_SKIP: u32 = 0x0200;
+    pub const SURF_NOLIGHTMAP: u32 = 0x0400;
+    pub const SURF_POINTLIGHT: u32 = 0x0800;
+    pub const SURF_METALSTEPS: u32 = 0x1000;
+    pub const SURF_NOSTEPS: u32 = 0x2000;
+    pub const SURF_NONSOLID: u32 = 0x4000;
+    pub const SURF_LIGHTFILTER: u32 = 0x8000;
+    pub const SURF_ALPHASHADOW: u32 = 0x10000;
+    pub const SURF_NODLIGHT: u32 = 0x20000;
+}
+
+/// A Quake 3 BSP file.
+///
+/// Every lump needed to build the geometry is decoded when the file is opened.
+pub struct Quake3Bsp {
+    pub version: u32,
+    pub lumps: Vec<Lump>,
+    pub entities: Vec<Entity>,
+    pub shaders: Vec<Shader>,
+    pub models: Vec<Quake3Model>,
+    pub vertexes: Vec<Quake3Vertex>,
+    /// Vertex offsets relative to the `first_vertex` of the face using them.
+    pub mesh_vertexes: Vec<i32>,
+    pub faces: Vec<Quake3Face>,
+}
+
+pub struct Shader {
+    pub name: String,
+    pub surface_flags: u32,
+    pub contents: u32,
+}
+
+impl Shader {
+    /// The material name used for the shader, without the `textures/` prefix.
+    pub fn material_name(&self) -> &str {
+        return self.name.strip_prefix("textures/").unwrap_or(&self.name);
+    }
+}
+
+pub struct Quake3Model {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub first_face: u32,
+    pub num_faces: u32,
+    pub first_brush: u32,
+    pub num_brushes: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Quake3Vertex {
+    pub position: Vec3,
+    pub uv: Vec2,
+    pub lightmap_uv: Vec2,
+    pub normal: Vec3,
+    pub color: [u8; 4],
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FaceType {
+    Polygon,
+    Patch,
+    Mesh,
+    Billboard,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Quake3Face {
+    pub shader: u32,
+    pub effect: i32,
+    pub face_type: FaceType,
+    pub first_vertex: u32,
+    pub num_vertexes: u32,
+    pub first_mesh_vertex: u32,
+    pub num_mesh_vertexes: u32,
+    pub lightmap_index: i32,
+    pub normal: Vec3,
+    /// Number of control points in each direction, only used by patches.
+    pub patch_size: [u32; 2],
+}
+
+impl Quake3Bsp {
+    /// Opens the BSP file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, BspError> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        return Quake3Bsp::from_reader(BufReader::new(file));
+    }
+
+    /// Reads a BSP file that is already loaded into memory.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BspError> {
+        return Quake3Bsp::from_reader(Cursor::new(bytes));
+    }
+
+    /// Reads a BSP file from any seekable source.
+    pub fn from_reader<R: Read + Seek>(mut file: R) -> Result<Self, BspError> {
+        let (version, lumps) = read_header(&mut file)?;
+        let order = ByteOrder::Little;
+
+        return Ok(Quake3Bsp {
+            version,
+            entities: entity::parse_entity_lump(&mut file, lumps[lump_names::LUMP_ENTITIES])?,
+            shaders: parse_split_chunks(
+                &mut file,
+                lumps[lump_names::LUMP_SHADERS],
+                |bytes: [u8; 72]| {
+                    let name_end = bytes[..64].iter().position(|&k| k == 0).unwrap_or(64);
+                    Shader {
+                        name: String::from_utf8_lossy(&bytes[..name_end]).into_owned(),
+                        surface_flags: order.u32(&bytes[64..68]),
+                        contents: order.u32(&bytes[68..72]),
+                    }
+                },
+            )?,
+            models: parse_split_chunks(
+                &mut file,
+                lumps[lump_names::LUMP_MODELS],
+                |bytes: [u8; 40]| Quake3Model {
+                    min: parse_vector3(&bytes[0..12], order),
+                    max: parse_vector3(&bytes[12..24], order),
+                    first_face: order.u32(&bytes[24..28]),
+                    num_faces: order.u32(&bytes[28..32]),
+                    first_brush: order.u32(&bytes[32..36]),
+                    num_brushes: order.u32(&bytes[36..40]),
+                },
+            )?,
+            vertexes: parse_split_chunks(
+                &mut file,
+                lumps[lump_names::LUMP_DRAWVERTS],
+                |bytes: [u8; 44]| Quake3Vertex {
+                    position: parse_vector3(&bytes[0..12], order),
+                    uv: parse_vector2(&bytes[12..20], order),
+                    lightmap_uv: parse_vector2(&bytes[20..28], order),
+                    normal: parse_vector3(&bytes[28..40], order),
+                    color: bytes[40..44].try_into().unwrap(),
+                },
+            )?,
+            mesh_vertexes: parse_split_chunks(
+                &mut file,
+                lumps[lump_names::LUMP_DRAWINDEXES],
+                |bytes: [u8; 4]| order.i32(&bytes),
+            )?,
+            faces: try_parse_split_chunks(
+                &mut file,
+                lumps[lump_names::LUMP_SURFACES],
+                |bytes: [u8; 104]| {
+                    let face_type = match order.i32(&bytes[8..12]) {
+                        1 => FaceType::Polygon,
+                        2 => FaceType::Patch,
+                        3 => FaceType::Mesh,
+                        4 => FaceType::Billboard,
+                        other => return Err(BspError::UnknownFaceType(other)),
+                    };
+                    Ok(Quake3Face {
+                        shader: order.u32(&bytes[0..4]),
+                        effect: order.i32(&bytes[4..8]),
+                        face_type,
+                        first_vertex: order.u32(&bytes[12..16]),
+                        num_vertexes: order.u32(&bytes[16..20]),
+                        first_mesh_vertex: order.u32(&bytes[20..24]),
+                        num_mesh_vertexes: order.u32(&bytes[24..28]),
+                        lightmap_index: order.i32(&bytes[28..32]),
+                        normal: parse_vector3(&bytes[84..96], order),
+                        patch_size: [order.u32(&bytes[96..100]), order.u32(&bytes[100..104])],
+                    })
+                },
+            )?,
+            lumps,
+        });
+    }
+
+    /// Triangulates the world model, grouping the geometry by shader.
+    ///
+    /// Patches are tessellated into `subdivisions` by `subdivisions` quads per
+    /// 3x3 block of control points.
+    pub fn to_primitives(
+        &self,
+        subdivisions: usize,
+    ) -> Result<HashMap<String, MaterialGroup>, BspError> {
+        let mut groups: HashMap<String, MaterialGroup> = HashMap::new();
+
+        let world = lookup(&self.models, 0, "models")?;
+        let first_face = world.first_face as usize;
+        let end = first_face + world.num_faces as usize;
+        let world_faces = self
+            .faces
+            .get(first_face..end)
+            .ok_or(BspError::IndexOutOfRange {
+                kind: "faces",
+                index: end,
+                length: self.faces.len(),
+            })?;
+
+        for face in world_faces {
+            let shader = lookup(&self.shaders, face.shader as usize, "shaders")?;
+            if shader.surface_flags & surface_flags::SURF_NODRAW > 0
+                || shader.surface_flags & surface_flags::SURF_SKY > 0
+                || shader.surface_flags & surface_flags::SURF_HINT > 0
+                || shader.surface_flags & surface_flags::SURF_SKIP > 0
+                || shader.name == "noshader"
+            {
+                continue;
+            }
+
+            let group = groups
+                .entry(shader.material_name().to_owned())
+                .or_insert(MaterialGroup {
+                    verticies: vec![],
+                    normals: vec![],
+                    uvs: vec![],
+                    lightmap_uvs: vec![],
+                    indices: vec![],
+                });
+
+            match face.face_type {
+                FaceType::Polygon | FaceType::Mesh => self.push_mesh(group, face)?,
+                FaceType::Patch => self.push_patch(group, face, subdivisions.max(1))?,
+                // Flares are drawn by the renderer and have no geometry
+                FaceType::Billboard => {}
+            }
+        }
+
+        return Ok(groups);
+    }
+
+    fn push_mesh(&self, group: &mut MaterialGroup, face: &Quake3Face) -> Result<(), BspError> {
+        let start = face.first_mesh_vertex as usize;
+        let count = face.num_mesh_vertexes as usize;
+        let mesh_vertexes =
+            self.mesh_vertexes
+                .get(start..start + count)
+                .ok_or(BspError::IndexOutOfRange {
+                    kind: "mesh vertexes",
+                    index: start + count,
+                    length: self.mesh_vertexes.len(),
+                })?;
+
+        for triangle in mesh_vertexes.chunks_exact(3) {
+            let corners = triangle
+                .iter()
+                .map(|&offset| {
+                    lookup(
+                        &self.vertexes,
+                        (face.first_vertex as i64 + offset as i64) as usize,
+                        "vertexes",
+                    )
+                    .copied()
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            push_triangle(group, [corners[0], corners[1], corners[2]]);
+        }
+
+        return Ok(());
+    }
+
+    fn push_patch(
+        &self,
+        group: &mut MaterialGroup,
+        face: &Quake3Face,
+        subdivisions: usize,
+    ) -> Result<(), BspError> {
+        let [width, height] = face.patch_size.map(|k| k as usize);
+        if width < 3 || height < 3 || width % 2 == 0 || height % 2 == 0 {
+            return Err(BspError::InvalidPatch {
+                width: face.patch_size[0],
+                height: face.patch_size[1],
+            });
+        }
+
+        let start = face.first_vertex as usize;
+        let controls =
+            self.vertexes
+                .get(start..start + width * height)
+                .ok_or(BspError::IndexOutOfRange {
+                    kind: "vertexes",
+                    index: start + width * height,
+                    length: self.vertexes.len(),
+                })?;
+
+        for patch_y in 0..(height - 1) / 2 {
+            for patch_x in 0..(width - 1) / 2 {
+                let control =
+                    |x: usize, y: usize| controls[(patch_y * 2 + y) * width + patch_x * 2 + x];
+
+                let grid = (0..=subdivisions)
+                    .flat_map(|y| (0..=subdivisions).map(move |x| (x, y)))
+                    .map(|(x, y)| {
+                        let u = x as f32 / subdivisions as f32;
+                        let v = y as f32 / subdivisions as f32;
+                        let rows = [0, 1, 2].map(|row| {
+                            bezier([control(0, row), control(1, row), control(2, row)], u)
+                        });
+                        bezier(rows, v)
+                    })
+                    .collect::<Vec<_>>();
+
+                let at = |x: usize, y: usize| grid[y * (subdivisions + 1) + x];
+                for y in 0..subdivisions {
+                    for x in 0..subdivisions {
+                        push_triangle(group, [at(x, y), at(x + 1, y), at(x, y + 1)]);
+                        push_triangle(group, [at(x + 1, y), at(x + 1, y + 1), at(x, y + 1)]);
+                    }
+                }
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+fn read_header<R: Read + Seek>(file: &mut R) -> Result<(u32, Vec<Lump>), BspError> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut header_bytes = [0u8; 8 + HEADER_LUMPS * 8];
+    file.read_exact(&mut header_bytes)?;
+
+    let ident: [u8; 4] = header_bytes[0..4].try_into().unwrap();
+    if &ident != b"IBSP" {
+        return Err(BspError::BadMagic(ident));
+    }
+    let version = ByteOrder::Little.u32(&header_bytes[4..8]);
+    if version != VERSION {
+        return Err(BspError::UnsupportedVersion(version));
+    }
+
+    let lumps = header_bytes[8..]
+        .chunks_exact(8)
+        .map(|bytes| Lump {
+            offset: ByteOrder::Little.u32(&bytes[0..4]),
+            length: ByteOrder::Little.u32(&bytes[4..8]),
+            version: 0,
+            id: [0; 4],
+        })
+        .collect();
+
+    return Ok((version, lumps));
+}
+
+/// Evaluates a quadratic bezier curve through three control points.
+fn bezier(points: [Quake3Vertex; 3], t: f32) -> Quake3Vertex {
+    let weights = [(1.0 - t) * (1.0 - t), 2.0 * t * (1.0 - t), t * t];
+    let blend_3 = |f: fn(&Quake3Vertex) -> Vec3| {
+        f(&points[0]) * weights[0] + f(&points[1]) * weights[1] + f(&points[2]) * weights[2]
+    };
+    let blend_2 = |f: fn(&Quake3Vertex) -> Vec2| {
+        f(&points[0]) * weights[0] + f(&points[1]) * weights[1] + f(&points[2]) * weights[2]
+    };
+
+    return Quake3Vertex {
+        position: blend_3(|k| k.position),
+        uv: blend_2(|k| k.uv),
+        lightmap_uv: blend_2(|k| k.lightmap_uv),
+        normal: blend_3(|k| k.normal).normalize(),
+        color: points[1].color,
+    };
+}
+
+/// Adds a triangle, making it counter-clockwise when seen from the side its
+/// vertex normals point to.
+fn push_triangle(group: &mut MaterialGroup, mut corners: [Quake3Vertex; 3]) {
+    let face_normal = (corners[1].position - corners[0].position)
+        .cross(corners[2].position - corners[0].position);
+    let vertex_normal = corners[0].normal + corners[1].normal + corners[2].normal;
+    if face_normal.dot(&vertex_normal) < 0.0 {
+        corners.swap(1, 2);
+    }
+
+    let initial_index = group.verticies.len();
+    for corner in corners {
+        group.verticies.push(corner.position.to_y_up() * 0.1);
+        group.normals.push(corner.normal.to_y_up());
+        group.uvs.push(corner.uv);
+    }
+    group
+        .indices
+        .extend_from_slice(&[initial_index, initial_index + 1, initial_index + 2]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A flat patch of `width` by `height` control points one unit apart.
+    fn patch_bsp(width: u32, height: u32) -> Quake3Bsp {
+        let vertexes = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| Quake3Vertex {
+                position: Vec3 {
+                    x: x as f32,
+                    y: y as f32,
+                    z: 0.0,
+                },
+                uv: Vec2 {
+                    x: x as f32,
+                    y: y as f32,
+                },
+                lightmap_uv: Vec2 { x: 0.0, y: 0.0 },
+                normal: Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                color: [255; 4],
+            })
+            .collect();
+
+        return Quake3Bsp {
+            version: VERSION,
+            lumps: vec![],
+            entities: vec![],
+            shaders: vec![Shader {
+                name: "textures/base/floor".to_owned(),
+                surface_flags: 0,
+                contents: 0,
+            }],
+            models: vec![Quake3Model {
+                min: Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                max: Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                first_face: 0,
+                num_faces: 1,
+                first_brush: 0,
+                num_brushes: 0,
+            }],
+            vertexes,
+            mesh_vertexes: vec![],
+            faces: vec![Quake3Face {
+                shader: 0,
+                effect: -1,
+                face_type: FaceType::Patch,
+                first_vertex: 0,
+                num_vertexes: width * height,
+                first_mesh_vertex: 0,
+                num_mesh_vertexes: 0,
+                lightmap_index: -1,
+                normal: Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                patch_size: [width, height],
+            }],
+        };
+    }
+
+    #[test]
+    fn tessellates_patches() {
+        let groups = patch_bsp(3, 3).to_primitives(4).unwrap();
+        let group = &groups["base/floor"];
+        // 4x4 quads of two triangles, each with its own three vertices
+        assert_eq!(group.verticies.len(), 4 * 4 * 2 * 3);
+        assert_eq!(group.indices.len(), 4 * 4 * 2 * 3);
+        assert_eq!(group.uvs[0], Vec2 { x: 0.0, y: 0.0 });
+        assert!(group
+            .uvs
+            .iter()
+            .all(|uv| (0.0..=2.0).contains(&uv.x) && (0.0..=2.0).contains(&uv.y)));
+
+        // A 5x3 patch is two 3x3 blocks side by side
+        let groups = patch_bsp(5, 3).to_primitives(2).unwrap();
+        assert_eq!(groups["base/floor"].indices.len(), 2 * 2 * 2 * 2 * 3);
+    }
+
+    #[test]
+    fn rejects_bad_patches() {
+        for [width, height] in [[2, 3], [4, 3], [3, 1]] {
+            assert!(matches!(
+                patch_bsp(width, height).to_primitives(4),
+                Err(BspError::InvalidPatch { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn world_face_range_past_the_end_is_an_error() {
+        let mut bsp = patch_bsp(3, 3);
+        bsp.models[0].first_face = u32::MAX;
+        assert!(matches!(
+            bsp.to_primitives(4),
+            Err(BspError::IndexOutOfRange { kind: "faces", .. })
+        ));
+    }
+}
@@ -0,0 +1,77 @@
+use std::marker::PhantomData;
+
+use super::{BspError, ByteOrder};
+
+/// A fixed size record stored in a lump.
+pub trait Record: Sized + 'static {
+    /// Size of one record in bytes.
+    const SIZE: usize;
+    /// Name of the lump the records are stored in, for error messages.
+    const NAME: &'static str;
+    /// Lump versions that use this layout.
+    const VERSIONS: &'static [u32] = &[0];
+
+    /// Decodes one record, `bytes` is exactly [`Record::SIZE`] bytes long.
+    fn from_bytes(bytes: &[u8], order: ByteOrder) -> Result<Self, BspError>;
+
+    /// Encodes the record, appending exactly [`Record::SIZE`] bytes to `out`.
+    fn write_bytes(&self, out: &mut Vec<u8>, order: ByteOrder);
+}
+
+/// A borrowed view of an uncompressed lump, records are decoded on access
+/// instead of being copied out up front.
+#[derive(Copy, Clone)]
+pub struct RecordSlice<'a, T> {
+    bytes: &'a [u8],
+    order: ByteOrder,
+    _record: PhantomData<T>,
+}
+
+impl<'a, T: Record> RecordSlice<'a, T> {
+    pub fn new(bytes: &'a [u8], order: ByteOrder) -> Result<Self, BspError> {
+        if !bytes.len().is_multiple_of(T::SIZE) {
+            return Err(BspError::BadRecordSize {
+                length: bytes.len(),
+                record_size: T::SIZE,
+            });
+        }
+
+        return Ok(RecordSlice {
+            bytes,
+            order,
+            _record: PhantomData,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        return self.bytes.len() / T::SIZE;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.bytes.is_empty();
+    }
+
+    /// The undecoded bytes of the record at `index`.
+    pub fn raw(&self, index: usize) -> Result<&'a [u8], BspError> {
+        return self
+            .bytes
+            .get(index * T::SIZE..(index + 1) * T::SIZE)
+            .ok_or(BspError::IndexOutOfRange {
+                kind: T::NAME,
+                index,
+                length: self.len(),
+            });
+    }
+
+    pub fn get(&self, index: usize) -> Result<T, BspError> {
+        return T::from_bytes(self.raw(index)?, self.order);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Result<T, BspError>> + 'a {
+        let order = self.order;
+        return self
+            .bytes
+            .chunks_exact(T::SIZE)
+            .map(move |bytes| T::from_bytes(bytes, order));
+    }
+}
@@ -0,0 +1,311 @@
+use crate::vector::Vec3;
+
+use super::{error::lookup, parse_vector3, BspError, ByteOrder};
+
+/// Game lump id of the static props.
+pub const STATIC_PROP_LUMP_ID: [u8; 4] = *b"sprp";
+
+/// The contents of the `sprp` game lump.
+#[derive(Clone, Debug, Default)]
+pub struct StaticPropLump {
+    /// Paths of the models used by the props.
+    pub model_names: Vec<String>,
+    /// Leaves the props are in, referenced by `first_leaf` and `leaf_count`.
+    pub leaves: Vec<u16>,
+    pub props: Vec<StaticProp>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Solidity {
+    NotSolid,
+    /// Uses the bounding box of the model.
+    BoundingBox,
+    /// Uses the collision model.
+    VPhysics,
+    Other(u8),
+}
+
+impl Solidity {
+    fn from_id(id: u8) -> Self {
+        return match id {
+            0 => Solidity::NotSolid,
+            2 => Solidity::BoundingBox,
+            6 => Solidity::VPhysics,
+            other => Solidity::Other(other),
+        };
+    }
+}
+
+#[allow(unused)]
+pub mod static_prop_flags {
+    pub const STATIC_PROP_FLAG_FADES: u8 = 0x01;
+    pub const STATIC_PROP_USE_LIGHTING_ORIGIN: u8 = 0x02;
+    pub const STATIC_PROP_NO_DRAW: u8 = 0x04;
+    pub const STATIC_PROP_IGNORE_NORMALS: u8 = 0x08;
+    pub const STATIC_PROP_NO_SHADOW: u8 = 0x10;
+    pub const STATIC_PROP_SCREEN_SPACE_FADE: u8 = 0x20;
+    pub const STATIC_PROP_NO_PER_VERTEX_LIGHTING: u8 = 0x40;
+    pub const STATIC_PROP_NO_SELF_SHADOWING: u8 = 0x80;
+}
+
+#[derive(Clone, Debug)]
+pub struct StaticProp {
+    pub origin: Vec3,
+    /// Pitch, yaw and roll in degrees, stored in `x`, `y` and `z`.
+    pub angles: Vec3,
+    pub model_index: u16,
+    pub model_name: String,
+    pub first_leaf: u16,
+    pub leaf_count: u16,
+    pub solidity: Solidity,
+    /// See [`static_prop_flags`].
+    pub flags: u8,
+    pub skin: i32,
+    pub fade_min_distance: f32,
+    pub fade_max_distance: f32,
+    pub lighting_origin: Vec3,
+    /// Version 5 and up, 1 otherwise.
+    pub forced_fade_scale: f32,
+    /// Minimum and maximum DirectX level, version 6 and 7 as well as TF2's version 10.
+    pub dx_level: Option<[u16; 2]>,
+    /// Minimum and maximum CPU and GPU levels, versions 8 and up except in TF2.
+    pub cpu_gpu_level: Option<[u8; 4]>,
+    /// Per instance color and alpha, version 7 and up except in TF2, opaque
+    /// white otherwise.
+    pub diffuse_modulation: [u8; 4],
+    /// Width and height of the lightmap of props with baked lighting, only
+    /// in TF2's version 10.
+    pub lightmap_resolution: Option<[u16; 2]>,
+    /// Version 9 and up except in TF2.
+    pub disable_x360: bool,
+    /// Additional flags, version 10 and up. TF2's version 10 stores every
+    /// flag here, [`StaticProp::flags`] holds the lowest byte.
+    pub flags_ex: u32,
+    /// Version 11, 1 otherwise.
+    pub uniform_scale: f32,
+}
+
+/// Minimum size of a static prop record in each version, some games pad them.
+fn minimum_record_size(version: u16, bsp_version: u32) -> Option<usize> {
+    return match (version, bsp_version) {
+        (4, _) => Some(56),
+        (5, _) => Some(60),
+        (6, _) => Some(64),
+        (7, _) | (8, _) => Some(68),
+        (9, _) => Some(72),
+        // Team Fortress 2 extends the version 6 layout instead, with flags and
+        // the lightmap resolution
+        (10, 20) => Some(72),
+        (10, _) => Some(76),
+        (11, _) => Some(80),
+        _ => None,
+    };
+}
+
+pub(super) fn parse_static_props(
+    data: &[u8],
+    version: u16,
+    bsp_version: u32,
+    order: ByteOrder,
+) -> Result<StaticPropLump, BspError> {
+    let minimum_size =
+        minimum_record_size(version, bsp_version).ok_or(BspError::UnsupportedLumpVersion {
+            lump: "static props",
+            version: version as u32,
+        })?;
+
+    let mut position = 0;
+    let mut take = |length: usize| -> Result<&[u8], BspError> {
+        let bytes = data
+            .get(position..position + length)
+            .ok_or(BspError::BadRecordSize {
+                length: data.len(),
+                record_size: length,
+            })?;
+        position += length;
+        return Ok(bytes);
+    };
+
+    let name_count = order.u32(take(4)?) as usize;
+    let model_names = take(name_count * 128)?
+        .chunks_exact(128)
+        .map(|name| {
+            let end = name.iter().position(|&k| k == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..end]).into_owned()
+        })
+        .collect::<Vec<_>>();
+
+    let leaf_count = order.u32(take(4)?) as usize;
+    let leaves = take(leaf_count * 2)?
+        .chunks_exact(2)
+        .map(|leaf| order.u16(leaf))
+        .collect();
+
+    let prop_count = order.u32(take(4)?) as usize;
+    let remaining = data.len() - position;
+    if prop_count == 0 {
+        return Ok(StaticPropLump {
+            model_names,
+            leaves,
+            props: vec![],
+        });
+    }
+    let record_size = remaining / prop_count;
+    if !remaining.is_multiple_of(prop_count) || record_size < minimum_size {
+        return Err(BspError::BadRecordSize {
+            length: remaining,
+            record_size: minimum_size,
+        });
+    }
+
+    let tf2_layout = version == 10 && bsp_version == 20;
+    let props = data[position..]
+        .chunks_exact(record_size)
+        .map(|bytes| {
+            let model_index = order.u16(&bytes[24..26]);
+            let at_least = |required_version: u16| version >= required_version;
+
+            Ok(StaticProp {
+                origin: parse_vector3(&bytes[0..12], order),
+                angles: parse_vector3(&bytes[12..24], order),
+                model_index,
+                model_name: lookup(&model_names, model_index as usize, "static prop models")?
+                    .clone(),
+                first_leaf: order.u16(&bytes[26..28]),
+                leaf_count: order.u16(&bytes[28..30]),
+                solidity: Solidity::from_id(bytes[30]),
+                // Byte 31 is padding in TF2, the flags moved to 64..68
+                flags: match tf2_layout {
+                    true => order.u32(&bytes[64..68]) as u8,
+                    false => bytes[31],
+                },
+                skin: order.i32(&bytes[32..36]),
+                fade_min_distance: order.f32(&bytes[36..40]),
+                fade_max_distance: order.f32(&bytes[40..44]),
+                lighting_origin: parse_vector3(&bytes[44..56], order),
+                forced_fade_scale: match at_least(5) {
+                    true => order.f32(&bytes[56..60]),
+                    false => 1.0,
+                },
+                dx_level: match (6..=7).contains(&version) || tf2_layout {
+                    true => Some([order.u16(&bytes[60..62]), order.u16(&bytes[62..64])]),
+                    false => None,
+                },
+                cpu_gpu_level: match at_least(8) && !tf2_layout {
+                    true => Some(bytes[60..64].try_into().unwrap()),
+                    false => None,
+                },
+                diffuse_modulation: match at_least(7) && !tf2_layout {
+                    true => bytes[64..68].try_into().unwrap(),
+                    false => [255; 4],
+                },
+                lightmap_resolution: match tf2_layout {
+                    true => Some([order.u16(&bytes[68..70]), order.u16(&bytes[70..72])]),
+                    false => None,
+                },
+                disable_x360: at_least(9) && !tf2_layout && bytes[68] != 0,
+                flags_ex: match (version, tf2_layout) {
+                    (_, true) => order.u32(&bytes[64..68]),
+                    (10.., false) => order.u32(&bytes[72..76]),
+                    _ => 0,
+                },
+                uniform_scale: match at_least(11) {
+                    true => order.f32(&bytes[76..80]),
+                    false => 1.0,
+                },
+            })
+        })
+        .collect::<Result<Vec<_>, BspError>>()?;
+
+    return Ok(StaticPropLump {
+        model_names,
+        leaves,
+        props,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The lump header with a single model name and no leaves, followed by
+    /// one prop record.
+    fn lump_with_record(record: &[u8]) -> Vec<u8> {
+        let order = ByteOrder::Little;
+        let mut data = vec![];
+        order.put_u32(&mut data, 1);
+        let mut name = b"models/props/crate.mdl".to_vec();
+        name.resize(128, 0);
+        data.extend(name);
+        order.put_u32(&mut data, 0);
+        order.put_u32(&mut data, 1);
+        data.extend_from_slice(record);
+        return data;
+    }
+
+    /// The fields shared by every version, up to the forced fade scale.
+    fn common_fields() -> Vec<u8> {
+        let order = ByteOrder::Little;
+        let mut record = vec![];
+        for value in [1.0, 2.0, 3.0, 0.0, 90.0, 0.0] {
+            order.put_f32(&mut record, value);
+        }
+        order.put_u16(&mut record, 0);
+        order.put_u16(&mut record, 0);
+        order.put_u16(&mut record, 0);
+        record.extend_from_slice(&[6, 0x11]);
+        order.put_i32(&mut record, 2);
+        for value in [100.0, 200.0, 0.0, 0.0, 0.0, 0.5] {
+            order.put_f32(&mut record, value);
+        }
+        return record;
+    }
+
+    #[test]
+    fn tf2_version_10_record() {
+        let order = ByteOrder::Little;
+        let mut record = common_fields();
+        // Byte 31 is padding in this layout
+        record[31] = 0;
+        order.put_u16(&mut record, 80);
+        order.put_u16(&mut record, 95);
+        order.put_u32(&mut record, 0x104);
+        order.put_u16(&mut record, 32);
+        order.put_u16(&mut record, 16);
+        assert_eq!(record.len(), 72);
+
+        let lump = parse_static_props(&lump_with_record(&record), 10, 20, order).unwrap();
+        let prop = &lump.props[0];
+        assert_eq!(prop.model_name, "models/props/crate.mdl");
+        assert_eq!(prop.skin, 2);
+        assert_eq!(prop.forced_fade_scale, 0.5);
+        assert_eq!(prop.dx_level, Some([80, 95]));
+        assert_eq!(prop.cpu_gpu_level, None);
+        assert_eq!(prop.flags, 0x04);
+        assert_eq!(prop.flags_ex, 0x104);
+        assert_eq!(prop.lightmap_resolution, Some([32, 16]));
+        assert_eq!(prop.diffuse_modulation, [255; 4]);
+        assert!(!prop.disable_x360);
+    }
+
+    #[test]
+    fn version_10_record() {
+        let order = ByteOrder::Little;
+        let mut record = common_fields();
+        record.extend_from_slice(&[1, 2, 3, 4]);
+        record.extend_from_slice(&[10, 20, 30, 40]);
+        record.extend_from_slice(&[1, 0, 0, 0]);
+        order.put_u32(&mut record, 0x200);
+        assert_eq!(record.len(), 76);
+
+        let lump = parse_static_props(&lump_with_record(&record), 10, 21, order).unwrap();
+        let prop = &lump.props[0];
+        assert_eq!(prop.flags, 0x11);
+        assert_eq!(prop.dx_level, None);
+        assert_eq!(prop.cpu_gpu_level, Some([1, 2, 3, 4]));
+        assert_eq!(prop.diffuse_modulation, [10, 20, 30, 40]);
+        assert!(prop.disable_x360);
+        assert_eq!(prop.flags_ex, 0x200);
+        assert_eq!(prop.lightmap_resolution, None);
+    }
+}
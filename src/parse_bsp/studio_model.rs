@@ -0,0 +1,598 @@
+//! Source engine studio models, as used by static props.
+//!
+//! A model is split over three files: the `.mdl` file holds the materials and
+//! the layout of the meshes, the `.vvd` file the vertices and the `.vtx` file
+//! the triangles for each level of detail. Only the highest level of detail is
+//! read, without bones or animations.
+
+use std::collections::HashMap;
+
+use super::{bsp_to_primitives::MaterialGroup, error::lookup, BspError, ByteOrder};
+use crate::vector::{Vec2, Vec3};
+
+/// Versions of the `.mdl` file this parser understands.
+pub const SUPPORTED_VERSIONS: std::ops::RangeInclusive<i32> = 44..=49;
+
+const TEXTURE_SIZE: usize = 64;
+const BODY_PART_SIZE: usize = 16;
+const MESH_SIZE: usize = 116;
+const VVD_VERTEX_SIZE: usize = 48;
+const VTX_VERTEX_SIZE: usize = 9;
+const VTX_MESH_SIZE: usize = 9;
+/// Sizes of a strip group and of a strip in the `.vtx` file. Newer games add
+/// the topology after the usual fields of both, without changing the version.
+const VTX_STRIP_SIZES: [[usize; 2]; 2] = [[25, 27], [33, 35]];
+
+/// Extensions of the `.vtx` file, from the one used by current hardware down
+/// to the software renderer.
+const VTX_EXTENSIONS: [&str; 4] = [".dx90.vtx", ".dx80.vtx", ".sw.vtx", ".vtx"];
+
+pub struct StudioModel {
+    pub name: String,
+    /// Material paths without extension, joined with the first texture
+    /// directory of the model, which is where almost every model keeps them.
+    pub materials: Vec<String>,
+    /// For every skin, the index into `materials` for each material reference.
+    pub skin_families: Vec<Vec<u16>>,
+    /// The meshes of the default model of each body part.
+    pub meshes: Vec<StudioMesh>,
+}
+
+pub struct StudioMesh {
+    /// Material reference, turned into a material by the skin families.
+    pub material: usize,
+    pub vertexes: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<Vec2>,
+    /// Triangles, wound counter-clockwise like the brush geometry.
+    pub indices: Vec<usize>,
+}
+
+/// The part of a `.mdl` mesh needed to find its vertices.
+struct MeshLayout {
+    material: usize,
+    first_vertex: usize,
+    vertex_count: usize,
+}
+
+impl StudioModel {
+    /// Loads `path`, like `models/props/barrel.mdl`, along with the `.vvd`
+    /// and `.vtx` files next to it.
+    pub fn load(
+        path: &str,
+        mut read_file: impl FnMut(&str) -> Result<Vec<u8>, BspError>,
+    ) -> Result<Self, BspError> {
+        let base = path.strip_suffix(".mdl").unwrap_or(path);
+        let mdl = read_file(&format!("{base}.mdl"))?;
+        let vvd = read_file(&format!("{base}.vvd"))?;
+
+        let mut vtx = Err(BspError::FileNotFound(format!("{base}.vtx")));
+        for extension in VTX_EXTENSIONS {
+            vtx = read_file(&format!("{base}{extension}"));
+            if vtx.is_ok() {
+                break;
+            }
+        }
+
+        return StudioModel::from_bytes(&mdl, &vvd, &vtx?);
+    }
+
+    pub fn from_bytes(mdl: &[u8], vvd: &[u8], vtx: &[u8]) -> Result<Self, BspError> {
+        let magic: [u8; 4] = slice(mdl, 0, 4)?.try_into().unwrap();
+        if &magic != b"IDST" {
+            return Err(BspError::BadMagic(magic));
+        }
+        let version = read_i32(mdl, 4)?;
+        if !SUPPORTED_VERSIONS.contains(&version) {
+            return Err(BspError::UnsupportedModelVersion {
+                file: "mdl",
+                version,
+            });
+        }
+
+        let texture_directories = (0..read_count(mdl, 212)?)
+            .map(|index| {
+                let offset = read_count(mdl, read_count(mdl, 216)? + index * 4)?;
+                return read_string(mdl, offset);
+            })
+            .collect::<Result<Vec<_>, BspError>>()?;
+        let directory = texture_directories.first().map_or("", String::as_str);
+
+        let materials = (0..read_count(mdl, 204)?)
+            .map(|index| {
+                let texture = read_count(mdl, 208)? + index * TEXTURE_SIZE;
+                let name = read_string(mdl, texture + read_count(mdl, texture)?)?;
+                return Ok(normalize_path(&format!("{directory}{name}")));
+            })
+            .collect::<Result<Vec<_>, BspError>>()?;
+
+        let skin_references = read_count(mdl, 220)?;
+        let skin_table = read_count(mdl, 228)?;
+        let skin_families = (0..read_count(mdl, 224)?)
+            .map(|family| {
+                (0..skin_references)
+                    .map(|reference| {
+                        read_u16(mdl, skin_table + (family * skin_references + reference) * 2)
+                    })
+                    .collect::<Result<Vec<_>, BspError>>()
+            })
+            .collect::<Result<Vec<_>, BspError>>()?;
+
+        let layouts = parse_mesh_layouts(mdl)?;
+        let vertices = parse_vvd(vvd)?;
+
+        // Try each layout, the wrong one reads strips and indices out of range
+        let mut result = Err(BspError::BadModel("no strip group size matches"));
+        for strip_sizes in VTX_STRIP_SIZES {
+            result = build_meshes(&layouts, &vertices, vtx, strip_sizes);
+            if result.is_ok() {
+                break;
+            }
+        }
+
+        return Ok(StudioModel {
+            name: read_string(slice(mdl, 12, 64)?, 0)?,
+            materials,
+            skin_families,
+            meshes: result?,
+        });
+    }
+
+    /// The material of `mesh` with the given skin, falling back to the first
+    /// skin if it does not exist.
+    pub fn material(&self, mesh: &StudioMesh, skin: usize) -> Result<&str, BspError> {
+        let family = match self.skin_families.get(skin) {
+            Some(family) => family,
+            None => lookup(&self.skin_families, 0, "skin families")?,
+        };
+        let material = *lookup(family, mesh.material, "skin references")?;
+        return Ok(lookup(&self.materials, material as usize, "materials")?);
+    }
+
+    /// Groups the meshes by material, in the coordinates of [`super::to_primitives`].
+    pub fn to_primitives(&self, skin: usize) -> Result<HashMap<String, MaterialGroup>, BspError> {
+        let mut groups: HashMap<String, MaterialGroup> = HashMap::new();
+        for mesh in &self.meshes {
+            let group = groups
+                .entry(self.material(mesh, skin)?.to_owned())
+                .or_insert(MaterialGroup {
+                    verticies: vec![],
+                    normals: vec![],
+                    uvs: vec![],
+                    lightmap_uvs: vec![],
+                    indices: vec![],
+                });
+
+            let initial_index = group.verticies.len();
+            group
+                .verticies
+                .extend(mesh.vertexes.iter().map(|vertex| vertex.to_y_up() * 0.1));
+            group
+                .normals
+                .extend(mesh.normals.iter().map(|normal| normal.to_y_up()));
+            group.uvs.extend_from_slice(&mesh.uvs);
+            group
+                .indices
+                .extend(mesh.indices.iter().map(|index| initial_index + index));
+        }
+        return Ok(groups);
+    }
+}
+
+/// Reads the meshes of the first model of each body part, the others are
+/// alternatives picked by body groups.
+fn parse_mesh_layouts(mdl: &[u8]) -> Result<Vec<Vec<MeshLayout>>, BspError> {
+    let body_parts = read_count(mdl, 236)?;
+    return (0..read_count(mdl, 232)?)
+        .map(|body_part| {
+            let body_part = body_parts + body_part * BODY_PART_SIZE;
+            if read_count(mdl, body_part + 4)? == 0 {
+                return Ok(vec![]);
+            }
+
+            let model = body_part + read_count(mdl, body_part + 12)?;
+            let first_vertex = read_count(mdl, model + 84)? / VVD_VERTEX_SIZE;
+            let meshes = model + read_count(mdl, model + 76)?;
+            return (0..read_count(mdl, model + 72)?)
+                .map(|mesh| {
+                    let mesh = meshes + mesh * MESH_SIZE;
+                    return Ok(MeshLayout {
+                        material: read_count(mdl, mesh)?,
+                        first_vertex: first_vertex + read_count(mdl, mesh + 12)?,
+                        vertex_count: read_count(mdl, mesh + 8)?,
+                    });
+                })
+                .collect();
+        })
+        .collect();
+}
+
+struct StudioVertex {
+    position: Vec3,
+    normal: Vec3,
+    uv: Vec2,
+}
+
+/// Reads the vertices of the highest level of detail, which the fixup table
+/// gathers from the vertices shared by every level.
+fn parse_vvd(vvd: &[u8]) -> Result<Vec<StudioVertex>, BspError> {
+    let magic: [u8; 4] = slice(vvd, 0, 4)?.try_into().unwrap();
+    if &magic != b"IDSV" {
+        return Err(BspError::BadMagic(magic));
+    }
+    let version = read_i32(vvd, 4)?;
+    if version != 4 {
+        return Err(BspError::UnsupportedModelVersion {
+            file: "vvd",
+            version,
+        });
+    }
+
+    let vertex_count = read_count(vvd, 16)?;
+    let fixup_count = read_count(vvd, 48)?;
+    let fixups = read_count(vvd, 52)?;
+    let vertex_data = read_count(vvd, 56)?;
+
+    let ranges = match fixup_count {
+        0 => vec![(0, vertex_count)],
+        _ => (0..fixup_count)
+            .map(|fixup| {
+                let fixup = fixups + fixup * 12;
+                Ok((read_count(vvd, fixup + 4)?, read_count(vvd, fixup + 8)?))
+            })
+            .collect::<Result<Vec<_>, BspError>>()?,
+    };
+
+    // The count comes from the file, make sure the vertices are there before
+    // reserving memory for them
+    let vertex_bytes = vertex_count
+        .checked_mul(VVD_VERTEX_SIZE)
+        .ok_or(BspError::BadModel("too many vertices"))?;
+    slice(vvd, vertex_data, vertex_bytes)?;
+
+    let order = ByteOrder::Little;
+    let mut vertices = Vec::with_capacity(vertex_count);
+    for (start, count) in ranges {
+        let bytes = slice(
+            vvd,
+            vertex_data + start * VVD_VERTEX_SIZE,
+            count * VVD_VERTEX_SIZE,
+        )?;
+        vertices.extend(bytes.chunks_exact(VVD_VERTEX_SIZE).map(|bytes| {
+            // Preceded by the bone weights
+            StudioVertex {
+                position: super::parse_vector3(&bytes[16..28], order),
+                normal: super::parse_vector3(&bytes[28..40], order),
+                uv: super::parse_vector2(&bytes[40..48], order),
+            }
+        }));
+    }
+    return Ok(vertices);
+}
+
+/// Builds the meshes from the triangles of the highest level of detail in the
+/// `.vtx` file, which follows the body parts, models and meshes of the `.mdl` file.
+fn build_meshes(
+    layouts: &[Vec<MeshLayout>],
+    vertices: &[StudioVertex],
+    vtx: &[u8],
+    [strip_group_size, strip_size]: [usize; 2],
+) -> Result<Vec<StudioMesh>, BspError> {
+    let version = read_i32(vtx, 0)?;
+    if version != 7 {
+        return Err(BspError::UnsupportedModelVersion {
+            file: "vtx",
+            version,
+        });
+    }
+
+    let body_parts = read_count(vtx, 32)?;
+    let mut meshes = vec![];
+    for (body_part, layouts) in layouts.iter().enumerate() {
+        if layouts.is_empty() {
+            continue;
+        }
+        let body_part = body_parts + body_part * 8;
+        let model = body_part + read_count(vtx, body_part + 4)?;
+        let lod = model + read_count(vtx, model + 4)?;
+        let vtx_meshes = lod + read_count(vtx, lod + 4)?;
+
+        for (index, layout) in layouts.iter().enumerate() {
+            let mesh_vertices = vertices
+                .get(layout.first_vertex..layout.first_vertex + layout.vertex_count)
+                .ok_or(BspError::BadModel("mesh vertices out of range"))?;
+            let mut mesh = StudioMesh {
+                material: layout.material,
+                vertexes: mesh_vertices.iter().map(|vertex| vertex.position).collect(),
+                normals: mesh_vertices.iter().map(|vertex| vertex.normal).collect(),
+                uvs: mesh_vertices.iter().map(|vertex| vertex.uv).collect(),
+                indices: vec![],
+            };
+
+            let vtx_mesh = vtx_meshes + index * VTX_MESH_SIZE;
+            let strip_groups = vtx_mesh + read_count(vtx, vtx_mesh + 4)?;
+            for strip_group in 0..read_count(vtx, vtx_mesh)? {
+                let strip_group = strip_groups + strip_group * strip_group_size;
+                let vertex_count = read_count(vtx, strip_group)?;
+                let vertex_offset = strip_group + read_count(vtx, strip_group + 4)?;
+                let index_count = read_count(vtx, strip_group + 8)?;
+                let index_offset = strip_group + read_count(vtx, strip_group + 12)?;
+                if index_count % 3 != 0 {
+                    return Err(BspError::BadModel("strip group is not a triangle list"));
+                }
+
+                // Only the triangle list is used, but the strips have to fit in it
+                let strips = strip_group + read_count(vtx, strip_group + 20)?;
+                for strip in 0..read_count(vtx, strip_group + 16)? {
+                    let strip = strips + strip * strip_size;
+                    let indices = read_count(vtx, strip + 4)? + read_count(vtx, strip)?;
+                    let vertices = read_count(vtx, strip + 12)? + read_count(vtx, strip + 8)?;
+                    if indices > index_count || vertices > vertex_count {
+                        return Err(BspError::BadModel("strip out of range"));
+                    }
+                }
+
+                // Indexes point to strip group vertices, which point to mesh vertices
+                let corners = (0..index_count)
+                    .map(|corner| {
+                        let index = read_u16(vtx, index_offset + corner * 2)? as usize;
+                        if index >= vertex_count {
+                            return Err(BspError::BadModel("strip group index out of range"));
+                        }
+                        let vertex = read_u16(vtx, vertex_offset + index * VTX_VERTEX_SIZE + 4)?;
+                        if vertex as usize >= layout.vertex_count {
+                            return Err(BspError::BadModel("mesh vertex index out of range"));
+                        }
+                        return Ok(vertex as usize);
+                    })
+                    .collect::<Result<Vec<_>, BspError>>()?;
+
+                // Source models are wound clockwise
+                for triangle in corners.chunks_exact(3) {
+                    mesh.indices
+                        .extend_from_slice(&[triangle[0], triangle[2], triangle[1]]);
+                }
+            }
+            meshes.push(mesh);
+        }
+    }
+    return Ok(meshes);
+}
+
+/// Lowercases a path and uses forward slashes, as materials are looked up.
+fn normalize_path(path: &str) -> String {
+    return path.replace('\\', "/").to_ascii_lowercase();
+}
+
+fn slice(data: &[u8], start: usize, length: usize) -> Result<&[u8], BspError> {
+    return data.get(start..start + length).ok_or(BspError::BadModel(
+        "record extends past the end of the file",
+    ));
+}
+
+/// Model files are always little endian on PC.
+fn read_u16(data: &[u8], start: usize) -> Result<u16, BspError> {
+    return Ok(ByteOrder::Little.u16(slice(data, start, 2)?));
+}
+
+fn read_i32(data: &[u8], start: usize) -> Result<i32, BspError> {
+    return Ok(ByteOrder::Little.i32(slice(data, start, 4)?));
+}
+
+/// Reads a count or an offset, which can't be negative.
+fn read_count(data: &[u8], start: usize) -> Result<usize, BspError> {
+    return usize::try_from(read_i32(data, start)?)
+        .map_err(|_| BspError::BadModel("negative count or offset"));
+}
+
+fn read_string(data: &[u8], start: usize) -> Result<String, BspError> {
+    let bytes = data
+        .get(start..)
+        .ok_or(BspError::BadModel("string out of range"))?;
+    let end = bytes.iter().position(|&k| k == 0).unwrap_or(bytes.len());
+    return Ok(String::from_utf8_lossy(&bytes[..end]).into_owned());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_i32(data: &mut [u8], at: usize, value: usize) {
+        data[at..at + 4].copy_from_slice(&(value as i32).to_le_bytes());
+    }
+
+    /// Appends `size` zero bytes, returning where they start.
+    fn push_zeros(data: &mut Vec<u8>, size: usize) -> usize {
+        data.resize(data.len() + size, 0);
+        return data.len() - size;
+    }
+
+    fn push_string(data: &mut Vec<u8>, text: &str) -> usize {
+        let start = data.len();
+        data.extend_from_slice(text.as_bytes());
+        data.push(0);
+        return start;
+    }
+
+    /// A version 48 model with two textures, a skin for each and one mesh
+    /// using four vertices, starting at the second vertex of the model.
+    fn mdl() -> Vec<u8> {
+        let mut mdl = vec![0; 240];
+        mdl[0..4].copy_from_slice(b"IDST");
+        set_i32(&mut mdl, 4, 48);
+        mdl[12..26].copy_from_slice(b"props/crate.md");
+
+        let directory = push_string(&mut mdl, "models\\Props\\");
+        let directories = push_zeros(&mut mdl, 4);
+        set_i32(&mut mdl, directories, directory);
+        set_i32(&mut mdl, 212, 1);
+        set_i32(&mut mdl, 216, directories);
+
+        let textures = push_zeros(&mut mdl, 2 * TEXTURE_SIZE);
+        for (index, name) in ["Crate", "Crate_Dirty"].into_iter().enumerate() {
+            let texture = textures + index * TEXTURE_SIZE;
+            let name = push_string(&mut mdl, name);
+            set_i32(&mut mdl, texture, name - texture);
+        }
+        set_i32(&mut mdl, 204, 2);
+        set_i32(&mut mdl, 208, textures);
+
+        let skins = push_zeros(&mut mdl, 4);
+        mdl[skins + 2..skins + 4].copy_from_slice(&1u16.to_le_bytes());
+        set_i32(&mut mdl, 220, 1);
+        set_i32(&mut mdl, 224, 2);
+        set_i32(&mut mdl, 228, skins);
+
+        let body_part = push_zeros(&mut mdl, BODY_PART_SIZE);
+        set_i32(&mut mdl, 232, 1);
+        set_i32(&mut mdl, 236, body_part);
+        set_i32(&mut mdl, body_part + 4, 1);
+        let model = push_zeros(&mut mdl, 148);
+        set_i32(&mut mdl, body_part + 12, model - body_part);
+        set_i32(&mut mdl, model + 72, 1);
+        set_i32(&mut mdl, model + 84, VVD_VERTEX_SIZE);
+        let mesh = push_zeros(&mut mdl, MESH_SIZE);
+        set_i32(&mut mdl, model + 76, mesh - model);
+        set_i32(&mut mdl, mesh + 8, 4);
+        return mdl;
+    }
+
+    /// Five vertices, gathered by the fixup table around a vertex only used
+    /// by lower levels of detail. Vertex `k` is at `(k, 2k, 3k)`.
+    fn vvd() -> Vec<u8> {
+        let mut vvd = vec![0; 64];
+        vvd[0..4].copy_from_slice(b"IDSV");
+        set_i32(&mut vvd, 4, 4);
+        set_i32(&mut vvd, 16, 5);
+
+        let fixups = push_zeros(&mut vvd, 24);
+        for (index, (start, count)) in [(0, 2), (3, 3)].into_iter().enumerate() {
+            set_i32(&mut vvd, fixups + index * 12 + 4, start);
+            set_i32(&mut vvd, fixups + index * 12 + 8, count);
+        }
+        set_i32(&mut vvd, 48, 2);
+        set_i32(&mut vvd, 52, fixups);
+
+        let vertex_data = vvd.len();
+        set_i32(&mut vvd, 56, vertex_data);
+        for k in [0.0, 1.0, 99.0, 2.0, 3.0, 4.0] {
+            let vertex = push_zeros(&mut vvd, VVD_VERTEX_SIZE);
+            let fields = [k, 2.0 * k, 3.0 * k, 0.0, 0.0, 1.0, k / 4.0, 0.5];
+            for (index, value) in fields.into_iter().enumerate() {
+                let at = vertex + 16 + index * 4;
+                vvd[at..at + 4].copy_from_slice(&f32::to_le_bytes(value));
+            }
+        }
+        return vvd;
+    }
+
+    /// One mesh with two strip groups of one triangle each, with the given
+    /// sizes of strip groups and strips.
+    fn vtx([strip_group_size, strip_size]: [usize; 2]) -> Vec<u8> {
+        let mut vtx = vec![0; 36];
+        set_i32(&mut vtx, 0, 7);
+        let body_part = push_zeros(&mut vtx, 8);
+        set_i32(&mut vtx, 28, 1);
+        set_i32(&mut vtx, 32, body_part);
+        let model = push_zeros(&mut vtx, 8);
+        set_i32(&mut vtx, body_part, 1);
+        set_i32(&mut vtx, body_part + 4, model - body_part);
+        let lod = push_zeros(&mut vtx, 12);
+        set_i32(&mut vtx, model, 1);
+        set_i32(&mut vtx, model + 4, lod - model);
+        let mesh = push_zeros(&mut vtx, VTX_MESH_SIZE);
+        set_i32(&mut vtx, lod, 1);
+        set_i32(&mut vtx, lod + 4, mesh - lod);
+        let strip_groups = push_zeros(&mut vtx, 2 * strip_group_size);
+        set_i32(&mut vtx, mesh, 2);
+        set_i32(&mut vtx, mesh + 4, strip_groups - mesh);
+
+        for (index, mesh_vertices) in [[0u16, 1, 2], [0, 2, 3]].into_iter().enumerate() {
+            let strip_group = strip_groups + index * strip_group_size;
+            let vertices = push_zeros(&mut vtx, 3 * VTX_VERTEX_SIZE);
+            for (k, mesh_vertex) in mesh_vertices.into_iter().enumerate() {
+                let at = vertices + k * VTX_VERTEX_SIZE + 4;
+                vtx[at..at + 2].copy_from_slice(&mesh_vertex.to_le_bytes());
+            }
+            let indices = vtx.len();
+            for index in [0u16, 1, 2] {
+                vtx.extend_from_slice(&index.to_le_bytes());
+            }
+            let strip = push_zeros(&mut vtx, strip_size);
+            set_i32(&mut vtx, strip, 3);
+            set_i32(&mut vtx, strip + 8, 3);
+
+            for (field, value) in [3, vertices, 3, indices, 1, strip].into_iter().enumerate() {
+                let value = match field % 2 {
+                    0 => value,
+                    _ => value - strip_group,
+                };
+                set_i32(&mut vtx, strip_group + field * 4, value);
+            }
+            // Newer files point the topology at the indices
+            if strip_group_size > 25 {
+                set_i32(&mut vtx, strip_group + 25, 3);
+                set_i32(&mut vtx, strip_group + 29, indices - strip_group);
+            }
+        }
+        return vtx;
+    }
+
+    #[test]
+    fn reads_both_strip_group_layouts() {
+        for strip_sizes in VTX_STRIP_SIZES {
+            let model = StudioModel::from_bytes(&mdl(), &vvd(), &vtx(strip_sizes)).unwrap();
+            assert_eq!(model.name, "props/crate.md");
+            assert_eq!(
+                model.materials,
+                ["models/props/crate", "models/props/crate_dirty"]
+            );
+            assert_eq!(model.skin_families, [[0], [1]]);
+            assert_eq!(model.meshes.len(), 1);
+
+            let mesh = &model.meshes[0];
+            let x = mesh.vertexes.iter().map(|vertex| vertex.x);
+            assert_eq!(x.collect::<Vec<_>>(), [1.0, 2.0, 3.0, 4.0]);
+            assert_eq!(mesh.vertexes[3].z, 12.0);
+            assert_eq!(mesh.uvs[1], Vec2 { x: 0.5, y: 0.5 });
+            // Each triangle is flipped to counter-clockwise
+            assert_eq!(mesh.indices, [0, 2, 1, 0, 3, 2]);
+
+            assert_eq!(model.material(mesh, 1).unwrap(), "models/props/crate_dirty");
+            assert_eq!(model.material(mesh, 7).unwrap(), "models/props/crate");
+        }
+    }
+
+    #[test]
+    fn wrong_strip_group_size_is_rejected() {
+        let layouts = parse_mesh_layouts(&mdl()).unwrap();
+        let vertices = parse_vvd(&vvd()).unwrap();
+        let [old, new] = VTX_STRIP_SIZES;
+        assert!(build_meshes(&layouts, &vertices, &vtx(old), new).is_err());
+        assert!(build_meshes(&layouts, &vertices, &vtx(new), old).is_err());
+    }
+
+    #[test]
+    fn strips_must_fit_in_their_strip_group() {
+        let layouts = parse_mesh_layouts(&mdl()).unwrap();
+        let vertices = parse_vvd(&vvd()).unwrap();
+        for strip_sizes in VTX_STRIP_SIZES {
+            let mut vtx = vtx(strip_sizes);
+            // The last strip of the file, claiming more indices than its strip group
+            let strip = vtx.len() - strip_sizes[1];
+            set_i32(&mut vtx, strip, 6);
+            assert!(matches!(
+                build_meshes(&layouts, &vertices, &vtx, strip_sizes),
+                Err(BspError::BadModel("strip out of range"))
+            ));
+        }
+    }
+
+    #[test]
+    fn vertex_count_past_the_end_is_an_error() {
+        let mut vvd = vvd();
+        set_i32(&mut vvd, 16, i32::MAX as usize);
+        set_i32(&mut vvd, 48, 0);
+        assert!(matches!(parse_vvd(&vvd), Err(BspError::BadModel(_))));
+    }
+}
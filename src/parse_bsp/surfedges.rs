@@ -1,17 +1,35 @@
 use std::io::Read;
 use std::io::Seek;
 
-use super::parse_split_lump::parse_split_chunks;
+use super::parse_split_lump::try_parse_split_chunks;
+use super::record::Record;
+use super::BspError;
+use super::ByteOrder;
 use super::Lump;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
-pub(super) struct SurfEdge(pub i32);
+pub struct SurfEdge(pub i32);
 
 pub(super) fn parse_surf_edges<T: Read + Seek>(
     file: &mut T,
     lump: Lump,
-) -> std::io::Result<Vec<SurfEdge>> {
-    parse_split_chunks(file, lump, |bytes: [u8; 4]| {
-        SurfEdge(i32::from_le_bytes(bytes))
+    order: ByteOrder,
+) -> Result<Vec<SurfEdge>, BspError> {
+    lump.expect_version(SurfEdge::VERSIONS, SurfEdge::NAME)?;
+    try_parse_split_chunks(file, lump, |bytes: [u8; 4]| {
+        SurfEdge::from_bytes(&bytes, order)
     })
 }
+
+impl Record for SurfEdge {
+    const SIZE: usize = 4;
+    const NAME: &'static str = "surface edges";
+
+    fn from_bytes(bytes: &[u8], order: ByteOrder) -> Result<Self, BspError> {
+        return Ok(SurfEdge(order.i32(bytes)));
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>, order: ByteOrder) {
+        order.put_i32(out, self.0);
+    }
+}
@@ -3,13 +3,16 @@ use std::io::{Read, Seek};
 use crate::vector::Vec2;
 use crate::vector::Vec3;
 
+use super::face::Face;
 use super::parse_split_lump::parse_split_chunks;
 use super::parse_vector3;
 use super::texdata::TextureData;
+use super::BspError;
+use super::ByteOrder;
 use super::Lump;
 
 #[derive(Copy, Clone)]
-pub(super) struct TextureInfo {
+pub struct TextureInfo {
     pub texture_vectors: [(Vec3, f32); 2],
     pub lightmap_vectors: [(Vec3, f32); 2],
     pub flags: u32,
@@ -19,22 +22,24 @@ pub(super) struct TextureInfo {
 pub(super) fn parse_texture_info<T: Read + Seek>(
     file: &mut T,
     lump: Lump,
-) -> std::io::Result<Vec<TextureInfo>> {
+    order: ByteOrder,
+) -> Result<Vec<TextureInfo>, BspError> {
+    lump.expect_version(&[0], "texture info")?;
     parse_split_chunks(file, lump, |bytes: [u8; 72]| TextureInfo {
         texture_vectors: [0, 1].map(|i| {
             (
-                parse_vector3(bytes[i * 16..i * 16 + 12].try_into().unwrap()),
-                f32::from_le_bytes(bytes[i * 16 + 12..i * 16 + 16].try_into().unwrap()),
+                parse_vector3(&bytes[i * 16..i * 16 + 12], order),
+                order.f32(&bytes[i * 16 + 12..i * 16 + 16]),
             )
         }),
         lightmap_vectors: [0, 1].map(|i| {
             (
-                parse_vector3(bytes[i * 16..i * 16 + 12].try_into().unwrap()),
-                f32::from_le_bytes(bytes[i * 16 + 12..i * 16 + 16].try_into().unwrap()),
+                parse_vector3(&bytes[i * 16 + 32..i * 16 + 44], order),
+                order.f32(&bytes[i * 16 + 44..i * 16 + 48]),
             )
         }),
-        flags: u32::from_le_bytes(bytes[64..68].try_into().unwrap()),
-        texture_data_index: u32::from_le_bytes(bytes[68..72].try_into().unwrap()),
+        flags: order.u32(&bytes[64..68]),
+        texture_data_index: order.u32(&bytes[68..72]),
     })
 }
 
@@ -48,6 +53,21 @@ impl TextureInfo {
             y: 1.0 / data.height as f32,
         };
     }
+
+    /// Position of `coords` in the lightmap of `face`, from 0 to 1 across its
+    /// luxels, with whole luxel coordinates at the luxel centers.
+    pub fn get_lightmap_uv(self, coords: Vec3, face: &Face) -> Vec2 {
+        let [s, t] = [0, 1].map(|axis| {
+            let (vector, offset) = self.lightmap_vectors[axis];
+            vector.dot(&coords) + offset - face.lightmap_texture_mins_in_luxels[axis] as f32 + 0.5
+        });
+        let [width, height] = face.lightmap_texture_size_in_luxels.map(|k| k as f32 + 1.0);
+        return Vec2 { x: s, y: t }
+            * Vec2 {
+                x: 1.0 / width,
+                y: 1.0 / height,
+            };
+    }
 }
 
 #[allow(unused)]
@@ -1,47 +1,41 @@
-use std::{
-    fs::File,
-    io::{Read, Seek},
-};
+use std::io::{Read, Seek};
 
 use super::{
-    parse_split_lump::{decompress_stream, parse_split_chunks},
-    Lump,
+    parse_split_lump::{parse_split_chunks, read_lump},
+    BspError, ByteOrder, Lump,
 };
 
-pub(super) struct TextureDataStringArray(Vec<u8>);
+pub struct TextureDataStringArray(Vec<u8>);
 
 impl TextureDataStringArray {
-    pub fn get_str(&self, index: usize) -> Result<&str, std::str::Utf8Error> {
-        let start = index;
-        let mut end = index;
-        while self.0[end] > 0 {
-            end += 1;
-        }
-        return std::str::from_utf8(&self.0[start..end]);
+    pub fn get_str(&self, index: usize) -> Result<&str, BspError> {
+        let tail = self.0.get(index..).ok_or(BspError::IndexOutOfRange {
+            kind: "texture string data",
+            index,
+            length: self.0.len(),
+        })?;
+        let end = tail.iter().position(|&k| k == 0).unwrap_or(tail.len());
+        return Ok(std::str::from_utf8(&tail[..end])?);
     }
 }
 
-pub(super) fn parse_texture_data_string_array(
-    file: &mut File,
+pub(super) fn parse_texture_data_string_array<T: Read + Seek>(
+    file: &mut T,
     lump: Lump,
-) -> std::io::Result<TextureDataStringArray> {
-    println!("About to parse string data");
-    let (mut stream, length) = decompress_stream(file, lump)?;
-    println!("Parsed string data");
-
-    let mut out = vec![0; length as usize];
-    stream.read_exact(out.as_mut_slice())?;
-
-    return Ok(TextureDataStringArray(out));
+) -> Result<TextureDataStringArray, BspError> {
+    lump.expect_version(&[0], "texture string data")?;
+    return Ok(TextureDataStringArray(read_lump(file, lump)?));
 }
 
-pub(super) struct TextureString(pub u32);
+pub struct TextureString(pub u32);
 
 pub(super) fn parse_texture_data_string_table<T: Read + Seek>(
     file: &mut T,
     lump: Lump,
-) -> std::io::Result<Vec<TextureString>> {
+    order: ByteOrder,
+) -> Result<Vec<TextureString>, BspError> {
+    lump.expect_version(&[0], "texture string table")?;
     parse_split_chunks(file, lump, |bytes: [u8; 4]| {
-        TextureString(u32::from_le_bytes(bytes))
+        TextureString(order.u32(&bytes))
     })
 }
@@ -1,16 +1,34 @@
-use super::parse_split_lump::parse_split_chunks;
+use super::parse_split_lump::try_parse_split_chunks;
+use super::record::Record;
+use super::BspError;
+use super::ByteOrder;
 use super::Lump;
 use std::io::Read;
 use std::io::Seek;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
-pub(super) struct Vertex(pub crate::vector::Vec3);
+pub struct Vertex(pub crate::vector::Vec3);
 
 pub(super) fn parse_vertices<T: Read + Seek>(
     file: &mut T,
     lump: Lump,
-) -> std::io::Result<Vec<Vertex>> {
-    parse_split_chunks(file, lump, |bytes: [u8; 12]| {
-        Vertex(super::parse_vector3(bytes))
+    order: ByteOrder,
+) -> Result<Vec<Vertex>, BspError> {
+    lump.expect_version(Vertex::VERSIONS, Vertex::NAME)?;
+    try_parse_split_chunks(file, lump, |bytes: [u8; 12]| {
+        Vertex::from_bytes(&bytes, order)
     })
 }
+
+impl Record for Vertex {
+    const SIZE: usize = 12;
+    const NAME: &'static str = "vertexes";
+
+    fn from_bytes(bytes: &[u8], order: ByteOrder) -> Result<Self, BspError> {
+        return Ok(Vertex(super::parse_vector3(bytes, order)));
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>, order: ByteOrder) {
+        super::write_vector3(out, self.0, order);
+    }
+}
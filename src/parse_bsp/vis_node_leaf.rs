@@ -1,68 +1,96 @@
 use std::io::{Read, Seek};
 
-use super::{parse_split_lump::parse_split_chunks, Lump};
+use super::{
+    color::CompressedLightCube, parse_split_lump::parse_split_chunks, BspError, ByteOrder, Lump,
+};
 
-pub(super) struct VisNode {
-    plane_id: u32,
-    children: [i32; 2],
-    min: [u16; 3],
-    max: [u16; 3],
-    first_face: u16,
-    num_faces: u16,
-    area: u16,
+pub struct VisNode {
+    pub plane_id: u32,
+    pub children: [i32; 2],
+    pub min: [u16; 3],
+    pub max: [u16; 3],
+    pub first_face: u16,
+    pub num_faces: u16,
+    pub area: u16,
     _padding: u16,
 }
 
-pub(super) struct VisLeaf {
-    contents: u32,
-    cluster: u16,
-    area_and_flags: u16,
-    min: [u16; 3],
-    max: [u16; 3],
-    first_leaf_face: u16,
-    num_leaf_faces: u16,
-    first_leaf_brush: u16,
-    num_leaf_brushes: u16,
-    water_data: u16,
-    _padding: u16,
+pub struct VisLeaf {
+    pub contents: u32,
+    pub cluster: u16,
+    pub area_and_flags: u16,
+    pub min: [u16; 3],
+    pub max: [u16; 3],
+    pub first_leaf_face: u16,
+    pub num_leaf_faces: u16,
+    pub first_leaf_brush: u16,
+    pub num_leaf_brushes: u16,
+    pub water_data: u16,
+    /// Only present in version 0 of the lump.
+    pub ambient_lighting: Option<CompressedLightCube>,
 }
 
 pub(super) fn parse_vis_node<T: Read + Seek>(
     file: &mut T,
     lump: Lump,
-) -> std::io::Result<Vec<VisNode>> {
+    order: ByteOrder,
+) -> Result<Vec<VisNode>, BspError> {
+    lump.expect_version(&[0], "nodes")?;
     parse_split_chunks(file, lump, |bytes: [u8; 32]| VisNode {
-        plane_id: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
-        children: [0, 1]
-            .map(|k| i32::from_le_bytes(bytes[k * 4 + 4..k * 4 + 8].try_into().unwrap())),
-        min: [0, 1, 2]
-            .map(|k| u16::from_le_bytes(bytes[k * 2 + 12..k * 2 + 14].try_into().unwrap())),
-        max: [0, 1, 2]
-            .map(|k| u16::from_le_bytes(bytes[k * 2 + 18..k * 2 + 20].try_into().unwrap())),
-        first_face: u16::from_le_bytes(bytes[24..26].try_into().unwrap()),
-        num_faces: u16::from_le_bytes(bytes[26..28].try_into().unwrap()),
-        area: u16::from_le_bytes(bytes[28..30].try_into().unwrap()),
-        _padding: u16::from_le_bytes(bytes[30..32].try_into().unwrap()),
+        plane_id: order.u32(&bytes[0..4]),
+        children: [0, 1].map(|k| order.i32(&bytes[k * 4 + 4..k * 4 + 8])),
+        min: [0, 1, 2].map(|k| order.u16(&bytes[k * 2 + 12..k * 2 + 14])),
+        max: [0, 1, 2].map(|k| order.u16(&bytes[k * 2 + 18..k * 2 + 20])),
+        first_face: order.u16(&bytes[24..26]),
+        num_faces: order.u16(&bytes[26..28]),
+        area: order.u16(&bytes[28..30]),
+        _padding: order.u16(&bytes[30..32]),
     })
 }
 
 pub(super) fn parse_vis_leaf<T: Read + Seek>(
     file: &mut T,
     lump: Lump,
-) -> std::io::Result<Vec<VisLeaf>> {
-    parse_split_chunks(file, lump, |bytes: [u8; 32]| VisLeaf {
-        contents: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
-        cluster: u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
-        area_and_flags: u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
-        min: [0, 1, 2]
-            .map(|k| u16::from_le_bytes(bytes[k * 2 + 8..k * 2 + 10].try_into().unwrap())),
-        max: [0, 1, 2]
-            .map(|k| u16::from_le_bytes(bytes[k * 2 + 14..k * 2 + 16].try_into().unwrap())),
-        first_leaf_face: u16::from_le_bytes(bytes[20..22].try_into().unwrap()),
-        num_leaf_faces: u16::from_le_bytes(bytes[22..24].try_into().unwrap()),
-        first_leaf_brush: u16::from_le_bytes(bytes[24..26].try_into().unwrap()),
-        num_leaf_brushes: u16::from_le_bytes(bytes[26..28].try_into().unwrap()),
-        water_data: u16::from_le_bytes(bytes[28..30].try_into().unwrap()),
-        _padding: u16::from_le_bytes(bytes[30..32].try_into().unwrap()),
-    })
+    order: ByteOrder,
+) -> Result<Vec<VisLeaf>, BspError> {
+    match lump.version {
+        // Version 0 stores the ambient lighting inline, later versions moved
+        // it to its own lump.
+        0 => parse_split_chunks(file, lump, |bytes: [u8; 56]| {
+            leaf_from_bytes(
+                &bytes,
+                order,
+                Some(CompressedLightCube::from_bytes(
+                    bytes[30..54].try_into().unwrap(),
+                )),
+            )
+        }),
+        1 => parse_split_chunks(file, lump, |bytes: [u8; 32]| {
+            leaf_from_bytes(&bytes, order, None)
+        }),
+        version => Err(BspError::UnsupportedLumpVersion {
+            lump: "leafs",
+            version,
+        }),
+    }
+}
+
+fn leaf_from_bytes(
+    bytes: &[u8],
+    order: ByteOrder,
+    ambient_lighting: Option<CompressedLightCube>,
+) -> VisLeaf {
+    return VisLeaf {
+        contents: order.u32(&bytes[0..4]),
+        cluster: order.u16(&bytes[4..6]),
+        area_and_flags: order.u16(&bytes[6..8]),
+        min: [0, 1, 2].map(|k| order.u16(&bytes[k * 2 + 8..k * 2 + 10])),
+        max: [0, 1, 2].map(|k| order.u16(&bytes[k * 2 + 14..k * 2 + 16])),
+        first_leaf_face: order.u16(&bytes[20..22]),
+        num_leaf_faces: order.u16(&bytes[22..24]),
+        first_leaf_brush: order.u16(&bytes[24..26]),
+        num_leaf_brushes: order.u16(&bytes[26..28]),
+        water_data: order.u16(&bytes[28..30]),
+        ambient_lighting,
+    };
 }
@@ -0,0 +1,222 @@
+use std::io::{Read, Seek};
+
+use crate::vector::Vec3;
+
+use super::{parse_split_lump::parse_split_chunks, parse_vector3, BspError, ByteOrder, Lump};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum EmitType {
+    /// Light emitted by a textured surface, like `lights/` materials.
+    Surface,
+    Point,
+    Spotlight,
+    /// The sun of `light_environment`, shining along `normal`.
+    Skylight,
+    /// A point light with linear falloff.
+    QuakeLight,
+    /// Ambient light coming from the sky.
+    SkyAmbient,
+    Other(i32),
+}
+
+impl EmitType {
+    fn from_id(id: i32) -> Self {
+        return match id {
+            0 => EmitType::Surface,
+            1 => EmitType::Point,
+            2 => EmitType::Spotlight,
+            3 => EmitType::Skylight,
+            4 => EmitType::QuakeLight,
+            5 => EmitType::SkyAmbient,
+            other => EmitType::Other(other),
+        };
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct WorldLight {
+    pub origin: Vec3,
+    /// Color multiplied by brightness, in linear space.
+    pub intensity: Vec3,
+    /// Direction spotlights, surfaces and the sun shine in.
+    pub normal: Vec3,
+    /// Version 1 only, zero otherwise.
+    pub shadow_cast_offset: Vec3,
+    pub cluster: i32,
+    pub emit_type: EmitType,
+    pub style: i32,
+    /// Cosine of the angle where a spotlight starts to fade.
+    pub stopdot: f32,
+    /// Cosine of the angle where a spotlight has faded out.
+    pub stopdot2: f32,
+    pub exponent: f32,
+    /// Distance past which the light has no effect, 0 if unlimited.
+    pub radius: f32,
+    pub constant_attenuation: f32,
+    pub linear_attenuation: f32,
+    pub quadratic_attenuation: f32,
+    pub flags: i32,
+    pub tex_info: i32,
+    pub owner: i32,
+}
+
+pub(super) fn parse_world_lights<T: Read + Seek>(
+    file: &mut T,
+    lump: Lump,
+    order: ByteOrder,
+) -> Result<Vec<WorldLight>, BspError> {
+    lump.expect_version(&[0, 1], "world lights")?;
+    // Version 1 adds the shadow cast offset after the normal
+    return match lump.version {
+        0 => parse_split_chunks(file, lump, |bytes: [u8; 88]| {
+            WorldLight::from_bytes(&bytes, false, order)
+        }),
+        _ => parse_split_chunks(file, lump, |bytes: [u8; 100]| {
+            WorldLight::from_bytes(&bytes, true, order)
+        }),
+    };
+}
+
+impl WorldLight {
+    fn from_bytes(bytes: &[u8], shadow_cast_offset: bool, order: ByteOrder) -> Self {
+        let (shadow_cast_offset, rest) = match shadow_cast_offset {
+            true => (parse_vector3(&bytes[36..48], order), &bytes[48..]),
+            false => (
+                Vec3 {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                &bytes[36..],
+            ),
+        };
+
+        return WorldLight {
+            origin: parse_vector3(&bytes[0..12], order),
+            intensity: parse_vector3(&bytes[12..24], order),
+            normal: parse_vector3(&bytes[24..36], order),
+            shadow_cast_offset,
+            cluster: order.i32(&rest[0..4]),
+            emit_type: EmitType::from_id(order.i32(&rest[4..8])),
+            style: order.i32(&rest[8..12]),
+            stopdot: order.f32(&rest[12..16]),
+            stopdot2: order.f32(&rest[16..20]),
+            exponent: order.f32(&rest[20..24]),
+            radius: order.f32(&rest[24..28]),
+            constant_attenuation: order.f32(&rest[28..32]),
+            linear_attenuation: order.f32(&rest[32..36]),
+            quadratic_attenuation: order.f32(&rest[36..40]),
+            flags: order.i32(&rest[40..44]),
+            tex_info: order.i32(&rest[44..48]),
+            owner: order.i32(&rest[48..52]),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn vec3([x, y, z]: [f32; 3]) -> Vec3 {
+        return Vec3 { x, y, z };
+    }
+
+    /// A spotlight record, with the shadow cast offset for version 1.
+    fn spotlight_bytes(version: u32, order: ByteOrder) -> Vec<u8> {
+        let mut out = vec![];
+        let mut vectors = vec![[10.0, 20.0, 30.0], [100.0, 50.0, 0.0], [0.0, 0.0, -1.0]];
+        if version == 1 {
+            vectors.push([1.0, 2.0, 3.0]);
+        }
+        for value in vectors.into_iter().flatten() {
+            order.put_f32(&mut out, value);
+        }
+        for value in [7, 2, 32] {
+            order.put_i32(&mut out, value);
+        }
+        for value in [0.9, 0.7, 1.5, 256.0, 0.0, 0.5, 1.0] {
+            order.put_f32(&mut out, value);
+        }
+        for value in [1, -1, 3] {
+            order.put_i32(&mut out, value);
+        }
+        return out;
+    }
+
+    fn parse(data: &[u8], version: u32, order: ByteOrder) -> Vec<WorldLight> {
+        let lump = Lump {
+            offset: 0,
+            length: data.len() as u32,
+            version,
+            id: [0; 4],
+        };
+        return parse_world_lights(&mut Cursor::new(data), lump, order).unwrap();
+    }
+
+    #[test]
+    fn reads_both_versions() {
+        for order in [ByteOrder::Little, ByteOrder::Big] {
+            let version_0 = spotlight_bytes(0, order);
+            let version_1 = spotlight_bytes(1, order);
+            assert_eq!((version_0.len(), version_1.len()), (88, 100));
+
+            for (version, data) in [(0, version_0), (1, version_1)] {
+                let [light] = parse(&data, version, order)[..] else {
+                    panic!("expected one light");
+                };
+                assert_eq!(light.origin, vec3([10.0, 20.0, 30.0]));
+                assert_eq!(light.intensity, vec3([100.0, 50.0, 0.0]));
+                assert_eq!(light.normal, vec3([0.0, 0.0, -1.0]));
+                let shadow_cast_offset = match version {
+                    0 => [0.0; 3],
+                    _ => [1.0, 2.0, 3.0],
+                };
+                assert_eq!(light.shadow_cast_offset, vec3(shadow_cast_offset));
+                assert_eq!(light.cluster, 7);
+                assert_eq!(light.emit_type, EmitType::Spotlight);
+                assert_eq!(light.style, 32);
+                assert_eq!([light.stopdot, light.stopdot2], [0.9, 0.7]);
+                assert_eq!([light.exponent, light.radius], [1.5, 256.0]);
+                assert_eq!(
+                    [
+                        light.constant_attenuation,
+                        light.linear_attenuation,
+                        light.quadratic_attenuation
+                    ],
+                    [0.0, 0.5, 1.0]
+                );
+                assert_eq!([light.flags, light.tex_info, light.owner], [1, -1, 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn record_size_follows_the_lump_version() {
+        let data = spotlight_bytes(1, ByteOrder::Little);
+        let lump = Lump {
+            offset: 0,
+            length: data.len() as u32,
+            version: 0,
+            id: [0; 4],
+        };
+        assert!(matches!(
+            parse_world_lights(&mut Cursor::new(&data), lump, ByteOrder::Little),
+            Err(BspError::BadRecordSize {
+                length: 100,
+                record_size: 88
+            })
+        ));
+    }
+
+    #[test]
+    fn unknown_emit_types_are_kept() {
+        let mut data = spotlight_bytes(0, ByteOrder::Little);
+        data[40..44].copy_from_slice(&9_i32.to_le_bytes());
+        assert_eq!(
+            parse(&data, 0, ByteOrder::Little)[0].emit_type,
+            EmitType::Other(9)
+        );
+    }
+}
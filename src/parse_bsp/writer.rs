@@ -0,0 +1,388 @@
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{BufWriter, Read, Seek, Write},
+    path::Path,
+};
+
+use super::{
+    entity::{write_entities, Entity},
+    error::lookup,
+    lump_names,
+    pakfile::PakfileWriter,
+    record::Record,
+    Bsp, BspError, ByteOrder, LumpLayout, HEADER_SIZE,
+};
+
+/// Builds a Source engine BSP file.
+///
+/// As long as no lump has been replaced, every lump is written back at its
+/// original offset along with any data between and after the lumps, so an
+/// unmodified map is reproduced byte for byte. Once a lump changes, the lumps
+/// are packed in their original order, each one aligned to 4 bytes like `vbsp`
+/// does, with the pakfile last.
+pub struct BspWriter {
+    pub byte_order: ByteOrder,
+    pub version: u32,
+    pub lump_layout: LumpLayout,
+    pub map_revision: u32,
+    lumps: Vec<WriterLump>,
+    /// Whether any lump has been replaced since the map was read.
+    modified: bool,
+    /// Data after the last lump in the original file.
+    trailing: Vec<u8>,
+}
+
+struct WriterLump {
+    /// Offset in the original file, kept until a lump is replaced.
+    original_offset: u32,
+    version: u32,
+    id: [u8; 4],
+    /// The lump as stored in the file, still compressed if `id` is set.
+    data: Vec<u8>,
+    /// Data between the previous lump and this one in the original file.
+    leading: Vec<u8>,
+}
+
+impl BspWriter {
+    /// Copies every lump of `bsp` without decoding it.
+    pub fn from_bsp<R: Read + Seek>(bsp: &Bsp<R>) -> Result<Self, BspError> {
+        let lumps = bsp
+            .lumps
+            .iter()
+            .enumerate()
+            .map(|(index, lump)| {
+                Ok(WriterLump {
+                    original_offset: lump.offset,
+                    version: lump.version,
+                    id: lump.id,
+                    data: bsp.raw_lump_bytes(index)?,
+                    leading: vec![],
+                })
+            })
+            .collect::<Result<Vec<_>, BspError>>()?;
+
+        let mut writer = BspWriter {
+            byte_order: bsp.byte_order,
+            version: bsp.version,
+            lump_layout: bsp.lump_layout,
+            map_revision: bsp.map_revision,
+            lumps,
+            modified: false,
+            trailing: vec![],
+        };
+
+        // Keep whatever is stored between the lumps, so an unmodified map can
+        // be written back unchanged
+        if let Some(order) = writer.original_order() {
+            let mut position = HEADER_SIZE;
+            for index in order {
+                let lump = &mut writer.lumps[index];
+                if lump.data.is_empty() {
+                    continue;
+                }
+                let offset = lump.original_offset as u64;
+                lump.leading = bsp.read_bytes(position, offset - position)?;
+                position = offset + lump.data.len() as u64;
+            }
+            let file_length = bsp.file_length()?;
+            writer.trailing = bsp.read_bytes(position, file_length.saturating_sub(position))?;
+        }
+
+        return Ok(writer);
+    }
+
+    /// Replaces the contents of the lump at `index`, the new data is stored
+    /// uncompressed.
+    pub fn set_lump(&mut self, index: usize, data: Vec<u8>) -> Result<(), BspError> {
+        let length = self.lumps.len();
+        let lump = self.lumps.get_mut(index).ok_or(BspError::IndexOutOfRange {
+            kind: "lumps",
+            index,
+            length,
+        })?;
+        lump.data = data;
+        lump.id = [0; 4];
+        self.modified = true;
+        return Ok(());
+    }
+
+    /// Changes the version number stored in the directory entry of a lump.
+    pub fn set_lump_version(&mut self, index: usize, version: u32) -> Result<(), BspError> {
+        let length = self.lumps.len();
+        self.lumps
+            .get_mut(index)
+            .ok_or(BspError::IndexOutOfRange {
+                kind: "lumps",
+                index,
+                length,
+            })?
+            .version = version;
+        return Ok(());
+    }
+
+    /// Replaces the lump at `index` with the encoded `records`.
+    pub fn set_records<T: Record>(&mut self, index: usize, records: &[T]) -> Result<(), BspError> {
+        let version = lookup(&self.lumps, index, "lumps")?.version;
+        if !T::VERSIONS.contains(&version) {
+            return Err(BspError::UnsupportedLumpVersion {
+                lump: T::NAME,
+                version,
+            });
+        }
+
+        let mut data = Vec::with_capacity(records.len() * T::SIZE);
+        for record in records {
+            record.write_bytes(&mut data, self.byte_order);
+        }
+        return self.set_lump(index, data);
+    }
+
+    /// Replaces the entity lump.
+    pub fn set_entities(&mut self, entities: &[Entity]) -> Result<(), BspError> {
+        return self.set_lump(lump_names::LUMP_ENTITIES, write_entities(entities)?);
+    }
+
+    /// Replaces the pakfile, which is always written as the last lump.
+    pub fn set_pakfile(&mut self, pakfile: &PakfileWriter) -> Result<(), BspError> {
+        return self.set_lump(lump_names::LUMP_PAKFILE, pakfile.to_bytes()?);
+    }
+
+    /// The lump at `index` as it will be written, compressed if the original was.
+    pub fn lump_data(&self, index: usize) -> Result<&[u8], BspError> {
+        return Ok(&lookup(&self.lumps, index, "lumps")?.data);
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), BspError> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write(&mut file)?;
+        file.flush()?;
+        return Ok(());
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BspError> {
+        let mut out = vec![];
+        self.write(&mut out)?;
+        return Ok(out);
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> Result<(), BspError> {
+        let (write_order, offsets, packed) = match self.original_order() {
+            Some(write_order) => {
+                let offsets = self.lumps.iter().map(|lump| lump.original_offset);
+                (write_order, offsets.collect::<Vec<_>>(), false)
+            }
+            None => (self.write_order(), self.layout()?, true),
+        };
+        let order = self.byte_order;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(match order {
+            ByteOrder::Little => b"VBSP",
+            ByteOrder::Big => b"PSBV",
+        });
+        order.put_u32(&mut header, self.version);
+        for (lump, &offset) in self.lumps.iter().zip(&offsets) {
+            let length = lump.data.len() as u32;
+            let fields = match self.lump_layout {
+                LumpLayout::Standard => [offset, length, lump.version],
+                LumpLayout::Left4Dead2 => [lump.version, offset, length],
+            };
+            for field in fields {
+                order.put_u32(&mut header, field);
+            }
+            header.extend_from_slice(&lump.id);
+        }
+        order.put_u32(&mut header, self.map_revision);
+        out.write_all(&header)?;
+
+        let mut position = HEADER_SIZE;
+        for index in write_order {
+            let lump = &self.lumps[index];
+            if lump.data.is_empty() {
+                continue;
+            }
+
+            if packed {
+                let padding = offsets[index] as u64 - position;
+                out.write_all(&vec![0; padding as usize])?;
+            } else {
+                out.write_all(&lump.leading)?;
+            }
+            out.write_all(&self.fixed_up_data(index, offsets[index]))?;
+            position = offsets[index] as u64 + lump.data.len() as u64;
+        }
+        if packed {
+            out.write_all(&vec![0; (position.next_multiple_of(4) - position) as usize])?;
+        } else {
+            out.write_all(&self.trailing)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Lump indexes sorted by their position in the original file, empty lumps
+    /// go before a lump sharing their offset. The engine expects the pakfile to
+    /// come last, no matter where it was before.
+    fn write_order(&self) -> Vec<usize> {
+        let mut order = (0..self.lumps.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&index| {
+            let lump = &self.lumps[index];
+            let last = index == lump_names::LUMP_PAKFILE && !lump.data.is_empty();
+            (last, lump.original_offset, !lump.data.is_empty())
+        });
+        return order;
+    }
+
+    /// Lump indexes sorted by their original offset, if the lumps can be
+    /// written back there: nothing has been replaced and no lumps overlap.
+    fn original_order(&self) -> Option<Vec<usize>> {
+        if self.modified {
+            return None;
+        }
+
+        let mut order = (0..self.lumps.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&index| {
+            let lump = &self.lumps[index];
+            (lump.original_offset, !lump.data.is_empty())
+        });
+
+        let mut position = HEADER_SIZE;
+        for &index in &order {
+            let lump = &self.lumps[index];
+            if lump.data.is_empty() {
+                continue;
+            }
+            if (lump.original_offset as u64) < position {
+                return None;
+            }
+            position = lump.original_offset as u64 + lump.data.len() as u64;
+        }
+        return Some(order);
+    }
+
+    /// Assigns a file offset to every lump, packing them in [`Self::write_order`].
+    fn layout(&self) -> Result<Vec<u32>, BspError> {
+        let mut offsets = vec![0; self.lumps.len()];
+        let mut position = HEADER_SIZE;
+        for index in self.write_order() {
+            let lump = &self.lumps[index];
+            // Empty lumps keep a zero offset if they had one, otherwise they
+            // point wherever the writer was at the time
+            if lump.data.is_empty() && lump.original_offset == 0 {
+                continue;
+            }
+
+            offsets[index] = u32::try_from(position).map_err(|_| BspError::FileTooLarge)?;
+            position = (position + lump.data.len() as u64).next_multiple_of(4);
+        }
+        return Ok(offsets);
+    }
+
+    /// The game lump directory stores offsets from the start of the file,
+    /// they have to move along with the lump.
+    fn fixed_up_data(&self, index: usize, offset: u32) -> Cow<'_, [u8]> {
+        let lump = &self.lumps[index];
+        let delta = offset as i64 - lump.original_offset as i64;
+        if index != lump_names::LUMP_GAME_LUMP || delta == 0 || lump.id != [0; 4] {
+            return Cow::Borrowed(&lump.data);
+        }
+
+        let order = self.byte_order;
+        let mut data = lump.data.clone();
+        let count = data.get(0..4).map_or(0, |bytes| order.u32(bytes)) as usize;
+        let entries = data.get_mut(4..).unwrap_or_default();
+        for entry in entries.chunks_exact_mut(16).take(count) {
+            let entry_offset = order.u32(&entry[8..12]);
+            if entry_offset == 0 {
+                continue;
+            }
+            let mut fixed = vec![];
+            order.put_u32(&mut fixed, (entry_offset as i64 + delta) as u32);
+            entry[8..12].copy_from_slice(&fixed);
+        }
+        return Cow::Owned(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_bsp::tests::build_bsp;
+
+    /// A map that stores the pakfile first, unlike `vbsp`.
+    fn test_map() -> Vec<u8> {
+        let mut pakfile = PakfileWriter::new();
+        pakfile.insert("materials/test.vmt", b"LightmappedGeneric {}".to_vec());
+        let mut worldspawn = Entity::new("worldspawn");
+        worldspawn.set("skyname", "sky_day01_01");
+
+        return build_bsp(&[
+            (lump_names::LUMP_PAKFILE, 0, pakfile.to_bytes().unwrap()),
+            (
+                lump_names::LUMP_ENTITIES,
+                0,
+                write_entities(&[worldspawn]).unwrap(),
+            ),
+            (lump_names::LUMP_PLANES, 0, vec![7; 40]),
+        ]);
+    }
+
+    #[test]
+    fn unmodified_map_is_written_unchanged() {
+        let data = test_map();
+        let bsp = Bsp::from_bytes(&data).unwrap();
+        assert_eq!(BspWriter::from_bsp(&bsp).unwrap().to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn data_between_and_after_lumps_is_kept() {
+        // A lump that is dropped from the directory leaves its data behind
+        let mut data = build_bsp(&[
+            (lump_names::LUMP_PLANES, 0, vec![7; 40]),
+            (lump_names::LUMP_VERTEXES, 0, vec![0xab; 12]),
+            (lump_names::LUMP_EDGES, 0, vec![9; 8]),
+        ]);
+        let directory = 8 + lump_names::LUMP_VERTEXES * 16;
+        data[directory..directory + 16].fill(0);
+        data.extend_from_slice(b"trailing");
+
+        let bsp = Bsp::from_bytes(&data).unwrap();
+        assert_eq!(BspWriter::from_bsp(&bsp).unwrap().to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn modified_map_round_trips() {
+        let data = test_map();
+        let bsp = Bsp::from_bytes(&data).unwrap();
+        let mut entities = bsp.entities().unwrap().to_vec();
+        entities[0].set("skyname", "sky_night_01");
+        entities.push(Entity::new("info_player_start"));
+
+        let mut writer = BspWriter::from_bsp(&bsp).unwrap();
+        writer.set_entities(&entities).unwrap();
+        let written = writer.to_bytes().unwrap();
+
+        let reread = Bsp::from_bytes(&written).unwrap();
+        assert_eq!(reread.entities().unwrap(), entities);
+        for index in [lump_names::LUMP_PLANES, lump_names::LUMP_PAKFILE] {
+            assert_eq!(
+                reread.lump_bytes(index).unwrap(),
+                bsp.lump_bytes(index).unwrap()
+            );
+        }
+        let pakfile_offset = reread.lumps[lump_names::LUMP_PAKFILE].offset;
+        assert!(reread
+            .lumps
+            .iter()
+            .all(|lump| lump.offset <= pakfile_offset));
+        assert_eq!(
+            reread
+                .pakfile()
+                .unwrap()
+                .read("materials/test.vmt")
+                .unwrap(),
+            b"LightmappedGeneric {}"
+        );
+    }
+}
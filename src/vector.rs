@@ -34,6 +34,14 @@ impl Vec3 {
         return self.x * other.x + self.y * other.y + self.z * other.z;
     }
 
+    pub fn cross(&self, other: Vec3) -> Vec3 {
+        return Vec3 {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        };
+    }
+
     pub fn normalize(&self) -> Self {
         let distance = self
             .distance_squared(&Vec3 {
@@ -81,6 +89,76 @@ impl Vec3 {
         }
     }
 
+    /// Forward, right and up directions for Source engine angles: pitch, yaw
+    /// and roll in degrees, stored in `x`, `y` and `z`.
+    pub fn angle_vectors(self) -> [Vec3; 3] {
+        let (sp, cp) = self.x.to_radians().sin_cos();
+        let (sy, cy) = self.y.to_radians().sin_cos();
+        let (sr, cr) = self.z.to_radians().sin_cos();
+
+        let forward = Vec3 {
+            x: cp * cy,
+            y: cp * sy,
+            z: -sp,
+        };
+        let right = Vec3 {
+            x: -sr * sp * cy + cr * sy,
+            y: -sr * sp * sy - cr * cy,
+            z: -sr * cp,
+        };
+        let up = Vec3 {
+            x: cr * sp * cy + sr * sy,
+            y: cr * sp * sy - sr * cy,
+            z: cr * cp,
+        };
+        return [forward, right, up];
+    }
+
+    /// The rotation given by Source engine angles as an `[x, y, z, w]`
+    /// quaternion, in the space produced by [`Vec3::to_y_up`].
+    pub fn angles_to_y_up_quaternion(self) -> [f32; 4] {
+        let [forward, right, up] = self.angle_vectors();
+        // The columns of the rotation matrix are where each y up axis ends up:
+        // x is forward, y is up and z is right in Source terms
+        let columns = [forward.to_y_up(), up.to_y_up(), right.to_y_up()];
+        let m = |row: usize, column: usize| columns[column].get_axis(AXISES[row]);
+
+        let trace = m(0, 0) + m(1, 1) + m(2, 2);
+        if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            return [
+                (m(2, 1) - m(1, 2)) / s,
+                (m(0, 2) - m(2, 0)) / s,
+                (m(1, 0) - m(0, 1)) / s,
+                s / 4.0,
+            ];
+        } else if m(0, 0) > m(1, 1) && m(0, 0) > m(2, 2) {
+            let s = (1.0 + m(0, 0) - m(1, 1) - m(2, 2)).sqrt() * 2.0;
+            return [
+                s / 4.0,
+                (m(0, 1) + m(1, 0)) / s,
+                (m(0, 2) + m(2, 0)) / s,
+                (m(2, 1) - m(1, 2)) / s,
+            ];
+        } else if m(1, 1) > m(2, 2) {
+            let s = (1.0 + m(1, 1) - m(0, 0) - m(2, 2)).sqrt() * 2.0;
+            return [
+                (m(0, 1) + m(1, 0)) / s,
+                s / 4.0,
+                (m(1, 2) + m(2, 1)) / s,
+                (m(0, 2) - m(2, 0)) / s,
+            ];
+        } else {
+            let s = (1.0 + m(2, 2) - m(0, 0) - m(1, 1)).sqrt() * 2.0;
+            return [
+                (m(0, 2) + m(2, 0)) / s,
+                (m(1, 2) + m(2, 1)) / s,
+                s / 4.0,
+                (m(1, 0) - m(0, 1)) / s,
+            ];
+        }
+    }
+
     pub fn elementwise_min(self, other: Vec3) -> Vec3 {
         Vec3 {
             x: self.x.min(other.x),